@@ -0,0 +1,46 @@
+//! Bundles the cyoa stories (every .html file in src/bin/cyoa, sorted by file name), and
+//! reads the size of the flash reserved for saves and settings from memory.x
+
+use std::path::PathBuf;
+
+fn main() {
+    let dir = PathBuf::from(std::env::var("CARGO_MANIFEST_DIR").unwrap()).join("src/bin/cyoa");
+    // Also re-runs when stories are added or removed
+    println!("cargo:rerun-if-changed={}", dir.display());
+
+    let mut stories: Vec<PathBuf> = std::fs::read_dir(&dir)
+        .expect("Could not list stories")
+        .map(|entry| entry.expect("Could not list stories").path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "html"))
+        .collect();
+    stories.sort();
+
+    let mut out = String::from("&[\n");
+    for story in stories {
+        out += &format!("    include_str!({:?}),\n", story.display().to_string());
+    }
+    out += "]\n";
+
+    let out_dir = PathBuf::from(std::env::var("OUT_DIR").unwrap());
+    std::fs::write(out_dir.join("stories.rs"), out).expect("Could not write stories");
+
+    let memory = PathBuf::from(std::env::var("CARGO_MANIFEST_DIR").unwrap()).join("memory.x");
+    println!("cargo:rerun-if-changed={}", memory.display());
+    let memory = std::fs::read_to_string(memory).expect("Could not read memory.x");
+    let size = storage_size(&memory).expect("memory.x has no STORAGE region with a LENGTH in K");
+    std::fs::write(out_dir.join("storage_size.rs"), format!("{size}\n"))
+        .expect("Could not write the storage size");
+}
+
+/// The length of the STORAGE region, in bytes
+fn storage_size(memory: &str) -> Option<usize> {
+    let region = memory
+        .lines()
+        .find(|line| line.trim_start().starts_with("STORAGE"))?;
+    let (_, length) = region.split_once("LENGTH")?;
+    let kilobytes = length
+        .trim_start_matches([' ', '='])
+        .trim()
+        .strip_suffix('K')?;
+    Some(kilobytes.parse::<usize>().ok()? * 1024)
+}
@@ -1,10 +1,16 @@
 /* Copied from rp-rs/rp-hal-boards */
 MEMORY {
-    BOOT2 : ORIGIN = 0x10000000, LENGTH = 0x100
-    FLASH : ORIGIN = 0x10000100, LENGTH = 2048K - 0x100
-    RAM   : ORIGIN = 0x20000000, LENGTH = 256K
+    BOOT2   : ORIGIN = 0x10000000, LENGTH = 0x100
+    FLASH   : ORIGIN = 0x10000100, LENGTH = 2048K - 0x100 - 40K
+    /* Saves and settings, erased and written at runtime (see src/save.rs, read by build.rs):
+       a sector per story, the last story played and the config */
+    STORAGE : ORIGIN = 0x10000000 + 2048K - 40K, LENGTH = 40K
+    RAM     : ORIGIN = 0x20000000, LENGTH = 256K
 }
 
+ASSERT(ORIGIN(FLASH) + LENGTH(FLASH) <= ORIGIN(STORAGE), "FLASH overlaps STORAGE");
+ASSERT(ORIGIN(STORAGE) + LENGTH(STORAGE) == 0x10000000 + 2048K, "STORAGE is not at the end of the flash");
+
 EXTERN(BOOT2_FIRMWARE)
 
 SECTIONS {
@@ -0,0 +1,300 @@
+//! Play a cyoa story in the terminal, with exactly the same logic as the device.
+//!
+//! Usage:
+//!   player [--back erase|replay] [--menu <style>] [--width <n>] [--save <file>] [--seed <n>]
+//!          <story.html>...
+//!     Interactive: SPACE is a short press, ENTER a long press, 'b' (or BACKSPACE) a double
+//!     press (go back) and 'q' quits.
+//!   player [--back erase|replay] [--menu <style>] [--width <n>] [--save <file>] [--seed <n>]
+//!          --script <choices> <story.html>...
+//!     Make the (comma separated) choices, e.g. '0,2,b,1' ('b' goes back), and print what
+//!     was typed. In timed passages, e.g. 'w3000' waits 3 seconds without pressing.
+//!
+//! The menu style is one of 'caret' (default), 'numbered', 'retype' or 'cycle'. With
+//! `--width`, passages and menus are wrapped at that column.
+//!
+//! Timed passages time out in real time, as on the device.
+//!
+//! With several stories, one is picked first (with the story played last listed first),
+//! like the device does when it has several stories.
+//!
+//! With `--save`, progress is saved to (and resumed from) the file, like the device does
+//! with its flash. With several stories, each has its own file ('<file>.<n>', where n is
+//! the story's position on the command line) and the last story is in '<file>.last'.
+//!
+//! Random picks depend on `--seed <n>`; without it a new seed is used and printed, so that
+//! the run can be reproduced.
+
+use std::cell::RefCell;
+use std::io::{Read, Write};
+use std::path::PathBuf;
+use std::process::{Command, ExitCode, Stdio};
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use ghostwriter::cyoa::menu::MenuStyle;
+use ghostwriter::cyoa::{self, Button, Clock, GoBack, Options, Press};
+use ghostwriter::host::{self, Editor};
+use ghostwriter::keyboard::Keyboard;
+use ghostwriter::leds;
+use ghostwriter::save::{Storage, Storages, SAVE_SIZE};
+
+use embassy_time::Duration;
+use usbd_hid::descriptor::KeyboardReport;
+
+const USAGE: &str = "Usage: player [--back erase|replay] [--menu caret|numbered|retype|cycle] \
+                     [--width <n>] [--save <file>] [--seed <n>] [--script <choices>] <story.html>...";
+
+fn main() -> ExitCode {
+    let mut args = std::env::args().skip(1).peekable();
+
+    let mut options = Options::default();
+    let mut save_path = None;
+    let mut script = None;
+    let mut seed = None;
+    while let Some(flag) = args.next_if(|arg| arg.starts_with("--")) {
+        match (flag.as_str(), args.next().as_deref()) {
+            ("--back", Some("erase")) => options.go_back = GoBack::Erase,
+            ("--back", Some("replay")) => options.go_back = GoBack::Replay,
+            ("--menu", Some("caret")) => options.menu = MenuStyle::Caret,
+            ("--menu", Some("numbered")) => options.menu = MenuStyle::Numbered,
+            ("--menu", Some("retype")) => options.menu = MenuStyle::Retype,
+            ("--menu", Some("cycle")) => options.menu = MenuStyle::Cycle,
+            ("--width", Some(width)) if width.parse::<usize>().is_ok() => {
+                options.width = width.parse().ok()
+            }
+            ("--save", Some(path)) => save_path = Some(PathBuf::from(path)),
+            ("--seed", Some(n)) if n.parse::<u64>().is_ok() => seed = n.parse().ok(),
+            ("--script", Some(choices)) => script = Some(choices.to_string()),
+            _ => {
+                eprintln!("{USAGE}");
+                return ExitCode::FAILURE;
+            }
+        }
+    }
+
+    let paths: Vec<String> = args.collect();
+    let stories: Vec<String> = paths
+        .iter()
+        .map(|path| std::fs::read_to_string(path).expect("Could not read story"))
+        .collect();
+    if stories.is_empty() {
+        eprintln!("{USAGE}");
+        return ExitCode::FAILURE;
+    }
+    for (path, story) in paths.iter().zip(&stories) {
+        let start = twine::find_start_passage_id(story);
+        if !twine::passages(story).any(|passage| passage.pid == start) {
+            eprintln!("{path} is not a Twine story (no start passage)");
+            return ExitCode::FAILURE;
+        }
+    }
+    let stories: Vec<&str> = stories.iter().map(String::as_str).collect();
+
+    options.seed = seed.unwrap_or_else(|| {
+        let seed = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_nanos() as u64);
+        eprintln!("Seed: {seed} (replay with --seed {seed})");
+        seed
+    });
+
+    // One file per story, plus one for the last story if there are several
+    let file = |suffix: &str| {
+        save_path.as_ref().map(|path| {
+            let mut path = path.clone().into_os_string();
+            path.push(format!(".{suffix}"));
+            PathBuf::from(path)
+        })
+    };
+    let (mut last_story, mut saves) = match stories.len() {
+        1 => (FileStorage(None), vec![FileStorage(save_path.clone())]),
+        n => (
+            FileStorage(file("last")),
+            (0..n)
+                .map(|ix| FileStorage(file(&ix.to_string())))
+                .collect(),
+        ),
+    };
+
+    match script {
+        Some(script) => {
+            let Some(steps) = host::parse_script(&script) else {
+                eprintln!("Choices should be comma separated numbers, 'b' or waits like 'w3000'");
+                return ExitCode::FAILURE;
+            };
+
+            let reports =
+                host::record_library(&stories, &steps, &mut last_story, &mut saves, &options);
+            println!("{}", host::replay(&reports));
+        }
+        None => play_interactive(&stories, &mut last_story, &mut saves, &options),
+    }
+
+    ExitCode::SUCCESS
+}
+
+/// Storage keeping the save in a file, if any
+struct FileStorage(Option<PathBuf>);
+
+impl Storage for FileStorage {
+    async fn read(&mut self, buf: &mut [u8; SAVE_SIZE]) {
+        match self.0.as_ref().and_then(|path| std::fs::read(path).ok()) {
+            Some(data) if data.len() == SAVE_SIZE => buf.copy_from_slice(&data),
+            _ => buf.fill(0xFF),
+        }
+    }
+
+    async fn write(&mut self, buf: &[u8; SAVE_SIZE]) {
+        if let Some(path) = &self.0 {
+            if let Err(err) = std::fs::write(path, buf) {
+                eprintln!("Could not save to {}: {err}", path.display());
+            }
+        }
+    }
+}
+
+/// How often the terminal button and clock check for keys and time, while the other waits
+const POLL_INTERVAL: std::time::Duration = std::time::Duration::from_millis(10);
+
+/// The system's clock. Sleeping lets keys be read in the meantime (see [`TerminalButton`]).
+struct WallClock;
+
+impl Clock for WallClock {
+    async fn sleep(&mut self, duration: Duration) {
+        let deadline = Instant::now() + std::time::Duration::from_micros(duration.as_micros());
+        while let Some(remaining) = deadline.checked_duration_since(Instant::now()) {
+            std::thread::sleep(remaining.min(POLL_INTERVAL));
+            embassy_futures::yield_now().await;
+        }
+    }
+}
+
+/// Keyboard typing into the editor shared with the button (for rendering)
+struct TerminalKeyboard<'a>(&'a RefCell<Editor>);
+
+impl Keyboard for TerminalKeyboard<'_> {
+    async fn send(&mut self, report: &KeyboardReport) {
+        self.0.borrow_mut().process(report);
+    }
+}
+
+/// Button reading keys from the terminal, rendering the editor before waiting. Keys are
+/// read by another thread, so that waiting for them can be raced against the clock.
+struct TerminalButton<'a> {
+    editor: &'a RefCell<Editor>,
+    keys: Receiver<u8>,
+    current: Press,
+}
+
+impl Button for TerminalButton<'_> {
+    async fn wait_for_press(&mut self) {
+        render(&self.editor.borrow());
+
+        self.current = loop {
+            match self.keys.try_recv() {
+                Ok(b' ') => break Press::Short,
+                Ok(b'\n') => break Press::Long,
+                Ok(b'b') | Ok(0x7f) => break Press::Double,
+                Ok(b'q') | Err(TryRecvError::Disconnected) => {
+                    set_raw_mode(false);
+                    std::process::exit(0);
+                }
+                Ok(_) => {}
+                Err(TryRecvError::Empty) => {
+                    std::thread::sleep(POLL_INTERVAL);
+                    embassy_futures::yield_now().await;
+                }
+            }
+        };
+    }
+
+    async fn wait_for_release(&mut self) -> Press {
+        self.current
+    }
+}
+
+fn play_interactive(
+    stories: &[&str],
+    last_story: &mut FileStorage,
+    saves: &mut [FileStorage],
+    options: &Options,
+) {
+    let editor = RefCell::new(Editor::new());
+    let mut keyboard = TerminalKeyboard(&editor);
+    let (sender, keys) = mpsc::channel();
+    std::thread::spawn(move || {
+        while let Some(key) = read_key() {
+            if sender.send(key).is_err() {
+                break;
+            }
+        }
+    });
+    let mut button = TerminalButton {
+        editor: &editor,
+        keys,
+        current: Press::Short,
+    };
+    let leds_signal = leds::Signal::new();
+
+    set_raw_mode(true);
+    println!("Press SPACE to start (SPACE: short press, ENTER: long press, b: back, q: quit)");
+
+    let mut library = Storages { last_story, saves };
+    embassy_futures::block_on(cyoa::play_library(
+        stories,
+        &mut keyboard,
+        &mut button,
+        &mut WallClock,
+        &leds_signal,
+        &mut library,
+        options,
+    ));
+
+    render(&editor.borrow());
+    println!();
+    set_raw_mode(false);
+}
+
+/// Clear the screen and print the editor's content, with the cursor in reverse video
+fn render(editor: &Editor) {
+    let (cursor_line, cursor_col) = editor.cursor();
+    let mut out = String::from("\x1b[2J\x1b[H");
+
+    for (ix, line) in editor.text().split('\n').enumerate() {
+        if ix == cursor_line {
+            let (before, after) = line.split_at(cursor_col.min(line.len()));
+            let mut after = after.chars();
+            let under_cursor = after.next().unwrap_or(' ');
+            out += &format!("{before}\x1b[7m{under_cursor}\x1b[0m{}", after.as_str());
+        } else {
+            out += line;
+        }
+        out += "\r\n";
+    }
+
+    let mut stdout = std::io::stdout();
+    let _ = stdout.write_all(out.as_bytes());
+    let _ = stdout.flush();
+}
+
+fn read_key() -> Option<u8> {
+    let mut buf = [0];
+    match std::io::stdin().read(&mut buf) {
+        Ok(1) => Some(buf[0]),
+        _ => None,
+    }
+}
+
+/// Read keys as soon as they're pressed, without echoing them
+fn set_raw_mode(raw: bool) {
+    let args: &[&str] = if raw {
+        &["-icanon", "-echo"]
+    } else {
+        &["icanon", "echo"]
+    };
+    let _ = Command::new("stty")
+        .args(args)
+        .stdin(Stdio::inherit())
+        .status();
+}
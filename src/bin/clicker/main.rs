@@ -1,31 +1,85 @@
-//! An HID/Keyboard device that triggers a Space (short press) or Enter (long press)
+//! An HID/Keyboard device that maps button gestures to keys, media keys, system controls,
+//! mouse clicks, text or macros, with a few profiles to switch between (see
+//! [`ghostwriter::clicker`]). It can also jiggle the mouse to keep the host awake.
+//!
+//! When the host is suspended, a press wakes it up (USB remote wakeup) instead.
+//!
+//! It is also a USB serial port, with a command console (see [`ghostwriter::console`]) to
+//! switch profiles, type text (including long texts streamed with `send`, see
+//! [`ghostwriter::proxy`]), run macros, or change the settings:
+//!
+//! - `jiggle`: whether the mouse is jiggling (on or off)
+//! - those of [`ghostwriter::config`], which are saved in flash. `delay` and `batch` apply
+//!   right away, the others after a restart. `poll-ms` applies to every interface but the
+//!   keyboard, always polled every [`bulk::POLL_MS`] for `send`.
 
 #![no_std]
 #![no_main]
 
 use {defmt_rtt as _, panic_probe as _};
 
+use core::cell::Cell;
+use core::fmt::Write;
+
 // USB Human Interface Device (HID) Class support
-use usbd_hid::descriptor::{KeyboardReport, SerializedDescriptor};
+use usbd_hid::descriptor::{MouseReport, SerializedDescriptor};
 
 use defmt::*;
 use embassy_executor::Spawner;
 use embassy_futures::join::join;
+use embassy_futures::select::{select, select3, Either, Either3};
 use embassy_rp::bind_interrupts;
+use embassy_rp::clocks::RoscRng;
 use embassy_rp::gpio::{Input, Pull};
 use embassy_rp::peripherals::USB;
 use embassy_rp::usb::{Driver, InterruptHandler};
-use embassy_time::{Duration, Timer};
+use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+use embassy_sync::signal::Signal;
+use embassy_time::{Duration, Instant};
+use embassy_usb::class::cdc_acm::{self, CdcAcmClass};
 use embassy_usb::class::hid;
-use embassy_usb::{Builder, Config};
+use embassy_usb::Builder;
 
+use ghostwriter::button::debounce::Policy;
+use ghostwriter::button::{Gesture, GesturePin};
+use ghostwriter::clicker::{self, Action, Clicker, Devices, PROFILES};
+use ghostwriter::config::{self, Config};
+use ghostwriter::console::{self, Command, LineReader, Reply};
+use ghostwriter::consumer::{self, HidConsumer};
+use ghostwriter::cyoa::SystemClock;
+use ghostwriter::keyboard::bulk::{self, FlowControl};
+use ghostwriter::keyboard::{
+    self, CapsLockPolicy, HidKeyboard, Keyboard, LockKeysHandler, LockSignal, ReportFormat,
+};
 use ghostwriter::leds;
+use ghostwriter::mouse::{self, HidMouse, JiggleConfig, Jiggler};
+use ghostwriter::proxy;
+use ghostwriter::save::{self, FlashStorage, Storage};
+use ghostwriter::system::{self, HidSystemControl};
 
 bind_interrupts!(struct Irqs {
     USBCTRL_IRQ => InterruptHandler<USB>;
 });
 
-type HidWriter<'a> = hid::HidWriter<'a, Driver<'a, USB>, 8>;
+/// The keyboard, consumer control, system control and mouse HID interfaces
+type HidDevices<'w, 'd> = Devices<
+    HidKeyboard<'w, 'd, Driver<'d, USB>>,
+    HidConsumer<'w, 'd, Driver<'d, USB>>,
+    HidSystemControl<'w, 'd, Driver<'d, USB>>,
+    HidMouse<'w, 'd, Driver<'d, USB>>,
+>;
+
+/// Lets the clicker wake up a suspended host
+struct Wakeup {
+    suspended: Cell<bool>,
+    request: Signal<NoopRawMutex, ()>,
+}
+
+/// The settings, and where they are saved
+struct Settings<S> {
+    config: Config,
+    storage: S,
+}
 
 #[embassy_executor::main]
 async fn main(_spawner: Spawner) {
@@ -34,11 +88,19 @@ async fn main(_spawner: Spawner) {
     // Create the driver, from the HAL.
     let driver = Driver::new(p.USB, Irqs);
 
+    // Read the settings (see ghostwriter::config)
+    let mut flash = save::Flash::new_blocking(p.FLASH);
+    let settings = {
+        let mut storage = FlashStorage {
+            flash: &mut flash,
+            offset: config::CONFIG_OFFSET,
+        };
+        config::load(&mut storage).await
+    };
+
     // Create embassy-usb Config
-    let mut config = Config::new(0xc0de, 0xcafe);
-    config.manufacturer = Some("Boo-inc");
-    config.product = Some("Ghostwriter");
-    config.serial_number = Some("0oooo00000");
+    let mut config = settings.usb_config();
+    config.supports_remote_wakeup = true;
 
     // Create embassy-usb DeviceBuilder using the driver and config.
     // It needs some buffers for building the descriptors.
@@ -48,6 +110,14 @@ async fn main(_spawner: Spawner) {
     let mut control_buf = [0; 64];
 
     let mut state = hid::State::new();
+    let mut consumer_state = hid::State::new();
+    let mut system_state = hid::State::new();
+    let mut mouse_state = hid::State::new();
+    let mut serial_state = cdc_acm::State::new();
+
+    // The host's lock keys, from the output reports of either pipe
+    let lock_keys = LockSignal::new();
+    let mut control_handler = LockKeysHandler { signal: &lock_keys };
 
     let mut builder = Builder::new(
         driver,
@@ -60,50 +130,120 @@ async fn main(_spawner: Spawner) {
 
     // Create classes on the builder.
     let config = embassy_usb::class::hid::Config {
-        report_descriptor: KeyboardReport::desc(),
+        report_descriptor: ReportFormat::Boot.descriptor(),
+        request_handler: Some(&mut control_handler),
+        // Fast enough for bulk typing (see `send`), the delay paces normal typing
+        poll_ms: bulk::POLL_MS,
+        max_packet_size: 64,
+    };
+    let hid = keyboard::HidReaderWriter::new(&mut builder, &mut state, config);
+    let (reader, mut writer) = hid.split();
+    let mut reader_handler = LockKeysHandler { signal: &lock_keys };
+    let reader_fut = reader.run(false, &mut reader_handler);
+
+    // A second interface for media keys etc. (composite device)
+    let config = embassy_usb::class::hid::Config {
+        report_descriptor: consumer::DESCRIPTOR,
+        request_handler: None,
+        poll_ms: settings.poll_ms,
+        max_packet_size: 64,
+    };
+    let mut consumer_writer = consumer::HidWriter::new(&mut builder, &mut consumer_state, config);
+
+    // And a third one for sleep, wake up, etc.
+    let config = embassy_usb::class::hid::Config {
+        report_descriptor: system::DESCRIPTOR,
+        request_handler: None,
+        poll_ms: settings.poll_ms,
+        max_packet_size: 64,
+    };
+    let mut system_writer = system::HidWriter::new(&mut builder, &mut system_state, config);
+
+    // And a mouse, for clicks, scrolling and the jiggler
+    let config = embassy_usb::class::hid::Config {
+        report_descriptor: MouseReport::desc(),
         request_handler: None,
-        poll_ms: 60,
+        poll_ms: settings.poll_ms,
         max_packet_size: 64,
     };
-    let mut writer = hid::HidWriter::<_, 8>::new(&mut builder, &mut state, config);
+    let mut mouse_writer = mouse::HidWriter::new(&mut builder, &mut mouse_state, config);
+
+    // And a serial port, for the console
+    let serial = CdcAcmClass::new(&mut builder, &mut serial_state, console::PACKET_SIZE as u16);
 
     // Build the builder.
     let mut usb = builder.build();
 
-    // Run the USB device.
-    let usb_fut = usb.run();
+    let wakeup = Wakeup {
+        suspended: Cell::new(false),
+        request: Signal::new(),
+    };
+
+    // Run the USB device, waking up the host on request while suspended.
+    let usb_fut = async {
+        loop {
+            usb.run_until_suspend().await;
+            wakeup.suspended.set(true);
+            wakeup.request.reset();
+            if let Either::Second(()) = select(usb.wait_resume(), wakeup.request.wait()).await {
+                info!("ghostwriter clicker waking up the host");
+                if usb.remote_wakeup().await.is_err() {
+                    warn!("ghostwriter clicker could not wake up the host");
+                }
+            }
+            wakeup.suspended.set(false);
+        }
+    };
 
     // Set up the signal pin that will be used to trigger the keyboard.
     let mut signal_pin = Input::new(p.PIN_23, Pull::None);
 
-    // Enable the schmitt trigger to slightly debounce.
+    // Enable the schmitt trigger against noise (bounces are handled by the debouncer).
     signal_pin.set_schmitt(true);
 
     let led_slices = leds::init_pwm((p.PWM_SLICE1, p.PWM_SLICE2), (p.PIN_18, p.PIN_19, p.PIN_20));
     let signal = leds::Signal::new();
 
     let leds_fut = leds::animate_leds(&signal, led_slices);
-    let click_fut = click(&mut writer, signal_pin, &signal);
+    let button = GesturePin::new(signal_pin, Policy::default(), settings.thresholds());
+    let devices = Devices {
+        keyboard: HidKeyboard {
+            writer: &mut writer,
+            delay: settings.delay,
+            lock_keys: Some(&lock_keys),
+            caps_lock: CapsLockPolicy::InvertShift,
+            format: ReportFormat::Boot,
+            max_batch: settings.max_batch,
+        },
+        consumer: HidConsumer {
+            writer: &mut consumer_writer,
+            delay: settings.delay,
+        },
+        system: HidSystemControl {
+            writer: &mut system_writer,
+            delay: settings.delay,
+        },
+        mouse: HidMouse {
+            writer: &mut mouse_writer,
+            delay: settings.delay,
+        },
+    };
+    let settings = Settings {
+        config: settings,
+        storage: FlashStorage {
+            flash: &mut flash,
+            offset: config::CONFIG_OFFSET,
+        },
+    };
+    let click_fut = click(
+        devices, button, serial, settings, &signal, &wakeup, &lock_keys,
+    );
     let app_fut = join(click_fut, leds_fut);
 
     // Run everything concurrently.
-    join(usb_fut, app_fut).await;
+    join(join(usb_fut, reader_fut), app_fut).await;
 }
 
-const SPACE: KeyboardReport = KeyboardReport {
-    modifier: 0,
-    reserved: 0,
-    leds: 0,
-    keycodes: [44, 0, 0, 0, 0, 0],
-};
-
-const ENTER: KeyboardReport = KeyboardReport {
-    modifier: 0,
-    reserved: 0,
-    leds: 0,
-    keycodes: [40, 0, 0, 0, 0, 0],
-};
-
 /// Short press
 const PRESSED_ANIMATION: leds::Animation = leds::Animation {
     #[allow(clippy::eq_op)]
@@ -113,45 +253,251 @@ const PRESSED_ANIMATION: leds::Animation = leds::Animation {
     loop_after: None,
 };
 
-/// Pressed timed out, enter was sent
-/// This will tail off and is the "default" state after a selection (ENTER)
-/// was made.
-const ENTER_TRIGGERED_ANIMATION: leds::Animation = leds::Animation {
+/// Typing the text sent over the serial port
+const TYPING_ANIMATION: leds::Animation = leds::Animation {
     #[allow(clippy::eq_op)]
-    color: (1.0 / 1.0, 5.0 / 9.0, 0.3 / 1.0),
-    bounds: (0.3, 1.),
-    peak_after: Duration::from_millis(200), // doesn't matter
-    loop_after: None,
+    color: (1.0 / 1.0, 1.0 / 9.0, 1.0 / 1.0),
+    bounds: (0.0, 1.0),
+    peak_after: Duration::from_millis(100),
+    loop_after: Some(Duration::from_millis(400)),
 };
 
-async fn click<'a>(writer: &mut HidWriter<'a>, mut signal_pin: Input<'a>, signal: &leds::Signal) {
-    signal.signal(ENTER_TRIGGERED_ANIMATION);
+/// Mirrors the host's Caps Lock
+const CAPS_LOCK_COLOR: (f64, f64, f64) = (1.0, 0.0, 0.0);
+
+/// Waiting for a press, in the profile's color (or red while Caps Lock is on)
+/// This will tail off and is the "default" state after an action, or keep breathing
+/// slowly while jiggling the mouse or while Caps Lock is on.
+fn idle_animation(clicker: &Clicker, lock_keys: &LockSignal) -> leds::Animation {
+    let caps_lock = lock_keys.try_get().is_some_and(|keys| keys.caps_lock);
+    leds::Animation {
+        color: match caps_lock {
+            true => CAPS_LOCK_COLOR,
+            false => clicker.profile().color,
+        },
+        bounds: (0.3, 1.),
+        peak_after: Duration::from_millis(200),
+        loop_after: (caps_lock || clicker.jiggling()).then_some(Duration::from_secs(3)),
+    }
+}
+
+async fn click<'a>(
+    mut devices: HidDevices<'_, 'a>,
+    mut button: GesturePin<'a>,
+    mut serial: CdcAcmClass<'a, Driver<'a, USB>>,
+    mut settings: Settings<impl Storage>,
+    signal: &leds::Signal,
+    wakeup: &Wakeup,
+    lock_keys: &LockSignal,
+) {
+    let mut clicker = Clicker::new(PROFILES);
+    info!("ghostwriter clicker profile: {}", clicker.profile().name);
+    signal.signal(idle_animation(&clicker, lock_keys));
+    let mut jiggler = None;
+    let mut lock_changes = unwrap!(lock_keys.receiver());
+    let mut lines = LineReader::<256>::new();
     loop {
         debug!("ghostwriter clicker waiting for press");
-        signal_pin.wait_for_falling_edge().await;
-
-        debug!("ghostwriter clicker pressed, waiting for release");
-        signal.signal(PRESSED_ANIMATION);
-        if embassy_time::with_timeout(
-            Duration::from_millis(600),
-            signal_pin.wait_for_rising_edge(),
+        let pressed = async {
+            match &mut jiggler {
+                Some(jiggler) => {
+                    let jiggle = mouse::jiggle(&mut devices.mouse, jiggler);
+                    select(button.wait_for_press(), jiggle).await;
+                }
+                None => button.wait_for_press().await,
+            }
+        };
+        match select3(
+            pressed,
+            lock_changes.changed(),
+            lines.next_line(&mut serial),
         )
         .await
-        .is_err()
         {
-            debug!("ghostwriter clicker release timed out, ENTER");
-            signal.signal(ENTER_TRIGGERED_ANIMATION);
-            let _ = writer.write_serialize(&ENTER).await;
-        } else {
-            debug!("ghostwriter clicker released, SPACE");
-            let _ = writer.write_serialize(&SPACE).await;
+            Either3::First(()) => {}
+            Either3::Second(keys) => {
+                debug!("ghostwriter clicker caps lock: {}", keys.caps_lock);
+                signal.signal(idle_animation(&clicker, lock_keys));
+                continue;
+            }
+            Either3::Third(line) => {
+                // The serial port is never closed
+                let line = unwrap!(line);
+                if line.is_ok_and(|line| console::parse(line) == Ok(Command::Send)) {
+                    info!("ghostwriter clicker typing the text sent");
+                    signal.signal(TYPING_ANIMATION);
+                    // In bulk, as fast as the host takes it
+                    let keyboard = &mut devices.keyboard;
+                    let (delay, max_batch) = (keyboard.delay, keyboard.max_batch);
+                    keyboard.delay = bulk::DELAY;
+                    keyboard.max_batch = bulk::MAX_BATCH;
+                    let sent = proxy::type_stream(
+                        &mut serial,
+                        &mut lines,
+                        keyboard,
+                        &FlowControl::default(),
+                        &mut SystemClock,
+                        button.wait_for_press(),
+                        proxy::PROGRESS_EVERY,
+                    )
+                    .await;
+                    keyboard.delay = delay;
+                    keyboard.max_batch = max_batch;
+                    info!("ghostwriter clicker typed {} bytes", sent.typed);
+                    if sent.cancelled {
+                        // The press that cancelled it does nothing else
+                        button.next().await;
+                    }
+                    signal.signal(idle_animation(&clicker, lock_keys));
+                    continue;
+                }
+                let mut handler = Console {
+                    clicker: &mut clicker,
+                    devices: &mut devices,
+                    jiggler: &mut jiggler,
+                    settings: &mut settings,
+                    wakeup,
+                    lock_keys,
+                };
+                console::respond(&mut serial, line, &mut handler).await;
+                signal.signal(idle_animation(&clicker, lock_keys));
+                continue;
+            }
+        }
+
+        debug!("ghostwriter clicker pressed, waiting for gesture");
+        signal.signal(PRESSED_ANIMATION);
+        let mut gesture = button.next().await;
+        if wakeup.suspended.get() {
+            // The host doesn't read reports while suspended
+            wakeup.request.signal(());
+            signal.signal(idle_animation(&clicker, lock_keys));
+            continue;
         }
-        Timer::after(DELAY).await;
-        ghostwriter::keyboard::release_keys(writer).await;
-        Timer::after(DELAY).await;
 
-        signal_pin.wait_for_high().await; // workaround for https://github.com/embassy-rs/embassy/issues/4790
+        loop {
+            match clicker.handle(gesture) {
+                Some(Action::NextProfile) => {
+                    info!("ghostwriter clicker profile: {}", clicker.profile().name);
+                }
+                Some(Action::ToggleJiggler) => {
+                    info!("ghostwriter clicker jiggling: {}", clicker.jiggling());
+                    jiggler = new_jiggler(&clicker);
+                }
+                Some(action) => clicker::perform(&mut devices, action).await,
+                None => debug!("ghostwriter clicker ignoring gesture"),
+            }
+            signal.signal(idle_animation(&clicker, lock_keys));
+
+            // A long press goes on until the release (a very long press, holds)
+            if matches!(
+                gesture,
+                Gesture::Click | Gesture::DoubleClick | Gesture::TripleClick | Gesture::Release
+            ) {
+                break;
+            }
+            gesture = button.next().await;
+        }
     }
 }
 
-const DELAY: Duration = Duration::from_millis(30);
+/// A jiggler if the clicker is jiggling
+fn new_jiggler(clicker: &Clicker) -> Option<Jiggler> {
+    clicker
+        .jiggling()
+        .then(|| Jiggler::new(JiggleConfig::default(), RoscRng.next_u64(), Instant::now()))
+}
+
+/// Carries out the console's commands
+struct Console<'c, 'w, 'd, S> {
+    clicker: &'c mut Clicker,
+    devices: &'c mut HidDevices<'w, 'd>,
+    jiggler: &'c mut Option<Jiggler>,
+    settings: &'c mut Settings<S>,
+    wakeup: &'c Wakeup,
+    lock_keys: &'c LockSignal,
+}
+
+impl<S: Storage> console::Handler for Console<'_, '_, '_, S> {
+    async fn handle(
+        &mut self,
+        command: Command<'_>,
+        reply: &mut Reply,
+    ) -> Result<(), console::Error> {
+        let on_off = |on| if on { "on" } else { "off" };
+        match command {
+            Command::Version => {
+                let _ = writeln!(reply, "ghostwriter clicker {}", console::VERSION);
+            }
+            Command::Status => {
+                let caps_lock = self.lock_keys.try_get().is_some_and(|keys| keys.caps_lock);
+                let _ = writeln!(reply, "profile: {}", self.clicker.profile().name);
+                let _ = writeln!(reply, "jiggle: {}", on_off(self.clicker.jiggling()));
+                let _ = writeln!(reply, "caps lock: {}", on_off(caps_lock));
+                let _ = writeln!(reply, "suspended: {}", self.wakeup.suspended.get());
+            }
+            Command::Get("jiggle") => {
+                let _ = writeln!(reply, "{}", on_off(self.clicker.jiggling()));
+            }
+            Command::Get(setting) => {
+                self.settings.config.get(setting, reply)?;
+                if setting == "poll-ms" {
+                    let _ = core::write!(reply, " (keyboard: {})", bulk::POLL_MS);
+                }
+                let _ = writeln!(reply);
+            }
+            Command::Set("jiggle", value) => {
+                let jiggling = match value {
+                    "on" => true,
+                    "off" => false,
+                    _ => return Err(console::Error::InvalidValue),
+                };
+                if jiggling != self.clicker.jiggling() {
+                    self.clicker.set_jiggling(jiggling);
+                    *self.jiggler = new_jiggler(self.clicker);
+                }
+            }
+            Command::Set(setting, value) => {
+                let config = &mut self.settings.config;
+                config.set(setting, value)?;
+                config::store(&mut self.settings.storage, config).await;
+                info!("ghostwriter clicker saved setting {}", setting);
+                match setting {
+                    "delay" => {
+                        self.devices.keyboard.delay = config.delay;
+                        self.devices.consumer.delay = config.delay;
+                        self.devices.system.delay = config.delay;
+                        self.devices.mouse.delay = config.delay;
+                    }
+                    "batch" => self.devices.keyboard.max_batch = config.max_batch,
+                    // The keyboard is built with `bulk::POLL_MS`, so that `send` can type fast
+                    "poll-ms" => {
+                        let _ = writeln!(
+                            reply,
+                            "saved, restart to apply (but to the keyboard, always polled every {} ms)",
+                            bulk::POLL_MS
+                        );
+                    }
+                    _ => {
+                        let _ = writeln!(reply, "saved, restart to apply");
+                    }
+                }
+            }
+            Command::Mode(name) => {
+                let profile = self
+                    .clicker
+                    .switch_to(name)
+                    .ok_or(console::Error::UnknownMode)?;
+                info!("ghostwriter clicker profile: {}", profile.name);
+            }
+            Command::Type(text) => self.devices.keyboard.type_str(text).await,
+            Command::Macro(name) => {
+                let steps = clicker::find_macro(name).ok_or(console::Error::UnknownMacro)?;
+                clicker::perform(self.devices, &Action::Macro(steps)).await;
+            }
+            // Answered by the console itself, and streamed by the click loop
+            Command::Help | Command::Send => {}
+        }
+        Ok(())
+    }
+}
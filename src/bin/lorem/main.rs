@@ -21,7 +21,12 @@ use embassy_usb::class::hid;
 
 use rand_distr::{ChiSquared, Distribution, Normal};
 
+use ghostwriter::button::debounce::Policy;
+use ghostwriter::button::GesturePin;
+use ghostwriter::config;
+use ghostwriter::keyboard::{self, LockKeysHandler, LockSignal};
 use ghostwriter::leds;
+use ghostwriter::save::{self, FlashStorage};
 
 mod text;
 
@@ -29,7 +34,7 @@ bind_interrupts!(struct Irqs {
     USBCTRL_IRQ => InterruptHandler<USB>;
 });
 
-type HidWriter<'a> = hid::HidWriter<'a, Driver<'a, USB>, 8>;
+type HidWriter<'a> = keyboard::HidWriter<'a, Driver<'a, USB>>;
 
 #[embassy_executor::main]
 async fn main(_spawner: Spawner) {
@@ -38,14 +43,23 @@ async fn main(_spawner: Spawner) {
     // Create the driver, from the HAL.
     let driver = Driver::new(p.USB, Irqs);
 
+    // Read the settings (see ghostwriter::config)
+    let mut flash = save::Flash::new_blocking(p.FLASH);
+    let mut storage = FlashStorage {
+        flash: &mut flash,
+        offset: config::CONFIG_OFFSET,
+    };
+    let settings = config::load(&mut storage).await;
+
     // Create embassy-usb Config
-    let mut config = embassy_usb::Config::new(0xc0de, 0xcafe);
-    config.manufacturer = Some("Boo-inc");
-    config.product = Some("Ghostwriter");
-    config.serial_number = Some("0oooo00000");
+    let config = settings.usb_config();
 
     let mut hid_state = hid::State::new(); // HID state
 
+    // The host's lock keys, from the output reports of either pipe
+    let lock_keys = LockSignal::new();
+    let mut control_handler = LockKeysHandler { signal: &lock_keys };
+
     // Create embassy-usb DeviceBuilder using the driver and config.
     // It needs some buffers for building the descriptors.
     let mut config_descriptor = [0; 256];
@@ -64,12 +78,15 @@ async fn main(_spawner: Spawner) {
     // Create classes on the builder.
     let config = embassy_usb::class::hid::Config {
         report_descriptor: KeyboardReport::desc(),
-        request_handler: None,
-        poll_ms: 60,
+        request_handler: Some(&mut control_handler),
+        poll_ms: settings.poll_ms,
         max_packet_size: 64,
     };
 
-    let mut writer = hid::HidWriter::<_, 8>::new(&mut builder, &mut hid_state, config);
+    let hid = keyboard::HidReaderWriter::new(&mut builder, &mut hid_state, config);
+    let (reader, mut writer) = hid.split();
+    let mut reader_handler = LockKeysHandler { signal: &lock_keys };
+    let reader_fut = reader.run(false, &mut reader_handler);
 
     // Build the builder and run the device.
     let mut usb = builder.build();
@@ -78,7 +95,7 @@ async fn main(_spawner: Spawner) {
     // Set up the signal pin that will be used to trigger the keyboard.
     let signal_pin = {
         let mut signal_pin = Input::new(p.PIN_23, Pull::None);
-        // Enable the schmitt trigger to slightly debounce.
+        // Enable the schmitt trigger against noise (bounces are handled by the debouncer).
         signal_pin.set_schmitt(true);
         signal_pin
     };
@@ -89,12 +106,13 @@ async fn main(_spawner: Spawner) {
     signal.signal(IDLE_ANIMATION);
 
     // Lorem-specific functions
-    let handle_usb = handle_usb(&mut writer, signal_pin, &signal);
+    let button = GesturePin::new(signal_pin, Policy::default(), settings.thresholds());
+    let handle_usb = handle_usb(&mut writer, button, &signal, &lock_keys);
     let handle_leds = leds::animate_leds(&signal, led_slices);
 
     // Run everything concurrently.
     // If we had made everything `'static` above instead, we could do this using separate tasks instead.
-    join(usb_fut, join(handle_leds, handle_usb)).await;
+    join(join(usb_fut, reader_fut), join(handle_leds, handle_usb)).await;
 }
 
 const IDLE_ANIMATION: leds::Animation = leds::Animation {
@@ -115,8 +133,9 @@ const TYPING_ANIMATION: leds::Animation = leds::Animation {
 
 async fn handle_usb<'a>(
     writer: &mut HidWriter<'a>,
-    mut signal_pin: Input<'a>,
+    mut button: GesturePin<'a>,
     signal: &leds::Signal,
+    lock_keys: &LockSignal,
 ) {
     let mut n_written: usize = 0;
 
@@ -129,7 +148,7 @@ async fn handle_usb<'a>(
     loop {
         debug!("ghostwriter waiting for click");
         // We're stopped and waiting for a click
-        signal_pin.wait_for_falling_edge().await;
+        button.wait_for_press().await;
         debug!("ghostwriter received click");
 
         // Button was pressed, so notify the LEDs
@@ -146,7 +165,8 @@ async fn handle_usb<'a>(
                 let kprd = rand_kprd.sample(&mut RoscRng) as u64;
                 let iki = 30 + 10 * rand_iki.sample(&mut RoscRng) as u64;
 
-                ghostwriter::keyboard::write_ascii_byte(writer, c).await;
+                let caps_lock = lock_keys.try_get().is_some_and(|keys| keys.caps_lock);
+                ghostwriter::keyboard::write_ascii_byte(writer, c, caps_lock).await;
                 Timer::after(Duration::from_millis(kprd)).await;
                 ghostwriter::keyboard::release_keys(writer).await;
                 Timer::after(Duration::from_millis(iki)).await;
@@ -154,7 +174,7 @@ async fn handle_usb<'a>(
         };
 
         // Write until the button is pressed
-        let _ = select(write, signal_pin.wait_for_falling_edge()).await;
+        let _ = select(write, button.wait_for_press()).await;
         debug!("ghostwriter releasing keys");
 
         // Button was pressed, so release all keys in the keyboard and notify the LEDs
@@ -0,0 +1,502 @@
+//! Gestures on the single button
+//!
+//! [`Recognizer`] turns the presses and releases of the button into gestures:
+//!
+//! - [`Gesture::Click`], [`Gesture::DoubleClick`] and [`Gesture::TripleClick`]: one to three
+//!   short presses, each within [`Thresholds::multi_click`] of the previous release. They
+//!   are emitted once no other press follows in time (or right away for the third).
+//! - [`Gesture::LongPress`] and [`Gesture::VeryLongPress`]: the button has been held that
+//!   long. They are emitted while the button is still held, so that the device can react
+//!   without waiting for the release.
+//! - [`Gesture::Hold`]: repeated every [`Thresholds::hold_repeat`] after a long press, as
+//!   long as the button is held, and then [`Gesture::Release`] when it's released.
+//!
+//! The recognizer is pure and driven by timestamps: it is fed the edges with
+//! [`Recognizer::update`], polled for gestures with [`Recognizer::poll`], and
+//! [`Recognizer::deadline`] tells when to poll again if nothing happens in the meantime.
+//! On the device, [`GesturePin`] drives it from the button's pin, through a
+//! [`debounce::Debouncer`].
+
+use embassy_time::{Duration, Instant};
+
+pub mod debounce;
+
+#[cfg(target_os = "none")]
+use debounce::Debouncer;
+#[cfg(target_os = "none")]
+use embassy_futures::select::{select, Either};
+#[cfg(target_os = "none")]
+use embassy_rp::gpio::Input;
+#[cfg(target_os = "none")]
+use embassy_time::Timer;
+
+/// What the button did
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Gesture {
+    Click,
+    DoubleClick,
+    TripleClick,
+    LongPress,
+    VeryLongPress,
+    Hold,
+    Release,
+}
+
+/// Timings of the gestures
+#[derive(Clone, Copy, Debug)]
+pub struct Thresholds {
+    /// Maximum time between releasing the button and pressing it again for a multi-click
+    pub multi_click: Duration,
+    /// Presses at least this long are long presses
+    pub long_press: Duration,
+    /// Presses at least this long are very long presses
+    pub very_long_press: Duration,
+    /// Interval between holds, after a long press
+    pub hold_repeat: Duration,
+}
+
+impl Default for Thresholds {
+    fn default() -> Self {
+        Thresholds {
+            multi_click: Duration::from_millis(300),
+            long_press: Duration::from_millis(600),
+            very_long_press: Duration::from_secs(3),
+            hold_repeat: Duration::from_millis(500),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum State {
+    Idle,
+    /// Pressed since 'since', after 'clicks' short presses
+    Down {
+        since: Instant,
+        clicks: u8,
+        /// Which gestures were emitted for this press so far
+        held: Held,
+    },
+    /// Released at 'since' after 'clicks' short presses, maybe more to come
+    Up {
+        since: Instant,
+        clicks: u8,
+    },
+}
+
+/// How far a press went
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+struct Held {
+    long: bool,
+    very_long: bool,
+    holds: u32,
+}
+
+/// State machine recognizing gestures, see the [module docs](self)
+#[derive(Clone, Debug)]
+pub struct Recognizer {
+    thresholds: Thresholds,
+    state: State,
+    presses: u32,
+}
+
+impl Recognizer {
+    pub fn new(thresholds: Thresholds) -> Recognizer {
+        Recognizer {
+            thresholds,
+            state: State::Idle,
+            presses: 0,
+        }
+    }
+
+    /// Number of presses so far (wrapping), to tell when the button is pushed down
+    pub fn presses(&self) -> u32 {
+        self.presses
+    }
+
+    pub fn is_pressed(&self) -> bool {
+        matches!(self.state, State::Down { .. })
+    }
+
+    /// The button was pressed (or released) at 'now'. Returns the gesture this completes,
+    /// if any. Edges that don't change anything (e.g. two presses in a row) are ignored.
+    ///
+    /// Gestures due before 'now' should be polled first, only expired clicks are caught up
+    /// on.
+    pub fn update(&mut self, pressed: bool, now: Instant) -> Option<Gesture> {
+        // Clicks that timed out without being polled
+        let expired = self.poll_clicks(now);
+
+        match (self.state, pressed) {
+            (State::Idle, true) => self.press(now, 0),
+            (State::Up { clicks, .. }, true) => self.press(now, clicks),
+            (State::Down { held, .. }, false) if held.long => {
+                self.state = State::Idle;
+                return Some(Gesture::Release);
+            }
+            (State::Down { clicks, .. }, false) => {
+                let clicks = clicks + 1;
+                if clicks == 3 {
+                    self.state = State::Idle;
+                    return Some(Gesture::TripleClick);
+                }
+                self.state = State::Up { since: now, clicks };
+            }
+            _ => {}
+        }
+
+        expired
+    }
+
+    fn press(&mut self, now: Instant, clicks: u8) {
+        self.presses = self.presses.wrapping_add(1);
+        self.state = State::Down {
+            since: now,
+            clicks,
+            held: Held::default(),
+        };
+    }
+
+    /// The next gesture due at 'now', if any. Call this until it returns `None`, since
+    /// several gestures can be due at once.
+    pub fn poll(&mut self, now: Instant) -> Option<Gesture> {
+        if let Some(gesture) = self.poll_clicks(now) {
+            return Some(gesture);
+        }
+
+        let State::Down {
+            since,
+            ref mut clicks,
+            ref mut held,
+        } = self.state
+        else {
+            return None;
+        };
+        let thresholds = &self.thresholds;
+
+        if now < since + thresholds.long_press {
+            return None;
+        }
+
+        // A long press after clicks: the clicks are done
+        if *clicks > 0 {
+            let gesture = click_gesture(*clicks);
+            *clicks = 0;
+            return Some(gesture);
+        }
+
+        if !held.long {
+            held.long = true;
+            return Some(Gesture::LongPress);
+        }
+
+        // Whichever is due first, the very long press or the next hold
+        let very_long_at = (!held.very_long).then_some(since + thresholds.very_long_press);
+        let hold_at = next_hold(since, thresholds, held.holds);
+        match very_long_at {
+            Some(at) if at <= hold_at && at <= now => {
+                held.very_long = true;
+                Some(Gesture::VeryLongPress)
+            }
+            _ if hold_at <= now => {
+                held.holds += 1;
+                Some(Gesture::Hold)
+            }
+            _ => None,
+        }
+    }
+
+    /// Clicks that are complete at 'now', i.e. not followed by another press in time
+    fn poll_clicks(&mut self, now: Instant) -> Option<Gesture> {
+        match self.state {
+            State::Up { since, clicks } if now >= since + self.thresholds.multi_click => {
+                self.state = State::Idle;
+                Some(click_gesture(clicks))
+            }
+            _ => None,
+        }
+    }
+
+    /// When to poll next if nothing happens, if ever
+    pub fn deadline(&self) -> Option<Instant> {
+        let thresholds = &self.thresholds;
+        match self.state {
+            State::Idle => None,
+            State::Up { since, .. } => Some(since + thresholds.multi_click),
+            State::Down { since, held, .. } if !held.long => Some(since + thresholds.long_press),
+            State::Down { since, held, .. } => {
+                let hold_at = next_hold(since, thresholds, held.holds);
+                if held.very_long {
+                    Some(hold_at)
+                } else {
+                    Some(hold_at.min(since + thresholds.very_long_press))
+                }
+            }
+        }
+    }
+}
+
+fn click_gesture(clicks: u8) -> Gesture {
+    match clicks {
+        1 => Gesture::Click,
+        2 => Gesture::DoubleClick,
+        _ => Gesture::TripleClick,
+    }
+}
+
+/// When the hold after 'holds' holds is due
+fn next_hold(since: Instant, thresholds: &Thresholds, holds: u32) -> Instant {
+    since + thresholds.long_press + thresholds.hold_repeat * (holds + 1)
+}
+
+/// Gestures of a button that pulls the pin low when pressed, debounced
+#[cfg(target_os = "none")]
+pub struct GesturePin<'d> {
+    pin: Input<'d>,
+    debouncer: Debouncer,
+    recognizer: Recognizer,
+}
+
+#[cfg(target_os = "none")]
+impl<'d> GesturePin<'d> {
+    pub fn new(pin: Input<'d>, debounce: debounce::Policy, thresholds: Thresholds) -> Self {
+        GesturePin {
+            pin,
+            debouncer: Debouncer::new(debounce),
+            recognizer: Recognizer::new(thresholds),
+        }
+    }
+
+    /// Wait for the next gesture
+    pub async fn next(&mut self) -> Gesture {
+        loop {
+            if let Some(gesture) = self.step().await {
+                return gesture;
+            }
+        }
+    }
+
+    /// Wait until the button is pushed down, e.g. for feedback before the gesture is known.
+    /// Gestures completed in the meantime are dropped.
+    pub async fn wait_for_press(&mut self) {
+        let presses = self.recognizer.presses();
+        while self.recognizer.presses() == presses {
+            self.step().await;
+        }
+    }
+
+    /// Wait for an edge or the next deadline (of the debouncer or the recognizer).
+    /// Returns the gesture this completed, if any.
+    ///
+    /// NOTE: this can be cancelled (e.g. in a 'select') without losing anything, the
+    /// state machines are only updated after the await.
+    async fn step(&mut self) -> Option<Gesture> {
+        let now = Instant::now();
+        if let Some(pressed) = self.debouncer.poll(now) {
+            if let Some(gesture) = self.recognizer.update(pressed, now) {
+                return Some(gesture);
+            }
+        }
+        if let Some(gesture) = self.recognizer.poll(now) {
+            return Some(gesture);
+        }
+
+        let deadline = match (self.debouncer.deadline(), self.recognizer.deadline()) {
+            (Some(debounced), Some(recognized)) => Some(debounced.min(recognized)),
+            (debounced, recognized) => debounced.or(recognized),
+        };
+        match deadline {
+            Some(deadline) => {
+                match select(self.pin.wait_for_any_edge(), Timer::at(deadline)).await {
+                    Either::First(()) => {}
+                    // Polled on the next step
+                    Either::Second(()) => return None,
+                }
+            }
+            None => self.pin.wait_for_any_edge().await,
+        }
+
+        // NOTE: the level is read rather than inferred from the edge, which would be
+        // wrong if edges were missed while bouncing
+        let now = Instant::now();
+        let pressed = self.debouncer.update(self.pin.is_low(), now)?;
+        self.recognizer.update(pressed, now)
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+    use Gesture::*;
+
+    /// Run the recognizer on (time in ms, pressed) edges, polling at every deadline until
+    /// 'end', and return the gestures with the time they were emitted at
+    fn gestures(edges: &[(u64, bool)], end: u64) -> Vec<(u64, Gesture)> {
+        let mut recognizer = Recognizer::new(Thresholds::default());
+        let mut out = vec![];
+
+        let poll_until = |recognizer: &mut Recognizer, until: u64, out: &mut Vec<_>| {
+            while let Some(deadline) = recognizer.deadline() {
+                if deadline.as_millis() > until {
+                    break;
+                }
+                // A deadline always has a gesture
+                let gesture = recognizer.poll(deadline).unwrap();
+                out.push((deadline.as_millis(), gesture));
+            }
+        };
+
+        for (at, pressed) in edges {
+            poll_until(&mut recognizer, *at, &mut out);
+            if let Some(gesture) = recognizer.update(*pressed, Instant::from_millis(*at)) {
+                out.push((*at, gesture));
+            }
+        }
+        poll_until(&mut recognizer, end, &mut out);
+
+        out
+    }
+
+    /// Edges for presses given as (start, duration) in ms
+    fn presses(presses: &[(u64, u64)]) -> Vec<(u64, bool)> {
+        presses
+            .iter()
+            .flat_map(|(start, duration)| [(*start, true), (start + duration, false)])
+            .collect()
+    }
+
+    #[test]
+    fn recognizes_clicks() {
+        assert_eq!(gestures(&presses(&[(0, 100)]), 10_000), [(400, Click)]);
+        assert_eq!(
+            gestures(&presses(&[(0, 100), (300, 100)]), 10_000),
+            [(700, DoubleClick)]
+        );
+        // The third click doesn't wait for more
+        assert_eq!(
+            gestures(&presses(&[(0, 100), (300, 100), (600, 100)]), 10_000),
+            [(700, TripleClick)]
+        );
+        // and a fourth starts over
+        assert_eq!(
+            gestures(
+                &presses(&[(0, 100), (300, 100), (600, 100), (900, 100)]),
+                10_000
+            ),
+            [(700, TripleClick), (1300, Click)]
+        );
+    }
+
+    #[test]
+    fn separates_slow_clicks() {
+        // Pressing again right when the window closes is too late
+        assert_eq!(
+            gestures(&presses(&[(0, 100), (400, 100)]), 10_000),
+            [(400, Click), (800, Click)]
+        );
+        assert_eq!(
+            gestures(&presses(&[(0, 100), (399, 100)]), 10_000),
+            [(799, DoubleClick)]
+        );
+    }
+
+    #[test]
+    fn recognizes_long_presses_while_held() {
+        assert_eq!(
+            gestures(&presses(&[(0, 700)]), 10_000),
+            [(600, LongPress), (700, Release)]
+        );
+        assert_eq!(
+            gestures(&presses(&[(0, 3200)]), 10_000),
+            [
+                (600, LongPress),
+                (1100, Hold),
+                (1600, Hold),
+                (2100, Hold),
+                (2600, Hold),
+                (3000, VeryLongPress),
+                (3100, Hold),
+                (3200, Release)
+            ]
+        );
+        // Just short of a long press
+        assert_eq!(gestures(&presses(&[(0, 599)]), 10_000), [(899, Click)]);
+    }
+
+    #[test]
+    fn finishes_clicks_before_long_press() {
+        assert_eq!(
+            gestures(&presses(&[(0, 100), (300, 700)]), 10_000),
+            [(900, Click), (900, LongPress), (1000, Release)]
+        );
+    }
+
+    #[test]
+    fn emits_expired_clicks_on_late_updates() {
+        // Not polled in time, the click is emitted on the next press
+        let mut recognizer = Recognizer::new(Thresholds::default());
+        assert_eq!(recognizer.update(true, Instant::from_millis(0)), None);
+        assert_eq!(recognizer.update(false, Instant::from_millis(100)), None);
+        assert_eq!(
+            recognizer.update(true, Instant::from_millis(5000)),
+            Some(Click)
+        );
+        assert!(recognizer.is_pressed());
+        assert_eq!(recognizer.presses(), 2);
+    }
+
+    #[test]
+    fn ignores_repeated_edges() {
+        assert_eq!(
+            gestures(&[(0, true), (50, true), (100, false), (150, false)], 10_000),
+            [(400, Click)]
+        );
+        assert_eq!(gestures(&[(0, false)], 10_000), []);
+    }
+
+    #[test]
+    fn every_press_sequence_makes_sense() {
+        // All sequences of up to 3 presses of various lengths and gaps
+        let durations = [50, 599, 600, 2000, 3500];
+        let gaps = [1, 299, 300, 1000];
+
+        let mut sequences: Vec<Vec<(u64, u64)>> = vec![vec![]];
+        for _ in 0..3 {
+            let mut longer = vec![];
+            for sequence in &sequences {
+                for duration in durations {
+                    for gap in gaps {
+                        let start = sequence
+                            .last()
+                            .map_or(0, |(start, duration)| start + duration + gap);
+                        let mut sequence = sequence.clone();
+                        sequence.push((start, duration));
+                        longer.push(sequence);
+                    }
+                }
+            }
+            sequences.extend(longer);
+        }
+
+        for sequence in &sequences {
+            let out = gestures(&presses(sequence), 100_000);
+            let count = |gesture| out.iter().filter(|(_, g)| *g == gesture).count();
+
+            // Gestures come in order, and nothing is left pending
+            assert!(
+                out.windows(2).all(|w| w[0].0 <= w[1].0),
+                "{sequence:?}: {out:?}"
+            );
+
+            // Each press is either short (part of a click) or long (and released)
+            let clicks = count(Click) + 2 * count(DoubleClick) + 3 * count(TripleClick);
+            let long = sequence.iter().filter(|(_, d)| *d >= 600).count();
+            assert_eq!(clicks + long, sequence.len(), "{sequence:?}: {out:?}");
+            assert_eq!(count(LongPress), long, "{sequence:?}: {out:?}");
+            assert_eq!(count(Release), long, "{sequence:?}: {out:?}");
+
+            let very_long = sequence.iter().filter(|(_, d)| *d >= 3000).count();
+            assert_eq!(count(VeryLongPress), very_long, "{sequence:?}: {out:?}");
+        }
+    }
+}
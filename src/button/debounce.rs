@@ -0,0 +1,308 @@
+//! Debouncing the button's raw level
+//!
+//! Mechanical contacts bounce for a few milliseconds when pressed and released, which
+//! would otherwise look like several presses. The [`Debouncer`] turns the raw levels (with
+//! their timestamps) into clean ones, with one of these policies:
+//!
+//! - [`Policy::Integrator`]: the level changes once the raw level has spent (in total) that
+//!   long on the other side, like a saturating counter. Robust to noise, but adds latency.
+//! - [`Policy::Lockout`]: the level changes right away, and then ignores the raw level for
+//!   that long. No latency, but a glitch is a press.
+//! - [`Policy::EagerDefer`]: presses are reported right away, and releases once the raw
+//!   level has been released for that long. No latency on presses, and bounces on either
+//!   side are absorbed by the deferred release.
+//!
+//! Like the [`Recognizer`](super::Recognizer) it feeds, it is pure and driven by timestamps.
+
+use embassy_time::{Duration, Instant};
+
+/// How to debounce, see the [module docs](self)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Policy {
+    Integrator(Duration),
+    Lockout(Duration),
+    EagerDefer(Duration),
+}
+
+impl Default for Policy {
+    fn default() -> Self {
+        Policy::EagerDefer(Duration::from_millis(10))
+    }
+}
+
+/// Debouncing state machine, see the [module docs](self)
+#[derive(Clone, Debug)]
+pub struct Debouncer {
+    policy: Policy,
+    /// The last raw level, and when it was seen
+    raw: bool,
+    since: Instant,
+    /// The debounced level
+    level: bool,
+    /// Integrator: time spent pressed as of 'since', saturating at the policy's duration
+    integral: Duration,
+    /// Lockout: when the raw level is listened to again
+    locked_until: Option<Instant>,
+}
+
+impl Debouncer {
+    /// A debouncer for a button that is released
+    pub fn new(policy: Policy) -> Debouncer {
+        Debouncer {
+            policy,
+            raw: false,
+            since: Instant::from_ticks(0),
+            level: false,
+            integral: Duration::from_ticks(0),
+            locked_until: None,
+        }
+    }
+
+    /// The debounced level (pressed or not)
+    pub fn level(&self) -> bool {
+        self.level
+    }
+
+    /// The raw level is 'raw' since 'now'. Returns the new debounced level if it changed.
+    pub fn update(&mut self, raw: bool, now: Instant) -> Option<bool> {
+        // Catch up on what was due before this (e.g. a deferred release)
+        let due = self.poll(now);
+
+        if let Policy::Integrator(_) = self.policy {
+            self.integral = self.integral_at(now);
+        }
+        self.raw = raw;
+        self.since = now;
+
+        match self.policy {
+            Policy::Integrator(_) => due,
+            Policy::Lockout(duration) => {
+                let locked = self.locked_until.is_some_and(|until| now < until);
+                if locked || raw == self.level {
+                    return due;
+                }
+                self.locked_until = Some(now + duration);
+                self.set(raw)
+            }
+            Policy::EagerDefer(_) => {
+                if raw && !self.level {
+                    self.set(true)
+                } else {
+                    due
+                }
+            }
+        }
+    }
+
+    /// The debounced level at 'now', if it changed since the last update (or poll)
+    pub fn poll(&mut self, now: Instant) -> Option<bool> {
+        let deadline = self.deadline()?;
+        if now < deadline {
+            return None;
+        }
+
+        match self.policy {
+            Policy::Integrator(_) => {
+                self.integral = self.integral_at(now);
+                self.since = now;
+            }
+            Policy::Lockout(duration) => self.locked_until = Some(deadline + duration),
+            Policy::EagerDefer(_) => {}
+        }
+        self.set(self.raw)
+    }
+
+    /// When the debounced level will change if the raw level stays the same, if ever
+    pub fn deadline(&self) -> Option<Instant> {
+        if self.raw == self.level {
+            return None;
+        }
+
+        match self.policy {
+            Policy::Integrator(duration) if self.raw => {
+                Some(self.since + (duration - self.integral))
+            }
+            Policy::Integrator(_) => Some(self.since + self.integral),
+            Policy::Lockout(_) => self.locked_until,
+            Policy::EagerDefer(duration) => Some(self.since + duration),
+        }
+    }
+
+    /// Time spent pressed at 'now' (saturating), for the integrator
+    fn integral_at(&self, now: Instant) -> Duration {
+        let Policy::Integrator(duration) = self.policy else {
+            return Duration::from_ticks(0);
+        };
+        let elapsed = now - self.since;
+        if self.raw {
+            (self.integral + elapsed).min(duration)
+        } else {
+            self.integral - elapsed.min(self.integral)
+        }
+    }
+
+    fn set(&mut self, level: bool) -> Option<bool> {
+        if level == self.level {
+            return None;
+        }
+        self.level = level;
+        Some(level)
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::super::{Gesture, Recognizer, Thresholds};
+    use super::*;
+
+    /// A press and release recorded on the device (time in µs, pressed), with the contacts
+    /// bouncing for about 1.5ms on press and 3ms on release
+    const BOUNCY_CLICK: &[(u64, bool)] = &[
+        (0, true),
+        (180, false),
+        (260, true),
+        (610, false),
+        (700, true),
+        (1_450, false),
+        (1_520, true),
+        (95_000, false),
+        (95_400, true),
+        (95_900, false),
+        (96_700, true),
+        (96_800, false),
+        (97_900, true),
+        (98_050, false),
+    ];
+
+    /// Run the debouncer on the trace, polling at every deadline until 'end', and return
+    /// the debounced edges (time in µs, pressed)
+    fn debounce(policy: Policy, trace: &[(u64, bool)], end: u64) -> Vec<(u64, bool)> {
+        let mut debouncer = Debouncer::new(policy);
+        let mut out = vec![];
+
+        let poll_until = |debouncer: &mut Debouncer, until: u64, out: &mut Vec<_>| {
+            while let Some(deadline) = debouncer.deadline() {
+                if deadline.as_micros() > until {
+                    break;
+                }
+                if let Some(level) = debouncer.poll(deadline) {
+                    out.push((deadline.as_micros(), level));
+                }
+            }
+        };
+
+        for (at, raw) in trace {
+            poll_until(&mut debouncer, *at, &mut out);
+            if let Some(level) = debouncer.update(*raw, Instant::from_micros(*at)) {
+                out.push((*at, level));
+            }
+        }
+        poll_until(&mut debouncer, end, &mut out);
+
+        out
+    }
+
+    /// Shift the trace by 'offset' µs
+    fn shifted(trace: &[(u64, bool)], offset: u64) -> impl Iterator<Item = (u64, bool)> + '_ {
+        trace.iter().map(move |(at, raw)| (at + offset, *raw))
+    }
+
+    #[test]
+    fn integrates() {
+        let policy = Policy::Integrator(Duration::from_millis(1));
+        assert_eq!(
+            debounce(policy, BOUNCY_CLICK, 200_000),
+            // 1ms spent pressed (net) at 1.34ms, and back to 0 at 97.1ms
+            [(1_340, true), (97_100, false)]
+        );
+    }
+
+    #[test]
+    fn locks_out() {
+        let policy = Policy::Lockout(Duration::from_millis(5));
+        assert_eq!(
+            debounce(policy, BOUNCY_CLICK, 200_000),
+            [(0, true), (95_000, false)]
+        );
+
+        // A glitch is a press, and the level catches up after the lockout
+        assert_eq!(
+            debounce(policy, &[(0, true), (100, false)], 200_000),
+            [(0, true), (5_000, false)]
+        );
+    }
+
+    #[test]
+    fn presses_eagerly_and_defers_releases() {
+        let policy = Policy::EagerDefer(Duration::from_millis(10));
+        assert_eq!(
+            debounce(policy, BOUNCY_CLICK, 200_000),
+            [(0, true), (108_050, false)]
+        );
+    }
+
+    #[test]
+    fn passes_clean_edges() {
+        for policy in [
+            Policy::Integrator(Duration::from_millis(5)),
+            Policy::Lockout(Duration::from_millis(5)),
+            Policy::EagerDefer(Duration::from_millis(5)),
+        ] {
+            let out = debounce(policy, &[(0, true), (50_000, false)], 200_000);
+            assert_eq!(
+                out.iter().map(|(_, level)| *level).collect::<Vec<_>>(),
+                [true, false]
+            );
+        }
+    }
+
+    /// The gestures for the trace, through the debouncer (if any) and the recognizer
+    fn gestures(policy: Option<Policy>, trace: &[(u64, bool)]) -> Vec<Gesture> {
+        let edges = match policy {
+            Some(policy) => debounce(policy, trace, 10_000_000),
+            None => trace.to_vec(),
+        };
+
+        let mut recognizer = Recognizer::new(Thresholds::default());
+        let mut out = vec![];
+        for (at, pressed) in edges {
+            while let Some(gesture) = recognizer.poll(Instant::from_micros(at)) {
+                out.push(gesture);
+            }
+            out.extend(recognizer.update(pressed, Instant::from_micros(at)));
+        }
+        while let Some(gesture) = recognizer.poll(Instant::from_micros(10_000_000)) {
+            out.push(gesture);
+        }
+        out
+    }
+
+    #[test]
+    fn feeds_clean_gestures() {
+        let double_click: Vec<_> = BOUNCY_CLICK
+            .iter()
+            .copied()
+            .chain(shifted(BOUNCY_CLICK, 200_000))
+            .collect();
+
+        // Bounces look like multi-clicks
+        assert_eq!(
+            gestures(None, BOUNCY_CLICK),
+            [Gesture::TripleClick, Gesture::TripleClick, Gesture::Click]
+        );
+
+        for policy in [
+            Policy::Integrator(Duration::from_millis(2)),
+            Policy::Lockout(Duration::from_millis(5)),
+            Policy::EagerDefer(Duration::from_millis(10)),
+        ] {
+            assert_eq!(gestures(Some(policy), BOUNCY_CLICK), [Gesture::Click]);
+            assert_eq!(
+                gestures(Some(policy), &double_click),
+                [Gesture::DoubleClick],
+                "{policy:?}"
+            );
+        }
+    }
+}
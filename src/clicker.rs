@@ -0,0 +1,582 @@
+//! Gesture to action mappings for the clicker
+//!
+//! A [`Profile`] maps button gestures to [`Action`]s: a key chord, some text, a consumer
+//! control (media keys, see [`crate::consumer`]), a system control (sleep etc., see
+//! [`crate::system`]), a mouse click or scroll (see [`crate::mouse`]), a macro (a sequence
+//! of those), switching to the next profile, or toggling the mouse jiggler (which keeps the
+//! host awake). The built-in [`PROFILES`] cover typical uses of a one-key remote:
+//!
+//! | Profile  | Click              | Double click | Long press              |
+//! |----------|--------------------|--------------|-------------------------|
+//! | Keys     | Space              | Space twice  | Enter                   |
+//! | Slides   | Right (next slide) | Left         | `b` (black screen)      |
+//! | Calls    | Ctrl+D (mute)      | Ctrl+E (cam) |                         |
+//! | Music    | Play/Pause         | Next track   | Previous track          |
+//! | Tests    | Run all (VS Code)  |              | `cargo test` (terminal) |
+//! | Power    | Wake up            |              | Sleep                   |
+//! | Mouse    | Left click         | Scroll down  | Toggle the jiggler      |
+//!
+//! In the Keys profile a triple click presses Space three times, so that tapping fast
+//! never loses a press.
+//!
+//! In every built-in profile a very long press (3 s) switches to the next one. Where a
+//! very long press does something, a long press only does once the button is released, so
+//! that switching doesn't also do what the long press does (e.g. put the host to sleep).
+//!
+//! Over the [serial console](crate::console), profiles can also be switched to by name,
+//! and the [`MACROS`] run by name.
+
+use embassy_time::Duration;
+use usbd_hid::descriptor::KeyboardReport;
+
+use crate::button::Gesture;
+use crate::consumer::{self, Consumer};
+use crate::keyboard::{keycode, modifier, Keyboard};
+use crate::mouse::{self, Mouse};
+use crate::system::{self, SystemControl};
+
+/// Keys pressed (and released) together
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Chord {
+    pub modifier: u8,
+    pub keycode: u8,
+}
+
+impl Chord {
+    pub const fn key(keycode: u8) -> Chord {
+        Chord {
+            modifier: 0,
+            keycode,
+        }
+    }
+
+    pub const fn with(modifier: u8, keycode: u8) -> Chord {
+        Chord { modifier, keycode }
+    }
+
+    pub fn report(&self) -> KeyboardReport {
+        KeyboardReport {
+            modifier: self.modifier,
+            reserved: 0,
+            leds: 0,
+            keycodes: [self.keycode, 0, 0, 0, 0, 0],
+        }
+    }
+}
+
+/// One step of a macro
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Step {
+    Chord(Chord),
+    /// Type the (ASCII) text
+    Text(&'static str),
+    Consumer(consumer::Usage),
+    System(system::Usage),
+    Mouse(mouse::Action),
+    /// Give the host some time, e.g. to open a prompt
+    Pause(Duration),
+}
+
+/// What a gesture does
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Action {
+    Chord(Chord),
+    /// Type the (ASCII) text
+    Text(&'static str),
+    Consumer(consumer::Usage),
+    System(system::Usage),
+    Mouse(mouse::Action),
+    Macro(&'static [Step]),
+    /// Switch to the next profile (handled by [`Clicker`], nothing is sent)
+    NextProfile,
+    /// Start or stop jiggling the mouse (handled by [`Clicker`], nothing is sent)
+    ToggleJiggler,
+}
+
+/// A named mapping from gestures to actions
+#[derive(Clone, Copy, Debug)]
+pub struct Profile {
+    pub name: &'static str,
+    /// Color of the LEDs while the profile is active
+    pub color: (f64, f64, f64),
+    /// Gestures not listed here are ignored
+    pub actions: &'static [(Gesture, Action)],
+}
+
+impl Profile {
+    pub fn action(&self, gesture: Gesture) -> Option<&'static Action> {
+        self.actions
+            .iter()
+            .find(|(g, _)| *g == gesture)
+            .map(|(_, action)| action)
+    }
+}
+
+/// The gesture that switches profiles in the built-in profiles
+pub const SWITCH_PROFILE: Gesture = Gesture::VeryLongPress;
+
+pub const PROFILES: &[Profile] = &[
+    Profile {
+        name: "Keys",
+        color: (1.0, 5.0 / 9.0, 0.3),
+        actions: &[
+            (Gesture::Click, Action::Chord(Chord::key(keycode::SPACE))),
+            // Tapping fast still presses Space every time
+            (Gesture::DoubleClick, Action::Macro(&[SPACE, SPACE])),
+            (Gesture::TripleClick, Action::Macro(&[SPACE, SPACE, SPACE])),
+            (
+                Gesture::LongPress,
+                Action::Chord(Chord::key(keycode::ENTER)),
+            ),
+            (SWITCH_PROFILE, Action::NextProfile),
+        ],
+    },
+    Profile {
+        name: "Slides",
+        color: (0.2, 0.4, 1.0),
+        actions: &[
+            (Gesture::Click, Action::Chord(Chord::key(keycode::RIGHT))),
+            (
+                Gesture::DoubleClick,
+                Action::Chord(Chord::key(keycode::LEFT)),
+            ),
+            (Gesture::LongPress, Action::Text("b")),
+            (SWITCH_PROFILE, Action::NextProfile),
+        ],
+    },
+    Profile {
+        // NOTE: Google Meet shortcuts
+        name: "Calls",
+        color: (0.2, 1.0, 0.3),
+        actions: &[
+            (
+                Gesture::Click,
+                Action::Chord(Chord::with(modifier::CTRL, 7)), // d
+            ),
+            (
+                Gesture::DoubleClick,
+                Action::Chord(Chord::with(modifier::CTRL, 8)), // e
+            ),
+            (SWITCH_PROFILE, Action::NextProfile),
+        ],
+    },
+    Profile {
+        name: "Music",
+        color: (1.0, 0.2, 0.6),
+        actions: &[
+            (Gesture::Click, Action::Consumer(consumer::Usage::PlayPause)),
+            (
+                Gesture::DoubleClick,
+                Action::Consumer(consumer::Usage::NextTrack),
+            ),
+            (
+                Gesture::LongPress,
+                Action::Consumer(consumer::Usage::PreviousTrack),
+            ),
+            (SWITCH_PROFILE, Action::NextProfile),
+        ],
+    },
+    Profile {
+        name: "Tests",
+        color: (0.6, 0.2, 1.0),
+        actions: &[
+            (Gesture::Click, Action::Macro(RUN_ALL_TESTS)),
+            (Gesture::LongPress, Action::Text(CARGO_TEST)),
+            (SWITCH_PROFILE, Action::NextProfile),
+        ],
+    },
+    Profile {
+        name: "Power",
+        color: (1.0, 1.0, 1.0),
+        actions: &[
+            (Gesture::Click, Action::System(system::Usage::WakeUp)),
+            (Gesture::LongPress, Action::System(system::Usage::Sleep)),
+            (SWITCH_PROFILE, Action::NextProfile),
+        ],
+    },
+    Profile {
+        name: "Mouse",
+        color: (0.1, 0.9, 0.9),
+        actions: &[
+            (
+                Gesture::Click,
+                Action::Mouse(mouse::Action::Click(mouse::buttons::LEFT)),
+            ),
+            (
+                Gesture::DoubleClick,
+                Action::Mouse(mouse::Action::Scroll(-5)),
+            ),
+            (Gesture::LongPress, Action::ToggleJiggler),
+            (SWITCH_PROFILE, Action::NextProfile),
+        ],
+    },
+];
+
+const SPACE: Step = Step::Chord(Chord::key(keycode::SPACE));
+
+/// Run all tests, from VS Code's command palette
+const RUN_ALL_TESTS: &[Step] = &[
+    Step::Chord(Chord::with(modifier::CTRL | modifier::SHIFT, 19)), // p
+    Step::Pause(Duration::from_millis(300)),
+    Step::Text("Test: Run All Tests\n"),
+];
+
+/// Run the tests, in a terminal
+const CARGO_TEST: &str = "cargo test\n";
+
+/// Macros that can be run by name
+pub const MACROS: &[(&str, &[Step])] = &[
+    ("run-all-tests", RUN_ALL_TESTS),
+    ("cargo-test", &[Step::Text(CARGO_TEST)]),
+    (
+        "lock-screen",
+        &[Step::Chord(Chord::with(modifier::GUI, 15))], // l
+    ),
+];
+
+/// The macro with that name, if any
+pub fn find_macro(name: &str) -> Option<&'static [Step]> {
+    MACROS
+        .iter()
+        .find(|(n, _)| *n == name)
+        .map(|(_, steps)| *steps)
+}
+
+/// The active profile, out of a list of profiles, and whether the mouse is jiggling
+#[derive(Clone, Debug)]
+pub struct Clicker {
+    profiles: &'static [Profile],
+    current: usize,
+    jiggling: bool,
+    /// The long press's action, until the release (see [`Clicker::handle`])
+    deferred: Option<&'static Action>,
+}
+
+impl Clicker {
+    /// Start with the first profile (there must be at least one)
+    pub fn new(profiles: &'static [Profile]) -> Clicker {
+        assert!(!profiles.is_empty());
+        Clicker {
+            profiles,
+            current: 0,
+            jiggling: false,
+            deferred: None,
+        }
+    }
+
+    pub fn profile(&self) -> &'static Profile {
+        &self.profiles[self.current]
+    }
+
+    /// Whether the mouse should be jiggled (see [`mouse::Jiggler`]), across profiles
+    pub fn jiggling(&self) -> bool {
+        self.jiggling
+    }
+
+    /// Switch to the profile with that name (case insensitive), if there is one
+    pub fn switch_to(&mut self, name: &str) -> Option<&'static Profile> {
+        self.current = self
+            .profiles
+            .iter()
+            .position(|profile| profile.name.eq_ignore_ascii_case(name))?;
+        self.deferred = None;
+        Some(self.profile())
+    }
+
+    pub fn set_jiggling(&mut self, jiggling: bool) {
+        self.jiggling = jiggling;
+    }
+
+    /// The action for the gesture in the active profile, if any. Switches profiles on
+    /// [`Action::NextProfile`] and toggles jiggling on [`Action::ToggleJiggler`] (which are
+    /// still returned, e.g. to show the new state).
+    ///
+    /// If the profile maps [`Gesture::VeryLongPress`], the long press's action is returned
+    /// on the [`Gesture::Release`] instead, unless the press turned into a very long one.
+    pub fn handle(&mut self, gesture: Gesture) -> Option<&'static Action> {
+        let profile = self.profile();
+        let action = match gesture {
+            Gesture::LongPress if profile.action(Gesture::VeryLongPress).is_some() => {
+                self.deferred = profile.action(gesture);
+                return None;
+            }
+            Gesture::VeryLongPress => {
+                self.deferred = None;
+                profile.action(gesture)?
+            }
+            Gesture::Release => self.deferred.take().or(profile.action(gesture))?,
+            _ => profile.action(gesture)?,
+        };
+        match action {
+            Action::NextProfile => self.current = (self.current + 1) % self.profiles.len(),
+            Action::ToggleJiggler => self.jiggling = !self.jiggling,
+            _ => {}
+        }
+        Some(action)
+    }
+}
+
+/// Where actions are sent
+pub struct Devices<K, C, S, M> {
+    pub keyboard: K,
+    pub consumer: C,
+    pub system: S,
+    pub mouse: M,
+}
+
+/// Send the action's reports to the devices
+pub async fn perform<K: Keyboard, C: Consumer, S: SystemControl, M: Mouse>(
+    devices: &mut Devices<K, C, S, M>,
+    action: &Action,
+) {
+    let step = match action {
+        Action::Chord(chord) => Step::Chord(*chord),
+        Action::Text(text) => Step::Text(text),
+        Action::Consumer(usage) => Step::Consumer(*usage),
+        Action::System(usage) => Step::System(*usage),
+        Action::Mouse(action) => Step::Mouse(*action),
+        Action::Macro(steps) => {
+            for step in *steps {
+                perform_step(devices, step).await;
+            }
+            return;
+        }
+        Action::NextProfile | Action::ToggleJiggler => return,
+    };
+    perform_step(devices, &step).await;
+}
+
+async fn perform_step<K: Keyboard, C: Consumer, S: SystemControl, M: Mouse>(
+    devices: &mut Devices<K, C, S, M>,
+    step: &Step,
+) {
+    match step {
+        Step::Chord(chord) => devices.keyboard.tap(&chord.report()).await,
+        Step::Text(text) => devices.keyboard.type_str(text).await,
+        Step::Consumer(usage) => devices.consumer.press(*usage).await,
+        Step::System(usage) => devices.system.press(*usage).await,
+        Step::Mouse(action) => devices.mouse.perform(*action).await,
+        Step::Pause(duration) => devices.keyboard.pause(*duration).await,
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+    use crate::host::Recorder;
+    use usbd_hid::descriptor::MouseReport;
+
+    #[test]
+    fn built_in_profiles_can_switch() {
+        for profile in PROFILES {
+            assert_eq!(
+                profile.action(SWITCH_PROFILE),
+                Some(&Action::NextProfile),
+                "{}",
+                profile.name
+            );
+        }
+    }
+
+    #[test]
+    fn switches_profiles() {
+        let mut clicker = Clicker::new(PROFILES);
+        assert_eq!(clicker.profile().name, "Keys");
+        assert_eq!(
+            clicker.handle(Gesture::Click),
+            Some(&Action::Chord(Chord::key(keycode::SPACE)))
+        );
+        assert_eq!(
+            clicker.handle(Gesture::DoubleClick),
+            Some(&Action::Macro(&[SPACE, SPACE]))
+        );
+        assert_eq!(
+            clicker.handle(Gesture::TripleClick),
+            Some(&Action::Macro(&[SPACE, SPACE, SPACE]))
+        );
+
+        assert_eq!(clicker.handle(SWITCH_PROFILE), Some(&Action::NextProfile));
+        assert_eq!(clicker.profile().name, "Slides");
+        assert_eq!(
+            clicker.handle(Gesture::Click),
+            Some(&Action::Chord(Chord::key(keycode::RIGHT)))
+        );
+
+        // Wraps around
+        for _ in 1..PROFILES.len() {
+            clicker.handle(SWITCH_PROFILE);
+        }
+        assert_eq!(clicker.profile().name, "Keys");
+    }
+
+    #[test]
+    fn switches_profiles_by_name() {
+        let mut clicker = Clicker::new(PROFILES);
+        assert_eq!(clicker.switch_to("music").map(|p| p.name), Some("Music"));
+        assert_eq!(clicker.profile().name, "Music");
+        assert_eq!(clicker.switch_to("Musik").map(|p| p.name), None);
+        assert_eq!(clicker.profile().name, "Music");
+        assert_eq!(clicker.switch_to("KEYS").map(|p| p.name), Some("Keys"));
+    }
+
+    #[test]
+    fn finds_macros() {
+        assert_eq!(find_macro("run-all-tests"), Some(RUN_ALL_TESTS));
+        assert_eq!(
+            find_macro("cargo-test"),
+            Some(&[Step::Text(CARGO_TEST)][..])
+        );
+        assert_eq!(find_macro("cargo"), None);
+        for (name, _) in MACROS {
+            assert!(!name.contains(char::is_whitespace), "{name}");
+        }
+    }
+
+    impl Consumer for Vec<consumer::Report> {
+        async fn send(&mut self, report: &consumer::Report) {
+            self.push(*report);
+        }
+    }
+
+    impl SystemControl for Vec<system::Report> {
+        async fn send(&mut self, report: &system::Report) {
+            self.push(*report);
+        }
+    }
+
+    /// Records the mouse reports, as (buttons, x, y, wheel)
+    impl Mouse for Vec<(u8, i8, i8, i8)> {
+        async fn send(&mut self, report: &MouseReport) {
+            self.push((report.buttons, report.x, report.y, report.wheel));
+        }
+    }
+
+    type Recorders =
+        Devices<Recorder, Vec<consumer::Report>, Vec<system::Report>, Vec<(u8, i8, i8, i8)>>;
+
+    /// The keyboard reports, as (modifier, first keycode)
+    fn sent(keyboard: &Recorder) -> Vec<(u8, u8)> {
+        keyboard
+            .reports
+            .iter()
+            .map(|report| (report.modifier, report.keycodes[0]))
+            .collect()
+    }
+
+    fn perform_recorded(action: &Action) -> Recorders {
+        let mut devices = Devices {
+            keyboard: Recorder::default(),
+            consumer: vec![],
+            system: vec![],
+            mouse: vec![],
+        };
+        embassy_futures::block_on(perform(&mut devices, action));
+        devices
+    }
+
+    #[test]
+    fn performs_actions() {
+        const STEPS: &[Step] = &[
+            Step::Chord(Chord::with(modifier::CTRL, keycode::ENTER)),
+            Step::Pause(Duration::from_millis(100)),
+            Step::Text("Hi"),
+            Step::Consumer(consumer::Usage::Mute),
+            Step::System(system::Usage::Sleep),
+            Step::Mouse(mouse::Action::Scroll(-2)),
+        ];
+        let devices = perform_recorded(&Action::Macro(STEPS));
+        assert_eq!(
+            sent(&devices.keyboard),
+            [
+                (modifier::CTRL, keycode::ENTER),
+                (0, 0),
+                // 'H': shift, then shift+h
+                (modifier::SHIFT, 0),
+                (modifier::SHIFT, 11),
+                (0, 0),
+                (0, 12),
+                (0, 0)
+            ]
+        );
+        assert_eq!(devices.keyboard.pauses, [(2, Duration::from_millis(100))]);
+        assert_eq!(
+            devices.consumer,
+            [
+                consumer::Report::pressed(consumer::Usage::Mute),
+                consumer::Report::RELEASED
+            ]
+        );
+        assert_eq!(
+            devices.system,
+            [
+                system::Report::pressed(system::Usage::Sleep),
+                system::Report::RELEASED
+            ]
+        );
+        assert_eq!(devices.mouse, [(0, 0, 0, -2)]);
+
+        let devices = perform_recorded(&Action::Consumer(consumer::Usage::PlayPause));
+        assert!(devices.keyboard.reports.is_empty());
+        assert_eq!(
+            devices.consumer,
+            [
+                consumer::Report::pressed(consumer::Usage::PlayPause),
+                consumer::Report::RELEASED
+            ]
+        );
+
+        for action in [Action::NextProfile, Action::ToggleJiggler] {
+            let devices = perform_recorded(&action);
+            assert!(devices.keyboard.reports.is_empty());
+            assert!(devices.consumer.is_empty());
+            assert!(devices.system.is_empty());
+            assert!(devices.mouse.is_empty());
+        }
+    }
+
+    #[test]
+    fn toggles_jiggling_across_profiles() {
+        let mut clicker = Clicker::new(PROFILES);
+        while clicker.profile().name != "Mouse" {
+            clicker.handle(SWITCH_PROFILE);
+        }
+        assert!(!clicker.jiggling());
+        assert_eq!(clicker.handle(Gesture::LongPress), None);
+        assert_eq!(
+            clicker.handle(Gesture::Release),
+            Some(&Action::ToggleJiggler)
+        );
+        assert!(clicker.jiggling());
+
+        // Still jiggling in the other profiles
+        clicker.handle(SWITCH_PROFILE);
+        assert!(clicker.jiggling());
+    }
+
+    #[test]
+    fn switching_defers_long_presses() {
+        let mut clicker = Clicker::new(PROFILES);
+        clicker.switch_to("power");
+        let sleep = Some(&Action::System(system::Usage::Sleep));
+
+        // A long press acts once released
+        assert_eq!(clicker.handle(Gesture::LongPress), None);
+        assert_eq!(clicker.handle(Gesture::Hold), None);
+        assert_eq!(clicker.handle(Gesture::Release), sleep);
+        assert_eq!(clicker.handle(Gesture::Release), None);
+
+        // A very long press only switches
+        assert_eq!(clicker.handle(Gesture::LongPress), None);
+        assert_eq!(clicker.handle(SWITCH_PROFILE), Some(&Action::NextProfile));
+        assert_eq!(clicker.handle(Gesture::Release), None);
+        assert_eq!(clicker.profile().name, "Mouse");
+    }
+
+    #[test]
+    fn power_profile_sleeps_on_long_press() {
+        let power = PROFILES.iter().find(|p| p.name == "Power").unwrap();
+        let devices = perform_recorded(power.action(Gesture::LongPress).unwrap());
+        assert_eq!(devices.system[0].to_bytes(), [0x82]);
+    }
+}
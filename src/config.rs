@@ -0,0 +1,515 @@
+//! Runtime configuration, persisted in flash
+//!
+//! The settings that used to be compiled into each binary (USB ids and strings, polling
+//! interval, typing delay, long press threshold) are read from a [`Config`] at boot, which
+//! the clicker's serial console can change (see [`Config::set`]).
+//!
+//! The LED animation presets are not settings: they stay compiled in (e.g. in
+//! [`crate::cyoa`]), since they are made of colors and curves the console has no syntax for.
+//!
+//! It is stored in its own flash sector, with the same kind of [`Storage`] as the saves.
+//! The record is:
+//!
+//! ```text
+//! | "GWCF" | version | payload length | payload | CRC-16 of all that (LE) |
+//!
+//! payload: | tag | value length | value | ... (one per setting)
+//! ```
+//!
+//! Numbers are little endian, on as few bytes as they need (see `number_size`), and
+//! strings are UTF-8. Settings that are missing (e.g. added by a later version of the
+//! firmware) keep their default, and unknown or invalid ones are skipped, so adding or
+//! removing settings needs no new [`VERSION`]. Changing how a tag is stored does: records
+//! of older versions are then migrated while being read. Erased flash, a bad CRC or a newer
+//! version read as the defaults.
+//!
+//! Versions:
+//!
+//! 1. Every number on 2 bytes.
+//! 2. `batch` and `poll-ms` on 1 byte.
+
+use core::fmt::{self, Write};
+use core::str;
+
+use embassy_time::Duration;
+
+use crate::button::Thresholds;
+use crate::console::Error;
+use crate::save::{Storage, SAVE_SIZE};
+
+/// Bumped whenever the way a tag is stored changes
+pub const VERSION: u8 = 2;
+
+const MAGIC: &[u8; 4] = b"GWCF";
+
+/// Magic, version and payload length
+const HEADER_SIZE: usize = MAGIC.len() + 2;
+const CRC_SIZE: usize = 2;
+
+/// Maximum length of the strings, in bytes
+pub const TEXT_SIZE: usize = 32;
+
+/// Offset of the config in flash, right below the cyoa saves and the last story played
+/// (see [`crate::save`])
+#[cfg(target_os = "none")]
+pub const CONFIG_OFFSET: u32 = crate::save::save_offset(crate::save::MAX_STORIES + 1);
+
+// Reserved in memory.x too
+#[cfg(target_os = "none")]
+const _: () = assert!(CONFIG_OFFSET >= crate::save::STORAGE_OFFSET);
+
+/// A short string, stored inline
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Text {
+    bytes: [u8; TEXT_SIZE],
+    len: usize,
+}
+
+impl Text {
+    /// The string, if it fits
+    pub fn new(s: &str) -> Option<Text> {
+        let mut bytes = [0; TEXT_SIZE];
+        bytes.get_mut(..s.len())?.copy_from_slice(s.as_bytes());
+        Some(Text {
+            bytes,
+            len: s.len(),
+        })
+    }
+
+    pub fn as_str(&self) -> &str {
+        // Only ever built from strings
+        str::from_utf8(&self.bytes[..self.len]).unwrap_or("")
+    }
+}
+
+impl fmt::Debug for Text {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self.as_str(), f)
+    }
+}
+
+/// The settings, see the [module docs](self)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Config {
+    /// Time to wait after each report (cyoa paces its typing itself)
+    pub delay: Duration,
+    /// How many keys typing presses in one report (see [`crate::keyboard::batch_report`])
+    pub max_batch: usize,
+    /// Presses at least this long are long presses (below the very long presses' 3 s)
+    pub long_press: Duration,
+    /// Polling interval of the HID interfaces, in ms (but the clicker's keyboard, polled
+    /// often enough for bulk typing, as its console says)
+    pub poll_ms: u8,
+    pub vendor_id: u16,
+    pub product_id: u16,
+    pub manufacturer: Text,
+    pub product: Text,
+    pub serial_number: Text,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            delay: Duration::from_millis(30),
+            max_batch: 1,
+            long_press: Duration::from_millis(600),
+            poll_ms: 60,
+            vendor_id: 0xc0de,
+            product_id: 0xcafe,
+            manufacturer: Text::new("Boo-inc").unwrap(),
+            product: Text::new("Ghostwriter").unwrap(),
+            serial_number: Text::new("0oooo00000").unwrap(),
+        }
+    }
+}
+
+/// The settings' names (for the console), in the order of their tags (from 1)
+pub const KEYS: &[&str] = &[
+    "delay",
+    "batch",
+    "long-press",
+    "poll-ms",
+    "vid",
+    "pid",
+    "manufacturer",
+    "product",
+    "serial",
+];
+
+/// A setting's value, as stored
+enum Value<'a> {
+    Number(u16),
+    Text(&'a str),
+    /// Neither (e.g. a number of the wrong size, or invalid UTF-8)
+    Invalid,
+}
+
+impl Config {
+    /// The USB device's config, with these ids and strings
+    pub fn usb_config(&self) -> embassy_usb::Config<'_> {
+        let mut config = embassy_usb::Config::new(self.vendor_id, self.product_id);
+        config.manufacturer = Some(self.manufacturer.as_str());
+        config.product = Some(self.product.as_str());
+        config.serial_number = Some(self.serial_number.as_str());
+        config
+    }
+
+    /// The button's thresholds, with this long press
+    pub fn thresholds(&self) -> Thresholds {
+        Thresholds {
+            long_press: self.long_press,
+            ..Default::default()
+        }
+    }
+
+    /// The setting with that name (see [`KEYS`]), written as its value for [`Config::set`]
+    pub fn get(&self, key: &str, out: &mut impl Write) -> Result<(), Error> {
+        let _ = match self.value(tag(key)?) {
+            Value::Number(number) if matches!(key, "vid" | "pid") => write!(out, "{number:#06x}"),
+            Value::Number(number) => write!(out, "{number}"),
+            Value::Text(text) => write!(out, "{text}"),
+            Value::Invalid => Ok(()),
+        };
+        Ok(())
+    }
+
+    /// Change the setting with that name, from its value (numbers are decimal, or
+    /// hexadecimal with '0x'; durations are in ms)
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), Error> {
+        let tag = tag(key)?;
+        let value = match self.value(tag) {
+            Value::Number(_) => {
+                let number = match value.strip_prefix("0x") {
+                    Some(hex) => u16::from_str_radix(hex, 16),
+                    None => value.parse(),
+                };
+                Value::Number(number.map_err(|_| Error::InvalidValue)?)
+            }
+            Value::Text(_) | Value::Invalid => Value::Text(value),
+        };
+        match self.read_setting(tag, value) {
+            true => Ok(()),
+            false => Err(Error::InvalidValue),
+        }
+    }
+
+    fn value(&self, tag: u8) -> Value<'_> {
+        let ms = |duration: Duration| Value::Number(duration.as_millis() as u16);
+        match tag {
+            1 => ms(self.delay),
+            2 => Value::Number(self.max_batch as u16),
+            3 => ms(self.long_press),
+            4 => Value::Number(self.poll_ms as u16),
+            5 => Value::Number(self.vendor_id),
+            6 => Value::Number(self.product_id),
+            7 => Value::Text(self.manufacturer.as_str()),
+            8 => Value::Text(self.product.as_str()),
+            _ => Value::Text(self.serial_number.as_str()),
+        }
+    }
+
+    /// Set the setting from its value, returning 'false' if the value is invalid
+    fn read_setting(&mut self, tag: u8, value: Value) -> bool {
+        let ms = |number| Duration::from_millis(number as u64);
+        match (tag, value) {
+            (1, Value::Number(delay @ 0..=1000)) => self.delay = ms(delay),
+            (2, Value::Number(batch @ 1..=6)) => self.max_batch = batch as usize,
+            (3, Value::Number(long_press @ 100..=2_000)) => self.long_press = ms(long_press),
+            (4, Value::Number(poll_ms @ 1..=255)) => self.poll_ms = poll_ms as u8,
+            (5, Value::Number(vendor_id)) => self.vendor_id = vendor_id,
+            (6, Value::Number(product_id)) => self.product_id = product_id,
+            (7..=9, Value::Text(text)) if !text.is_empty() => {
+                let Some(text) = Text::new(text) else {
+                    return false;
+                };
+                match tag {
+                    7 => self.manufacturer = text,
+                    8 => self.product = text,
+                    _ => self.serial_number = text,
+                }
+            }
+            _ => return false,
+        }
+        true
+    }
+}
+
+/// The tag of the setting with that name
+fn tag(key: &str) -> Result<u8, Error> {
+    let ix = KEYS
+        .iter()
+        .position(|k| *k == key)
+        .ok_or(Error::UnknownSetting)?;
+    Ok(ix as u8 + 1)
+}
+
+/// The size of the tag's numbers in records of that version, or None for strings (and
+/// unknown tags). This is where older versions are migrated.
+fn number_size(version: u8, tag: u8) -> Option<usize> {
+    match (version, tag) {
+        (1, 1..=6) => Some(2),
+        (_, 2 | 4) => Some(1),
+        (_, 1..=6) => Some(2),
+        _ => None,
+    }
+}
+
+/// Encode the config
+pub fn encode(config: &Config, buf: &mut [u8; SAVE_SIZE]) {
+    buf.fill(0xFF);
+    let mut len = HEADER_SIZE;
+    for tag in 1..=KEYS.len() as u8 {
+        let number;
+        let value = match config.value(tag) {
+            Value::Number(n) => {
+                number = n.to_le_bytes();
+                &number[..number_size(VERSION, tag).unwrap_or(2)]
+            }
+            Value::Text(text) => text.as_bytes(),
+            Value::Invalid => continue,
+        };
+        buf[len] = tag;
+        buf[len + 1] = value.len() as u8;
+        buf[len + 2..len + 2 + value.len()].copy_from_slice(value);
+        len += 2 + value.len();
+    }
+
+    buf[..MAGIC.len()].copy_from_slice(MAGIC);
+    buf[MAGIC.len()] = VERSION;
+    buf[MAGIC.len() + 1] = (len - HEADER_SIZE) as u8;
+    let crc = crc16(&buf[..len]);
+    buf[len..len + CRC_SIZE].copy_from_slice(&crc.to_le_bytes());
+}
+
+/// Decode the config, if there is a valid one
+pub fn decode(buf: &[u8; SAVE_SIZE]) -> Option<Config> {
+    let version = buf[MAGIC.len()];
+    if &buf[..MAGIC.len()] != MAGIC || !(1..=VERSION).contains(&version) {
+        return None;
+    }
+    let len = HEADER_SIZE + buf[MAGIC.len() + 1] as usize;
+    let crc = buf.get(len..len + CRC_SIZE)?;
+    if crc16(&buf[..len]).to_le_bytes() != crc {
+        return None;
+    }
+
+    let mut config = Config::default();
+    let mut payload = &buf[HEADER_SIZE..len];
+    while let [tag, len, rest @ ..] = payload {
+        let (value, rest) = rest.split_at_checked(*len as usize)?;
+        let value = match number_size(version, *tag) {
+            Some(size) if value.len() == size => {
+                let mut number = [0; 2];
+                number[..size].copy_from_slice(value);
+                Value::Number(u16::from_le_bytes(number))
+            }
+            Some(_) => Value::Invalid,
+            None => str::from_utf8(value).map_or(Value::Invalid, Value::Text),
+        };
+        // Invalid values keep the default
+        config.read_setting(*tag, value);
+        payload = rest;
+    }
+    Some(config)
+}
+
+/// Read the config, or the defaults if there is none
+pub async fn load(storage: &mut impl Storage) -> Config {
+    let mut buf = [0; SAVE_SIZE];
+    storage.read(&mut buf).await;
+    decode(&buf).unwrap_or_default()
+}
+
+pub async fn store(storage: &mut impl Storage, config: &Config) {
+    let mut buf = [0; SAVE_SIZE];
+    encode(config, &mut buf);
+    storage.write(&buf).await;
+}
+
+/// CRC-16/CCITT-FALSE
+fn crc16(data: &[u8]) -> u16 {
+    let mut crc = 0xFFFF_u16;
+    for byte in data {
+        crc ^= (*byte as u16) << 8;
+        for _ in 0..8 {
+            crc = match crc & 0x8000 {
+                0 => crc << 1,
+                _ => (crc << 1) ^ 0x1021,
+            };
+        }
+    }
+    crc
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+    use crate::save::NoStorage;
+
+    fn custom() -> Config {
+        Config {
+            delay: Duration::from_millis(5),
+            max_batch: 6,
+            poll_ms: 1,
+            product_id: 0xbeef,
+            product: Text::new("Clicker").unwrap(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn checks_crc() {
+        assert_eq!(crc16(b"123456789"), 0x29B1);
+    }
+
+    #[test]
+    fn round_trips() {
+        let mut buf = [0; SAVE_SIZE];
+        for config in [Config::default(), custom()] {
+            encode(&config, &mut buf);
+            assert_eq!(decode(&buf), Some(config));
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_records() {
+        // Erased flash
+        assert_eq!(decode(&[0xFF; SAVE_SIZE]), None);
+        assert_eq!(
+            embassy_futures::block_on(load(&mut NoStorage)),
+            Config::default()
+        );
+
+        let mut buf = [0; SAVE_SIZE];
+        encode(&custom(), &mut buf);
+
+        // Corrupted
+        let mut corrupted = buf;
+        corrupted[HEADER_SIZE + 2] ^= 1;
+        assert_eq!(decode(&corrupted), None);
+
+        // From a newer firmware
+        let mut newer = buf;
+        newer[MAGIC.len()] = VERSION + 1;
+        assert_eq!(decode(&newer), None);
+    }
+
+    /// A record of that version with these settings (as tag, value)
+    fn record(version: u8, settings: &[(u8, &[u8])]) -> [u8; SAVE_SIZE] {
+        let mut buf = [0xFF; SAVE_SIZE];
+        let mut len = HEADER_SIZE;
+        for (tag, value) in settings {
+            buf[len] = *tag;
+            buf[len + 1] = value.len() as u8;
+            buf[len + 2..len + 2 + value.len()].copy_from_slice(value);
+            len += 2 + value.len();
+        }
+        buf[..MAGIC.len()].copy_from_slice(MAGIC);
+        buf[MAGIC.len()] = version;
+        buf[MAGIC.len() + 1] = (len - HEADER_SIZE) as u8;
+        let crc = crc16(&buf[..len]);
+        buf[len..len + CRC_SIZE].copy_from_slice(&crc.to_le_bytes());
+        buf
+    }
+
+    #[test]
+    fn skips_settings() {
+        // Missing settings keep their default, unknown ones are skipped
+        let buf = record(VERSION, &[(2, &[3]), (42, b"later"), (8, b"Clicker")]);
+        let expected = Config {
+            max_batch: 3,
+            product: Text::new("Clicker").unwrap(),
+            ..Default::default()
+        };
+        assert_eq!(decode(&buf), Some(expected));
+
+        // Invalid values too
+        let buf = record(VERSION, &[(2, &[9]), (4, &[0]), (7, b""), (8, &[0xFF])]);
+        assert_eq!(decode(&buf), Some(Config::default()));
+        // Numbers of the wrong size (even when not UTF-8)
+        let buf = record(
+            VERSION,
+            &[(1, &[0xFF, 0xFE, 0xFD]), (5, &[1]), (2, &[3, 0])],
+        );
+        assert_eq!(decode(&buf), Some(Config::default()));
+
+        // Truncated
+        let buf = record(VERSION, &[(1, &[10, 0]), (8, b"Clicker")]);
+        let mut truncated = buf;
+        truncated[MAGIC.len() + 1] -= 1;
+        let len = HEADER_SIZE + truncated[MAGIC.len() + 1] as usize;
+        let crc = crc16(&truncated[..len]);
+        truncated[len..len + CRC_SIZE].copy_from_slice(&crc.to_le_bytes());
+        assert_eq!(decode(&truncated), None);
+    }
+
+    #[test]
+    fn migrates_settings() {
+        // Version 1 stored every number on 2 bytes
+        let settings: &[(u8, &[u8])] = &[
+            (1, &[15, 0]),
+            (2, &[6, 0]),
+            (3, &[0x20, 0x03]),
+            (4, &[1, 0]),
+            (6, &[0xef, 0xbe]),
+            (8, b"Clicker"),
+        ];
+        let expected = Config {
+            delay: Duration::from_millis(15),
+            max_batch: 6,
+            long_press: Duration::from_millis(800),
+            poll_ms: 1,
+            product_id: 0xbeef,
+            product: Text::new("Clicker").unwrap(),
+            ..Default::default()
+        };
+        assert_eq!(decode(&record(1, settings)), Some(expected));
+
+        // And is written back as the current version
+        let mut buf = [0; SAVE_SIZE];
+        encode(&expected, &mut buf);
+        assert_eq!(buf[MAGIC.len()], VERSION);
+        assert_eq!(decode(&buf), Some(expected));
+        // Where they are not valid anymore
+        assert_eq!(decode(&record(VERSION, settings)).unwrap().max_batch, 1);
+    }
+
+    #[test]
+    fn edits_settings() {
+        let mut config = Config::default();
+        let get = |config: &Config, key| {
+            let mut out = String::new();
+            config.get(key, &mut out).map(|()| out)
+        };
+
+        assert_eq!(get(&config, "delay").as_deref(), Ok("30"));
+        assert_eq!(get(&config, "vid").as_deref(), Ok("0xc0de"));
+        assert_eq!(get(&config, "product").as_deref(), Ok("Ghostwriter"));
+        assert_eq!(get(&config, "speed"), Err(Error::UnknownSetting));
+
+        assert_eq!(config.set("delay", "15"), Ok(()));
+        assert_eq!(config.set("pid", "0xBEEF"), Ok(()));
+        assert_eq!(config.set("product", "My clicker"), Ok(()));
+        assert_eq!(config.delay, Duration::from_millis(15));
+        assert_eq!(get(&config, "pid").as_deref(), Ok("0xbeef"));
+        assert_eq!(config.product.as_str(), "My clicker");
+
+        assert_eq!(config.set("batch", "7"), Err(Error::InvalidValue));
+        assert_eq!(config.set("poll-ms", "fast"), Err(Error::InvalidValue));
+        assert_eq!(
+            config.set("serial", &"0".repeat(40)),
+            Err(Error::InvalidValue)
+        );
+        assert_eq!(config.set("speed", "3"), Err(Error::UnknownSetting));
+        assert_eq!(config.max_batch, 1);
+
+        // Every setting reads back as it is set
+        let edited = config;
+        for key in KEYS {
+            let value = get(&edited, key).unwrap();
+            assert_eq!(config.set(key, &value), Ok(()), "{key}");
+        }
+        assert_eq!(config, edited);
+    }
+}
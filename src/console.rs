@@ -0,0 +1,493 @@
+//! A line-based command console, over the USB serial port (CDC-ACM)
+//!
+//! The host sends one command per line (ending with CR, LF or both), and the device
+//! answers with some lines of output then `ok`, or with `error: <message>`:
+//!
+//! ```text
+//! > get delay
+//! 30
+//! ok
+//! > mode slides
+//! ok
+//! > frobnicate
+//! error: unknown command
+//! ```
+//!
+//! Commands are parsed into a [`Command`] (see [`HELP`] for the list), and carried out by
+//! a [`Handler`], which the firmware provides. The serial port is a [`Transport`], which an
+//! in-memory one stands in for on the host ([`crate::host::MemoryTransport`]).
+//!
+//! Any serial terminal will do, e.g. `picocom --echo /dev/ttyACM0`, then `help`.
+
+use core::fmt::{self, Write};
+use core::str;
+
+use embassy_usb::class::cdc_acm::CdcAcmClass;
+use embassy_usb::driver::Driver;
+
+/// Size of the USB packets, the most bytes a [`Transport`] reads at once
+pub const PACKET_SIZE: usize = 64;
+
+/// Size of the [`Reply`] buffer, in bytes
+pub const REPLY_SIZE: usize = 512;
+
+/// The crate's version, for the `version` command
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// The commands and their arguments, for the `help` command
+pub const HELP: &str = "\
+version                show the firmware and its version
+status                 show the device's state
+get <setting>          show a setting
+set <setting> <value>  change a setting
+mode <name>            switch mode
+type <text>            type the text
+macro <name>           run a macro
+send                   type the text that follows, up to a Ctrl-D
+help                   show this";
+
+/// A parsed command line
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Command<'a> {
+    Version,
+    Status,
+    Get(&'a str),
+    Set(&'a str, &'a str),
+    Mode(&'a str),
+    /// Type the rest of the line, verbatim
+    Type(&'a str),
+    Macro(&'a str),
+    /// Type the text that follows the line, see [`crate::proxy`]
+    Send,
+    Help,
+}
+
+/// What went wrong with a command
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Error {
+    UnknownCommand,
+    MissingArgument,
+    UnexpectedArgument,
+    LineTooLong,
+    InvalidUtf8,
+    UnknownSetting,
+    InvalidValue,
+    UnknownMode,
+    UnknownMacro,
+    Cancelled,
+}
+
+impl Error {
+    pub fn message(self) -> &'static str {
+        match self {
+            Error::UnknownCommand => "unknown command (try help)",
+            Error::MissingArgument => "missing argument",
+            Error::UnexpectedArgument => "unexpected argument",
+            Error::LineTooLong => "line too long",
+            Error::InvalidUtf8 => "invalid UTF-8",
+            Error::UnknownSetting => "unknown setting",
+            Error::InvalidValue => "invalid value",
+            Error::UnknownMode => "unknown mode",
+            Error::UnknownMacro => "unknown macro",
+            Error::Cancelled => "cancelled",
+        }
+    }
+}
+
+/// Parse a command line. Command names are case insensitive, and arguments are separated
+/// by whitespace (except for the text to type, and the value of a setting, which are the
+/// rest of the line).
+pub fn parse(line: &str) -> Result<Command<'_>, Error> {
+    let line = line.trim_start();
+    let (name, rest) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+
+    let is = |command: &str| name.eq_ignore_ascii_case(command);
+    if is("version") {
+        nothing(Command::Version, rest)
+    } else if is("status") {
+        nothing(Command::Status, rest)
+    } else if is("send") {
+        nothing(Command::Send, rest)
+    } else if is("help") {
+        nothing(Command::Help, rest)
+    } else if is("get") {
+        only_word(rest).map(Command::Get)
+    } else if is("set") {
+        match word(rest)? {
+            (_, "") => Err(Error::MissingArgument),
+            (setting, value) => Ok(Command::Set(setting, value)),
+        }
+    } else if is("mode") {
+        only_word(rest).map(Command::Mode)
+    } else if is("macro") {
+        only_word(rest).map(Command::Macro)
+    } else if is("type") {
+        match rest {
+            "" => Err(Error::MissingArgument),
+            text => Ok(Command::Type(text)),
+        }
+    } else {
+        Err(Error::UnknownCommand)
+    }
+}
+
+/// The next word of the arguments, and what follows it
+fn word(rest: &str) -> Result<(&str, &str), Error> {
+    let rest = rest.trim_start();
+    match rest.split_once(char::is_whitespace) {
+        Some((word, rest)) => Ok((word, rest.trim())),
+        None if rest.is_empty() => Err(Error::MissingArgument),
+        None => Ok((rest, "")),
+    }
+}
+
+/// The only word of the arguments
+fn only_word(rest: &str) -> Result<&str, Error> {
+    match word(rest)? {
+        (word, "") => Ok(word),
+        _ => Err(Error::UnexpectedArgument),
+    }
+}
+
+/// The command, if there are no arguments
+fn nothing<'a>(command: Command<'a>, rest: &str) -> Result<Command<'a>, Error> {
+    match rest.trim() {
+        "" => Ok(command),
+        _ => Err(Error::UnexpectedArgument),
+    }
+}
+
+/// A serial port
+#[allow(async_fn_in_trait)]
+pub trait Transport {
+    /// Wait for some bytes, returning how many were read (None once the port is closed)
+    async fn read(&mut self, buf: &mut [u8; PACKET_SIZE]) -> Option<usize>;
+
+    /// Write all the bytes (or drop them if nobody is listening)
+    async fn write(&mut self, data: &[u8]);
+}
+
+/// The USB serial port. It is never closed: reads wait for the host to (re)connect.
+impl<'d, D: Driver<'d>> Transport for CdcAcmClass<'d, D> {
+    async fn read(&mut self, buf: &mut [u8; PACKET_SIZE]) -> Option<usize> {
+        loop {
+            match self.read_packet(buf).await {
+                Ok(len) => return Some(len),
+                Err(_) => self.wait_connection().await,
+            }
+        }
+    }
+
+    async fn write(&mut self, data: &[u8]) {
+        // NOTE: writes block until the host reads them, which it only does while the
+        // port is open (DTR set)
+        if !self.dtr() {
+            return;
+        }
+        for packet in data.chunks(self.max_packet_size() as usize) {
+            if self.write_packet(packet).await.is_err() {
+                return;
+            }
+        }
+    }
+}
+
+/// Splits what the transport reads into lines, of up to 'N' bytes (longer ones are
+/// dropped, see [`Error::LineTooLong`])
+pub struct LineReader<const N: usize> {
+    line: [u8; N],
+    len: usize,
+    overflow: bool,
+    /// The last byte read ended a line with a CR: a LF right after it ends the same line
+    after_cr: bool,
+    /// What's left of the last packet read
+    packet: [u8; PACKET_SIZE],
+    start: usize,
+    end: usize,
+}
+
+impl<const N: usize> LineReader<N> {
+    pub const fn new() -> Self {
+        LineReader {
+            line: [0; N],
+            len: 0,
+            overflow: false,
+            after_cr: false,
+            packet: [0; PACKET_SIZE],
+            start: 0,
+            end: 0,
+        }
+    }
+
+    /// The next line (without its end), or None once the transport is closed. This is
+    /// cancel safe: it can be raced against something else and called again later,
+    /// without losing any bytes.
+    pub async fn next_line(
+        &mut self,
+        transport: &mut impl Transport,
+    ) -> Option<Result<&str, Error>> {
+        loop {
+            while self.start < self.end {
+                let byte = self.packet[self.start];
+                self.start += 1;
+                if core::mem::replace(&mut self.after_cr, byte == b'\r') && byte == b'\n' {
+                    continue;
+                }
+                if byte == b'\r' || byte == b'\n' {
+                    let len = core::mem::take(&mut self.len);
+                    if core::mem::take(&mut self.overflow) {
+                        return Some(Err(Error::LineTooLong));
+                    }
+                    return Some(str::from_utf8(&self.line[..len]).map_err(|_| Error::InvalidUtf8));
+                }
+                if self.len < N {
+                    self.line[self.len] = byte;
+                    self.len += 1;
+                } else {
+                    self.overflow = true;
+                }
+            }
+
+            self.end = transport.read(&mut self.packet).await?;
+            self.start = 0;
+        }
+    }
+
+    /// The bytes that follow (instead of lines), up to 'max' of them and up to the
+    /// delimiter, and whether the delimiter was found (it is then skipped). None once the
+    /// transport is closed. A LF right after the CR ending the last line is skipped. This
+    /// is cancel safe too.
+    pub async fn read_until(
+        &mut self,
+        transport: &mut impl Transport,
+        max: usize,
+        delimiter: u8,
+    ) -> Option<(&[u8], bool)> {
+        loop {
+            if self.start == self.end {
+                self.end = transport.read(&mut self.packet).await?;
+                self.start = 0;
+            } else if core::mem::take(&mut self.after_cr) && self.packet[self.start] == b'\n' {
+                // The end of the line before (e.g. the 'send' command)
+                self.start += 1;
+            } else {
+                break;
+            }
+        }
+
+        let start = self.start;
+        let available = &self.packet[start..self.end];
+        let (len, found) = match available.iter().take(max).position(|b| *b == delimiter) {
+            Some(len) => (len, true),
+            None => (available.len().min(max), false),
+        };
+        self.start += len + found as usize;
+        Some((&self.packet[start..start + len], found))
+    }
+}
+
+impl<const N: usize> Default for LineReader<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The output of a command, written with [`write!`] (what doesn't fit is dropped)
+pub struct Reply {
+    buf: [u8; REPLY_SIZE],
+    len: usize,
+}
+
+impl Reply {
+    pub const fn new() -> Reply {
+        Reply {
+            buf: [0; REPLY_SIZE],
+            len: 0,
+        }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+}
+
+impl Default for Reply {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Write for Reply {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let len = s.len().min(REPLY_SIZE - self.len);
+        self.buf[self.len..self.len + len].copy_from_slice(&s.as_bytes()[..len]);
+        self.len += len;
+        Ok(())
+    }
+}
+
+/// Carries out the commands
+#[allow(async_fn_in_trait)]
+pub trait Handler {
+    /// Carry out the command, writing its output lines (if any) to the reply
+    async fn handle(&mut self, command: Command<'_>, reply: &mut Reply) -> Result<(), Error>;
+}
+
+/// Carry out the command line (if not blank) and send the reply. Lines are sent with CRLF
+/// line ends, for terminals.
+pub async fn respond(
+    transport: &mut impl Transport,
+    line: Result<&str, Error>,
+    handler: &mut impl Handler,
+) {
+    if line.is_ok_and(|line| line.trim().is_empty()) {
+        return;
+    }
+
+    let mut reply = Reply::new();
+    let result = match line.and_then(parse) {
+        Ok(Command::Help) => {
+            let _ = write!(reply, "{HELP}");
+            Ok(())
+        }
+        Ok(command) => handler.handle(command, &mut reply).await,
+        Err(error) => Err(error),
+    };
+
+    let mut lines = Reply::new();
+    for line in str::from_utf8(reply.as_bytes()).unwrap_or("").lines() {
+        let _ = write!(lines, "{line}\r\n");
+    }
+    let _ = match result {
+        Ok(()) => write!(lines, "ok\r\n"),
+        Err(error) => write!(lines, "error: {}\r\n", error.message()),
+    };
+    transport.write(lines.as_bytes()).await;
+}
+
+/// Read and carry out commands until the transport is closed
+pub async fn run<const N: usize>(
+    transport: &mut impl Transport,
+    lines: &mut LineReader<N>,
+    handler: &mut impl Handler,
+) {
+    while let Some(line) = lines.next_line(transport).await {
+        respond(transport, line, handler).await;
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+    use crate::host::MemoryTransport;
+
+    #[test]
+    fn parses_commands() {
+        assert_eq!(parse("version"), Ok(Command::Version));
+        assert_eq!(parse("  STATUS "), Ok(Command::Status));
+        assert_eq!(parse("help"), Ok(Command::Help));
+        assert_eq!(parse("send"), Ok(Command::Send));
+        assert_eq!(parse("get delay"), Ok(Command::Get("delay")));
+        assert_eq!(parse("set delay  15 "), Ok(Command::Set("delay", "15")));
+        assert_eq!(parse("set name a b"), Ok(Command::Set("name", "a b")));
+        assert_eq!(parse("Mode slides"), Ok(Command::Mode("slides")));
+        assert_eq!(parse("macro run-tests"), Ok(Command::Macro("run-tests")));
+        // The text is typed as is, spaces included
+        assert_eq!(
+            parse("type  Hello,  World! "),
+            Ok(Command::Type(" Hello,  World! "))
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_commands() {
+        assert_eq!(parse("frobnicate"), Err(Error::UnknownCommand));
+        assert_eq!(parse(""), Err(Error::UnknownCommand));
+        assert_eq!(parse("versions"), Err(Error::UnknownCommand));
+        assert_eq!(parse("version 2"), Err(Error::UnexpectedArgument));
+        assert_eq!(parse("get"), Err(Error::MissingArgument));
+        assert_eq!(parse("get  "), Err(Error::MissingArgument));
+        assert_eq!(parse("get delay batch"), Err(Error::UnexpectedArgument));
+        assert_eq!(parse("set delay"), Err(Error::MissingArgument));
+        assert_eq!(parse("set"), Err(Error::MissingArgument));
+        assert_eq!(parse("mode"), Err(Error::MissingArgument));
+        assert_eq!(parse("type"), Err(Error::MissingArgument));
+    }
+
+    /// Keeps a single setting, and records what is typed
+    #[derive(Default)]
+    struct Device {
+        delay: u32,
+        typed: String,
+    }
+
+    impl Handler for Device {
+        async fn handle(&mut self, command: Command<'_>, reply: &mut Reply) -> Result<(), Error> {
+            match command {
+                Command::Version => writeln!(reply, "test {VERSION}").unwrap(),
+                Command::Status => {
+                    writeln!(reply, "delay: {}\ntyped: {}", self.delay, self.typed.len()).unwrap()
+                }
+                Command::Get("delay") => writeln!(reply, "{}", self.delay).unwrap(),
+                Command::Set("delay", value) => {
+                    self.delay = value.parse().map_err(|_| Error::InvalidValue)?
+                }
+                Command::Get(_) | Command::Set(_, _) => return Err(Error::UnknownSetting),
+                Command::Type(text) => self.typed.push_str(text),
+                Command::Mode(_) => return Err(Error::UnknownMode),
+                Command::Macro(_) => return Err(Error::UnknownMacro),
+                Command::Send => return Err(Error::UnknownCommand),
+                Command::Help => unreachable!(),
+            }
+            Ok(())
+        }
+    }
+
+    fn session(packets: &[&[u8]]) -> (String, Device) {
+        let mut transport = MemoryTransport::new(packets);
+        let mut device = Device::default();
+        let mut lines = LineReader::<32>::new();
+        embassy_futures::block_on(run(&mut transport, &mut lines, &mut device));
+        (transport.output(), device)
+    }
+
+    #[test]
+    fn runs_commands() {
+        let (output, device) = session(&[
+            b"set delay 15\r\nget delay\r\n",
+            b"type Hello\r\n\r\n",
+            b"status\r\nset delay fast\r\nset speed 3\r\n",
+        ]);
+        assert_eq!(
+            output,
+            "ok\r\n15\r\nok\r\nok\r\ndelay: 15\r\ntyped: 5\r\nok\r\n\
+             error: invalid value\r\nerror: unknown setting\r\n"
+        );
+        assert_eq!(device.delay, 15);
+        assert_eq!(device.typed, "Hello");
+
+        let (output, _) = session(&[b"version\n", b"help\n"]);
+        let version = format!("test {VERSION}\r\nok\r\n");
+        assert!(output.starts_with(&version));
+        assert!(output.ends_with("help                   show this\r\nok\r\n"));
+    }
+
+    #[test]
+    fn splits_lines_across_packets() {
+        // Lines end with CR (terminals), LF or CRLF, possibly in another packet
+        let (_, device) = session(&[b"type He", b"llo\r", b"\ntype ,", b" World\n", b"type !\r"]);
+        assert_eq!(device.typed, "Hello, World!");
+    }
+
+    #[test]
+    fn drops_invalid_lines() {
+        let long = [b'a'; 40];
+        let (output, device) = session(&[b"type ", &long, b"\n", b"type \xFF\n", b"type ok\n"]);
+        assert_eq!(
+            output,
+            "error: line too long\r\nerror: invalid UTF-8\r\nok\r\n"
+        );
+        assert_eq!(device.typed, "ok");
+    }
+}
@@ -0,0 +1,128 @@
+//! USB Consumer Control (HID class) helpers: media keys, volume, brightness, ...
+//!
+//! Consumer controls are on their own usage page (0x0C), which the keyboard's report can't
+//! carry, so they are sent through a second HID interface with [`DESCRIPTOR`]. Its reports
+//! are a single 16 bit usage ID (little endian, as all HID fields), 0 meaning released.
+
+use embassy_time::{Duration, Timer};
+use embassy_usb::class::hid;
+use embassy_usb::driver::Driver;
+
+/// Size of a report, in bytes
+pub const REPORT_SIZE: usize = 2;
+
+pub type HidWriter<'a, D> = hid::HidWriter<'a, D, REPORT_SIZE>;
+
+/// Report descriptor: one array field of 16 bits, with any usage of the Consumer page
+/// up to 0x3FF (see the HID spec 6.2.2 for the items).
+#[rustfmt::skip]
+pub const DESCRIPTOR: &[u8] = &[
+    0x05, 0x0C,       // Usage Page (Consumer)
+    0x09, 0x01,       // Usage (Consumer Control)
+    0xA1, 0x01,       // Collection (Application)
+    0x15, 0x00,       //   Logical Minimum (0)
+    0x26, 0xFF, 0x03, //   Logical Maximum (0x3FF)
+    0x19, 0x00,       //   Usage Minimum (0)
+    0x2A, 0xFF, 0x03, //   Usage Maximum (0x3FF)
+    0x75, 0x10,       //   Report Size (16)
+    0x95, 0x01,       //   Report Count (1)
+    0x81, 0x00,       //   Input (Data, Array, Absolute)
+    0xC0,             // End Collection
+];
+
+/// Consumer page usages (see the HID Usage Tables, section 15)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u16)]
+pub enum Usage {
+    BrightnessUp = 0x6F,
+    BrightnessDown = 0x70,
+    NextTrack = 0xB5,
+    PreviousTrack = 0xB6,
+    Stop = 0xB7,
+    PlayPause = 0xCD,
+    Mute = 0xE2,
+    VolumeUp = 0xE9,
+    VolumeDown = 0xEA,
+}
+
+/// The usage currently pressed (if any)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Report {
+    pub usage: u16,
+}
+
+impl Report {
+    pub const RELEASED: Report = Report { usage: 0 };
+
+    pub fn pressed(usage: Usage) -> Report {
+        Report {
+            usage: usage as u16,
+        }
+    }
+
+    pub fn to_bytes(&self) -> [u8; REPORT_SIZE] {
+        self.usage.to_le_bytes()
+    }
+}
+
+/// Anything consumer reports can be sent to, like [`crate::keyboard::Keyboard`]
+#[allow(async_fn_in_trait)]
+pub trait Consumer {
+    async fn send(&mut self, report: &Report);
+
+    /// Press and release a control
+    async fn press(&mut self, usage: Usage) {
+        self.send(&Report::pressed(usage)).await;
+        self.send(&Report::RELEASED).await;
+    }
+}
+
+/// Consumer writing to the USB HID device, waiting 'delay' after each report
+pub struct HidConsumer<'w, 'd, D: Driver<'d>> {
+    pub writer: &'w mut HidWriter<'d, D>,
+    pub delay: Duration,
+}
+
+impl<'d, D: Driver<'d>> Consumer for HidConsumer<'_, 'd, D> {
+    async fn send(&mut self, report: &Report) {
+        let _ = self.writer.write(&report.to_bytes()).await;
+        Timer::after(self.delay).await;
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+    use crate::host::{descriptor_item, descriptor_items};
+
+    #[test]
+    fn descriptor_matches_reports() {
+        let items = descriptor_items(DESCRIPTOR);
+        let value = |prefix| descriptor_item(DESCRIPTOR, prefix).unwrap();
+
+        // Usage Page (Consumer), Usage (Consumer Control), Collection (Application)
+        assert_eq!(&items[..3], &[(0x04, 0x0C), (0x08, 0x01), (0xA0, 0x01)]);
+        assert_eq!(items.last(), Some(&(0xC0, 0)));
+
+        // Report Size times Report Count, in bytes
+        assert_eq!((value(0x74) * value(0x94) / 8) as usize, REPORT_SIZE);
+
+        // Every usage is within the logical and usage ranges
+        let max = value(0x24).min(value(0x28));
+        for usage in [Usage::BrightnessUp, Usage::PlayPause, Usage::VolumeDown] {
+            assert!(usage as u32 <= max);
+        }
+    }
+
+    #[test]
+    fn serializes_reports() {
+        assert_eq!(Report::pressed(Usage::PlayPause).to_bytes(), [0xCD, 0x00]);
+        assert_eq!(
+            Report::pressed(Usage::BrightnessDown).to_bytes(),
+            [0x70, 0x00]
+        );
+        assert_eq!(Report { usage: 0x0223 }.to_bytes(), [0x23, 0x02]);
+        assert_eq!(Report::RELEASED.to_bytes(), [0x00, 0x00]);
+    }
+}
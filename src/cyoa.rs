@@ -0,0 +1,1038 @@
+//! Choose Your Own Adventure player
+//!
+//! Passages are typed out until the first link, then the links are listed as a menu: a short
+//! press selects the next option and a long press picks it. How menus look depends on the
+//! host, see [`menu::MenuStyle`]. This is shared by the cyoa firmware and the host player,
+//! so that both behave exactly the same.
+//!
+//! Passage tags change how the passage is played, see [`Behavior`].
+//!
+//! A double press goes back to the previous passage, see [`GoBack`].
+//!
+//! Passages can pick random text and links with `(either:)` (see [`twine::macros`]), from
+//! an RNG seeded with [`Options::seed`].
+//!
+//! Progress is saved after each choice, and a saved story can be continued on the next
+//! start, see [`crate::save`].
+
+use embassy_futures::select::{select, Either};
+use embassy_time::Duration;
+use usbd_hid::descriptor::KeyboardReport;
+
+use crate::config::{Text, TEXT_SIZE};
+use crate::keyboard::Keyboard;
+use crate::leds;
+use crate::random::Rng;
+use crate::save::{self, Library, Save, Slot, Storage};
+use menu::{Menu, MenuRenderer, MenuStyle};
+
+pub mod layout;
+pub mod menu;
+
+/// Time to wait after each keyboard report, i.e. the normal typing speed
+pub const DELAY: Duration = Duration::from_millis(30);
+
+/// How the button was pressed
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Press {
+    Short,
+    Long,
+    Double,
+}
+
+/// How to go back to the previous passage
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum GoBack {
+    /// Delete (with Backspace) everything typed since the previous passage's menu, and
+    /// list the menu again
+    #[default]
+    Erase,
+    /// Type a separator and replay the previous passage. This is for hosts where
+    /// deleting isn't reliable (terminals, chat apps, ...)
+    Replay,
+}
+
+/// Player options
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Options {
+    pub go_back: GoBack,
+    pub menu: MenuStyle,
+    /// Wrap passages and menus at this column with hard newlines, for hosts which
+    /// would otherwise soft-wrap long lines (see [`layout`])
+    pub width: Option<usize>,
+    /// Seed of the random picks, the same seed (and presses) replays the same story
+    pub seed: u64,
+}
+
+/// Maximum size of a passage's text with its random picks; longer passages are typed
+/// without resolving them
+pub const PASSAGE_SIZE: usize = 2048;
+
+/// Maximum size of the story picker's menu (see [`pick_story`])
+const PICKER_SIZE: usize = 512;
+
+/// Maximum size of a title in the story picker, longer ones are cut
+pub const TITLE_SIZE: usize = 48;
+
+// Every story is listed (as "[[title]]\n")
+const _: () = assert!(PICKER_SIZE >= save::MAX_STORIES * (TITLE_SIZE + 5));
+
+/// Number of passages remembered for going back
+pub const HISTORY_SIZE: usize = 16;
+
+/// Bounded stack of visited passages (pids), with the option that was picked in each. When
+/// full, the oldest passages are forgotten.
+pub struct History<'a, const N: usize> {
+    entries: [(&'a str, usize); N],
+    /// Index of the oldest passage
+    start: usize,
+    len: usize,
+}
+
+impl<'a, const N: usize> Default for History<'a, N> {
+    fn default() -> Self {
+        History {
+            entries: [("", 0); N],
+            start: 0,
+            len: 0,
+        }
+    }
+}
+
+impl<'a, const N: usize> History<'a, N> {
+    /// Remember the passage, and the index of the option picked in its menu
+    pub fn push(&mut self, pid: &'a str, selected: usize) {
+        if self.len == N {
+            // forget the oldest
+            self.start = (self.start + 1) % N;
+            self.len -= 1;
+        }
+        self.entries[(self.start + self.len) % N] = (pid, selected);
+        self.len += 1;
+    }
+
+    pub fn pop(&mut self) -> Option<(&'a str, usize)> {
+        if self.len == 0 {
+            return None;
+        }
+        self.len -= 1;
+        Some(self.entries[(self.start + self.len) % N])
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// The passages and their picked options, oldest first
+    pub fn iter(&self) -> impl Iterator<Item = (&'a str, usize)> + '_ {
+        (0..self.len).map(|ix| self.entries[(self.start + ix) % N])
+    }
+}
+
+/// The (single) button used to play
+#[allow(async_fn_in_trait)]
+pub trait Button {
+    /// Wait until the button is pushed down
+    async fn wait_for_press(&mut self);
+
+    /// Wait until the button is released, or until it's been held long enough
+    /// to be a long press
+    async fn wait_for_release(&mut self) -> Press;
+}
+
+/// Time, for timed choices and auto-advancing passages
+#[allow(async_fn_in_trait)]
+pub trait Clock {
+    async fn sleep(&mut self, duration: Duration);
+}
+
+/// The clock of the embassy time driver
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    async fn sleep(&mut self, duration: Duration) {
+        embassy_time::Timer::after(duration).await;
+    }
+}
+
+const BACKSPACE: KeyboardReport = KeyboardReport {
+    modifier: 0,
+    reserved: 0,
+    leds: 0,
+    keycodes: [42, 0, 0, 0, 0, 0],
+};
+
+/// Some line returns to give room to the next passage
+const PASSAGE_SPACING: &str = "\n\n\n";
+/// Typed before replaying a passage when going back with [`GoBack::Replay`]
+const REPLAY_SEPARATOR: &str = "\n- - -\n\n";
+/// Offered on start when there is a save (listed like passage links)
+const CONTINUE: &str = "Continue";
+const RESUME_MENU: &str = "[[Continue]]\n[[New game]]";
+
+/// Short press, going through menus
+const PRESSED_ANIMATION: leds::Animation = leds::Animation {
+    #[allow(clippy::eq_op)]
+    color: (1.0 / 3.0, 1.0 / 5.0, 1.0 / 4.0),
+    bounds: (0.3, 1.0),
+    peak_after: Duration::from_millis(200),
+    loop_after: None,
+};
+
+/// The ghostwriter is typing
+const TYPING_ANIMATION: leds::Animation = leds::Animation {
+    #[allow(clippy::eq_op)]
+    color: (1.0 / 1.0, 1.0 / 9.0, 1.0 / 1.0),
+    bounds: (0.0, 1.0),
+    peak_after: Duration::from_millis(100),
+    loop_after: Some(Duration::from_millis(400)),
+};
+
+/// The ghostwriter is typing, in red
+const RED_ANIMATION: leds::Animation = leds::Animation {
+    color: (1.0, 0.0, 0.0),
+    ..TYPING_ANIMATION
+};
+
+/// The ghostwriter is typing, in blue
+const BLUE_ANIMATION: leds::Animation = leds::Animation {
+    color: (0.0, 0.0, 1.0),
+    ..TYPING_ANIMATION
+};
+
+/// The ghostwriter is typing, slowly pulsing in purple from (almost) dark
+const SPOOKY_ANIMATION: leds::Animation = leds::Animation {
+    color: (0.5, 0.0, 1.0),
+    bounds: (0.05, 1.0),
+    peak_after: Duration::from_millis(800),
+    loop_after: Some(Duration::from_millis(2000)),
+};
+
+const SLOW_DELAY: Duration = Duration::from_millis(80);
+const FAST_DELAY: Duration = Duration::from_millis(15);
+
+/// Pause after the end of a sentence ('.', '!' or '?') in dramatic passages
+const SENTENCE_PAUSE: Duration = Duration::from_millis(800);
+/// Pause after ',', ';' or ':' in dramatic passages
+const CLAUSE_PAUSE: Duration = Duration::from_millis(300);
+
+/// How often the countdown animation is updated
+const COUNTDOWN_STEP: Duration = Duration::from_millis(250);
+
+/// Waiting for a timed choice, pulsing in amber at each countdown step
+const COUNTDOWN_ANIMATION: leds::Animation = leds::Animation {
+    color: (1.0, 0.5, 0.0),
+    bounds: (0.0, 1.0),
+    peak_after: Duration::from_millis(125),
+    loop_after: Some(COUNTDOWN_STEP),
+};
+
+/// A timed choice
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Timeout {
+    /// Time the player has to start choosing
+    pub after: Duration,
+    /// The option picked if they don't (0 is the first)
+    pub default: usize,
+}
+
+/// How a passage is played, derived from its tags:
+///  * 'slow' and 'fast' change the typing speed,
+///  * 'dramatic' adds long pauses at punctuation,
+///  * 'red', 'blue' and 'spooky' change the LED animation while typing,
+///  * 'timeout-<duration>' picks an option if there's no press in time: the first one, or
+///    the n-th one with 'default-<n>',
+///  * 'auto-<duration>' goes to the first link after that long, without a menu.
+///
+/// Durations are like '3s' or '500ms'. Unknown tags are ignored.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Behavior {
+    /// Time to wait after each keyboard report while typing the passage
+    pub delay: Duration,
+    /// Whether to pause at punctuation
+    pub dramatic: bool,
+    /// The animation while typing the passage
+    pub animation: leds::Animation,
+    pub timeout: Option<Timeout>,
+    /// Go to the first link after this long
+    pub auto_advance: Option<Duration>,
+}
+
+impl Default for Behavior {
+    fn default() -> Self {
+        Behavior {
+            delay: DELAY,
+            dramatic: false,
+            animation: TYPING_ANIMATION,
+            timeout: None,
+            auto_advance: None,
+        }
+    }
+}
+
+impl Behavior {
+    pub fn from_tags(tags: twine::Tags) -> Behavior {
+        let mut behavior = Behavior::default();
+
+        for tag in tags.iter() {
+            match tag {
+                "slow" => behavior.delay = SLOW_DELAY,
+                "fast" => behavior.delay = FAST_DELAY,
+                "dramatic" => behavior.dramatic = true,
+                "red" => behavior.animation = RED_ANIMATION,
+                "blue" => behavior.animation = BLUE_ANIMATION,
+                "spooky" => behavior.animation = SPOOKY_ANIMATION,
+                _ => {}
+            }
+        }
+
+        let duration = |name| {
+            tags.value(name)
+                .and_then(twine::parse_duration_ms)
+                .map(Duration::from_millis)
+        };
+        behavior.timeout = duration("timeout").map(|after| Timeout {
+            after,
+            default: tags
+                .value("default")
+                .and_then(|n| n.parse::<usize>().ok())
+                .and_then(|n| n.checked_sub(1))
+                .unwrap_or(0),
+        });
+        behavior.auto_advance = duration("auto");
+
+        behavior
+    }
+}
+
+/// Type the (passage) text as specified by the behavior, wrapped at the width (if any)
+async fn type_passage(
+    keyboard: &mut impl Keyboard,
+    text: &str,
+    behavior: &Behavior,
+    width: Option<usize>,
+) {
+    keyboard.set_delay(behavior.delay);
+
+    for (ix, line) in layout::lines(text, width).enumerate() {
+        if ix > 0 {
+            keyboard.type_str("\n").await;
+        }
+
+        if behavior.dramatic {
+            type_dramatic(keyboard, line).await;
+        } else {
+            keyboard.type_str(line).await;
+        }
+    }
+
+    keyboard.set_delay(DELAY);
+}
+
+/// Type a line, pausing on punctuation followed by whitespace (or the end of the line) to
+/// avoid pausing e.g. in the middle of HTML entities or numbers.
+async fn type_dramatic(keyboard: &mut impl Keyboard, line: &str) {
+    let mut start = 0;
+    let mut chars = line.char_indices().peekable();
+    while let Some((ix, chr)) = chars.next() {
+        let pause = match chr {
+            '.' | '!' | '?' => SENTENCE_PAUSE,
+            ',' | ';' | ':' => CLAUSE_PAUSE,
+            _ => continue,
+        };
+
+        if chars.peek().is_none_or(|(_, next)| next.is_whitespace()) {
+            keyboard.type_str(&line[start..=ix]).await;
+            keyboard.pause(pause).await;
+            start = ix + 1;
+        }
+    }
+    keyboard.type_str(&line[start..]).await;
+}
+
+/// Play the story, starting on the first press, until a passage without links (the end) is
+/// typed.
+///
+/// If the storage has a save for this story, the player can choose to continue it. The
+/// progress is then saved after each choice and cleared once the story ends.
+pub async fn play(
+    story: &str,
+    keyboard: &mut impl Keyboard,
+    button: &mut impl Button,
+    clock: &mut impl Clock,
+    leds_signal: &leds::Signal,
+    storage: &mut impl Storage,
+    options: &Options,
+) {
+    button.wait_for_press().await;
+    play_started(
+        story,
+        keyboard,
+        button,
+        clock,
+        leds_signal,
+        storage,
+        options,
+    )
+    .await;
+}
+
+/// Play one of the stories, starting on the first press: the only one, or the one the
+/// reader picks (see [`pick_story`]). Each story has its own save in the library.
+pub async fn play_library(
+    stories: &[&str],
+    keyboard: &mut impl Keyboard,
+    button: &mut impl Button,
+    clock: &mut impl Clock,
+    leds_signal: &leds::Signal,
+    library: &mut impl Library,
+    options: &Options,
+) {
+    let ix = match stories {
+        [_] => {
+            button.wait_for_press().await;
+            0
+        }
+        _ => {
+            let storage = &mut library.slot(Slot::LastStory);
+            pick_story(
+                stories,
+                keyboard,
+                button,
+                clock,
+                leds_signal,
+                storage,
+                options,
+            )
+            .await
+        }
+    };
+
+    let storage = &mut library.slot(Slot::Save(ix));
+    play_started(
+        stories[ix],
+        keyboard,
+        button,
+        clock,
+        leds_signal,
+        storage,
+        options,
+    )
+    .await
+}
+
+/// Same as [`play`], when the first press already happened (e.g. in [`pick_story`])
+pub async fn play_started(
+    story: &str,
+    keyboard: &mut impl Keyboard,
+    button: &mut impl Button,
+    clock: &mut impl Clock,
+    leds_signal: &leds::Signal,
+    storage: &mut impl Storage,
+    options: &Options,
+) {
+    let start_passage_id = twine::find_start_passage_id(story);
+    let ifid = twine::story_metadata(story).ifid;
+
+    let mut passage = twine::find_passage_by_id(story, start_passage_id);
+    let mut history = History::<HISTORY_SIZE>::default();
+    let mut rng = Rng::new(options.seed);
+    let mut prose_buf = [0; PASSAGE_SIZE];
+
+    let mut buf = [0xFF; save::SAVE_SIZE];
+    storage.read(&mut buf).await;
+    let saved = save::decode(story, &buf);
+
+    // Whether the passage text should be typed; this is not the case after going back
+    // by erasing, since the text is still there.
+    let mut type_text = true;
+
+    // Number of passages in the history that were typed since starting, and which can be
+    // erased when going back. Passages from a save were typed on another day.
+    let mut on_screen = 0;
+
+    if let Some(saved) = saved {
+        if let Choice::Link {
+            target: CONTINUE, ..
+        } = select_passage_link(
+            keyboard,
+            button,
+            clock,
+            leds_signal,
+            &options.menu,
+            Menu {
+                links: RESUME_MENU,
+                width: options.width,
+            },
+            false,
+            None,
+        )
+        .await
+        {
+            passage = twine::find_passage_by_id(story, saved.passage);
+            history = saved.history;
+        }
+    }
+
+    loop {
+        let behavior = Behavior::from_tags(passage.tags);
+        let text = passage.text;
+
+        leds_signal.signal(behavior.animation);
+
+        // Loop until there are no more links to other passages
+        let link_section_start = match text.find("[[") {
+            None => {
+                // The end, write the whole passage
+                let prose = resolve(text, &mut prose_buf, &mut rng);
+                type_passage(keyboard, prose, &behavior, options.width).await;
+                leds_signal.signal(PRESSED_ANIMATION);
+
+                // The story is over, start anew next time
+                storage.write(&[0xFF; save::SAVE_SIZE]).await;
+                break;
+            }
+            Some(l) => l,
+        };
+
+        // If a link is found, write the passage until the link. If it wasn't typed now,
+        // its random picks are not known (and it can't be erased).
+        let prose = if type_text {
+            let prose = resolve(&text[..link_section_start], &mut prose_buf, &mut rng);
+            type_passage(keyboard, prose, &behavior, options.width).await;
+            Some(prose)
+        } else if twine::macros::contains_macros(&text[..link_section_start]) {
+            None
+        } else {
+            Some(&text[..link_section_start])
+        };
+
+        if let Some(after) = behavior.auto_advance {
+            // No menu, go to the first link once the time is up (or on a press)
+            if wait_for_press_within(button, clock, leds_signal, after).await {
+                button.wait_for_release().await;
+            }
+            keyboard.type_str(PASSAGE_SPACING).await;
+
+            // NOTE: the passage isn't added to the history, going back would only
+            // advance again. Going back can't erase across it either.
+            let target = pick_target(twine::get_link_data(text, 0).target, &mut rng);
+            passage = twine::find_passage_by_name(story, target);
+            type_text = true;
+            on_screen = 0;
+        } else {
+            // Then offer the next passage selection
+            let can_go_back = !history.is_empty();
+            let choice = select_passage_link(
+                keyboard,
+                button,
+                clock,
+                leds_signal,
+                &options.menu,
+                Menu {
+                    links: text,
+                    width: options.width,
+                },
+                can_go_back,
+                behavior.timeout,
+            )
+            .await;
+            match choice {
+                Choice::Link { target, selected } => {
+                    history.push(passage.pid, selected);
+                    passage = twine::find_passage_by_name(story, pick_target(target, &mut rng));
+                    type_text = true;
+                    on_screen += 1;
+                }
+                Choice::Back { selected } => {
+                    // NOTE: can_go_back guarantees there is a previous passage
+                    let (previous, previous_selected) = history.pop().unwrap();
+                    let previous = twine::find_passage_by_id(story, previous);
+                    let how = match prose {
+                        Some(_) if on_screen > 0 => {
+                            on_screen -= 1;
+                            options.go_back
+                        }
+                        _ => {
+                            on_screen = 0;
+                            GoBack::Replay
+                        }
+                    };
+                    let erased = Erased {
+                        current: &passage,
+                        prose: prose.unwrap_or(""),
+                        selected,
+                        previous: &previous,
+                        previous_selected,
+                        width: options.width,
+                    };
+                    go_back(keyboard, &options.menu, how, &erased).await;
+                    passage = previous;
+                    type_text = how == GoBack::Replay;
+                }
+            }
+        }
+
+        let save = Save {
+            passage: passage.pid,
+            history,
+        };
+        if save::encode(ifid, &save, &mut buf).is_some() {
+            storage.write(&buf).await;
+        }
+        history = save.history;
+    }
+}
+
+/// The story's name with its HTML entities decoded (e.g. for the USB product name), if it
+/// has one and it fits
+pub fn story_name(story: &str) -> Option<Text> {
+    let mut buf = [0; TEXT_SIZE];
+    let name = twine::unescape(twine::story_metadata(story).name, &mut buf)?;
+    if name.is_empty() {
+        return None;
+    }
+    Text::new(name)
+}
+
+/// Let the reader pick one of the stories by title, starting on the first press. The story
+/// played last is listed first, and the storage remembers the pick.
+///
+/// Returns the index of the picked story.
+pub async fn pick_story(
+    stories: &[&str],
+    keyboard: &mut impl Keyboard,
+    button: &mut impl Button,
+    clock: &mut impl Clock,
+    leds_signal: &leds::Signal,
+    storage: &mut impl Storage,
+    options: &Options,
+) -> usize {
+    let mut buf = [0xFF; save::SAVE_SIZE];
+    storage.read(&mut buf).await;
+    let last = save::decode_last_story(stories, &buf);
+
+    // The titles are listed as links, most recent first. Stories that don't fit (more than
+    // save::MAX_STORIES) are left out.
+    let order = || {
+        let last = last.unwrap_or(0);
+        core::iter::once(last).chain((0..stories.len()).filter(move |ix| *ix != last))
+    };
+    let mut menu_buf = [0; PICKER_SIZE];
+    let mut len = 0;
+    for ix in order() {
+        let mut title = [0; TITLE_SIZE];
+        let title = picker_title(twine::story_metadata(stories[ix]).name, &mut title);
+        let Some(link) = menu_buf.get_mut(len..len + title.len() + 5) else {
+            break;
+        };
+        link[..2].copy_from_slice(b"[[");
+        link[2..2 + title.len()].copy_from_slice(title.as_bytes());
+        link[2 + title.len()..].copy_from_slice(b"]]\n");
+        len += link.len();
+    }
+    // NOTE: only whole strs were copied
+    let links = core::str::from_utf8(&menu_buf[..len]).unwrap_or_default();
+
+    button.wait_for_press().await;
+    let choice = select_passage_link(
+        keyboard,
+        button,
+        clock,
+        leds_signal,
+        &options.menu,
+        Menu {
+            links,
+            width: options.width,
+        },
+        false,
+        None,
+    )
+    .await;
+    let Choice::Link { selected, .. } = choice else {
+        unreachable!("Can't go back from the story picker");
+    };
+    let picked = order().nth(selected).unwrap_or(0);
+
+    if last != Some(picked) {
+        let ifid = twine::story_metadata(stories[picked]).ifid;
+        if save::encode_last_story(ifid, &mut buf).is_some() {
+            storage.write(&buf).await;
+        }
+    }
+
+    picked
+}
+
+/// The story's name as listed in the picker: decoded, cut to fit, and without link syntax
+fn picker_title<'b>(name: &str, buf: &'b mut [u8; TITLE_SIZE]) -> &'b str {
+    let mut len = 0;
+    for chr in twine::unescaped(name) {
+        // Brackets would open or close a link
+        let chr = match chr {
+            '[' => '(',
+            ']' => ')',
+            chr => chr,
+        };
+        let Some(out) = buf.get_mut(len..len + chr.len_utf8()) else {
+            break;
+        };
+        len += chr.encode_utf8(out).len();
+    }
+
+    // A dash before a (decoded) "&gt;" would be a link's delimiter
+    while let Some(ix) = core::str::from_utf8(&buf[..len])
+        .ok()
+        .and_then(|title| title.find("-&gt;"))
+    {
+        buf[ix] = b' ';
+    }
+
+    // NOTE: only whole chars were written
+    core::str::from_utf8(&buf[..len]).unwrap_or_default()
+}
+
+/// The text with its random picks, or as is if it doesn't fit in the buffer
+fn resolve<'b>(text: &'b str, buf: &'b mut [u8; PASSAGE_SIZE], rng: &mut Rng) -> &'b str {
+    twine::macros::resolve(text, buf, |n| rng.below(n)).unwrap_or(text)
+}
+
+/// One of the targets of a (possibly random) link
+fn pick_target<'a>(target: &'a str, rng: &mut Rng) -> &'a str {
+    match twine::macros::Either::parse(target) {
+        Some(either) => either.get(rng.below(either.len())).unwrap_or(target),
+        None => target,
+    }
+}
+
+/// What to erase when going back
+struct Erased<'p, 'a> {
+    current: &'p twine::Passage<'a>,
+    /// The current passage's text as it was typed
+    prose: &'p str,
+    /// The selected option of the current menu
+    selected: usize,
+    previous: &'p twine::Passage<'a>,
+    /// The option that was picked in the previous passage's menu
+    previous_selected: usize,
+    /// Width the passages and menus were wrapped at
+    width: Option<usize>,
+}
+
+/// Go back from the current passage's menu to the previous passage. The current menu
+/// is expected to be closed.
+async fn go_back(
+    keyboard: &mut impl Keyboard,
+    renderer: &impl MenuRenderer,
+    how: GoBack,
+    erased: &Erased<'_, '_>,
+) {
+    match how {
+        GoBack::Erase => {
+            // Erase the current passage, the spacing, and the previous passage's menu
+            // (which is then listed again). The previous passage's text is kept.
+            let Erased {
+                current,
+                prose,
+                selected,
+                previous,
+                previous_selected,
+                width,
+            } = *erased;
+            let current_menu = Menu {
+                links: current.text,
+                width,
+            };
+            let previous_menu = Menu {
+                links: previous.text,
+                width,
+            };
+            let n = layout::wrapped_len(prose, width)
+                + renderer.len(&current_menu, selected)
+                + PASSAGE_SPACING.len()
+                + renderer.len(&previous_menu, previous_selected);
+
+            keyboard.set_delay(FAST_DELAY);
+            for _ in 0..n {
+                keyboard.tap(&BACKSPACE).await;
+            }
+            keyboard.set_delay(DELAY);
+        }
+        GoBack::Replay => {
+            keyboard.type_str(REPLAY_SEPARATOR).await;
+        }
+    }
+}
+
+/// Wait for a press for at most 'timeout', counting down on the LEDs. Returns whether
+/// there was a press.
+async fn wait_for_press_within(
+    button: &mut impl Button,
+    clock: &mut impl Clock,
+    leds_signal: &leds::Signal,
+    timeout: Duration,
+) -> bool {
+    let mut remaining = timeout;
+    while remaining > Duration::from_ticks(0) {
+        // Dimmer and dimmer
+        leds_signal.signal(leds::Animation {
+            bounds: (0.0, remaining.as_ticks() as f64 / timeout.as_ticks() as f64),
+            ..COUNTDOWN_ANIMATION
+        });
+
+        let step = remaining.min(COUNTDOWN_STEP);
+        match select(clock.sleep(step), button.wait_for_press()).await {
+            Either::First(()) => remaining -= step,
+            Either::Second(()) => return true,
+        }
+    }
+    false
+}
+
+/// What was selected in the menu
+enum Choice<'a> {
+    /// Go to the passage with this name, picked as this option of the menu
+    Link { target: &'a str, selected: usize },
+    /// Go back to the previous passage, leaving the menu with this option selected
+    Back { selected: usize },
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn select_passage_link<'a>(
+    keyboard: &mut impl Keyboard,
+    button: &mut impl Button,
+    clock: &mut impl Clock,
+    leds_signal: &leds::Signal,
+    renderer: &impl MenuRenderer,
+    menu: Menu<'a>,
+    can_go_back: bool,
+    mut timeout: Option<Timeout>,
+) -> Choice<'a> {
+    let n_links = menu.n_options();
+
+    renderer.show(keyboard, &menu).await;
+
+    // Finally, whenever there's a short press, select the next option (possibly
+    // looping to the first).
+    let mut current = 0;
+
+    leds_signal.signal(PRESSED_ANIMATION); // basically stop the typing animation
+    loop {
+        // Only the first press is timed
+        match timeout.take() {
+            Some(Timeout { after, default }) => {
+                if !wait_for_press_within(button, clock, leds_signal, after).await {
+                    // The player hesitated, pick the default
+                    let default = default.min(n_links - 1);
+                    if default != current {
+                        renderer.select(keyboard, &menu, current, default).await;
+                        current = default;
+                    }
+                    break;
+                }
+            }
+            None => button.wait_for_press().await,
+        }
+
+        leds_signal.signal(PRESSED_ANIMATION);
+
+        match button.wait_for_release().await {
+            Press::Short => {}
+            Press::Long => break,
+            Press::Double if can_go_back => {
+                renderer.close(keyboard, &menu, current).await;
+                return Choice::Back { selected: current };
+            }
+            Press::Double => continue,
+        }
+
+        let target = (current + 1) % n_links;
+        renderer.select(keyboard, &menu, current, target).await;
+        current = target;
+    }
+
+    renderer.close(keyboard, &menu, current).await;
+
+    keyboard.type_str(PASSAGE_SPACING).await;
+
+    // Finally return the name of the passage to go to
+    Choice::Link {
+        target: twine::get_link_data(menu.links, current).target,
+        selected: current,
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    use twine::Tags;
+
+    use crate::host::Recorder;
+
+    #[test]
+    fn history_is_a_stack() {
+        let mut history = History::<4>::default();
+        assert!(history.is_empty());
+        assert_eq!(history.pop(), None);
+
+        history.push("1", 0);
+        history.push("2", 1);
+        assert_eq!(history.pop(), Some(("2", 1)));
+        history.push("3", 2);
+        assert_eq!(history.iter().collect::<Vec<_>>(), [("1", 0), ("3", 2)]);
+        assert_eq!(history.pop(), Some(("3", 2)));
+        assert_eq!(history.pop(), Some(("1", 0)));
+        assert!(history.is_empty());
+    }
+
+    #[test]
+    fn history_forgets_oldest() {
+        let mut history = History::<3>::default();
+        for pid in ["1", "2", "3", "4", "5"] {
+            history.push(pid, 0);
+        }
+        let pids = history.iter().map(|(pid, _)| pid).collect::<Vec<_>>();
+        assert_eq!(pids, ["3", "4", "5"]);
+        assert_eq!(history.pop(), Some(("5", 0)));
+        assert_eq!(history.pop(), Some(("4", 0)));
+        assert_eq!(history.pop(), Some(("3", 0)));
+        assert_eq!(history.pop(), None);
+    }
+
+    #[test]
+    fn story_names_are_decoded() {
+        let name = |story| story_name(story).map(|name| name.as_str().to_owned());
+
+        let story = r#"<tw-storydata name="Tom &amp; Jerry&#39;s" ifid="A"></tw-storydata>"#;
+        assert_eq!(name(story).as_deref(), Some("Tom & Jerry's"));
+
+        // No name, or one too long for the settings
+        assert_eq!(name(r#"<tw-storydata name=""></tw-storydata>"#), None);
+        assert_eq!(name(r#"<tw-storydata ifid="A"></tw-storydata>"#), None);
+        let long = format!(r#"<tw-storydata name="{}"></tw-storydata>"#, "x".repeat(40));
+        assert_eq!(name(&long), None);
+    }
+
+    #[test]
+    fn picker_titles_are_links() {
+        let title = |name: &str| picker_title(name, &mut [0; TITLE_SIZE]).to_owned();
+
+        assert_eq!(
+            title("Tom &amp; Jerry&#39;s [[big]] -&gt; &amp;gt; day"),
+            "Tom & Jerry's ((big)) -> &gt; day"
+        );
+        assert_eq!(title("Left-&amp;gt;Right"), "Left &gt;Right");
+
+        // Cut between chars
+        assert_eq!(title(&"x".repeat(100)), "x".repeat(TITLE_SIZE));
+        assert_eq!(title(&"é".repeat(100)), "é".repeat(TITLE_SIZE / 2));
+        assert_eq!(
+            title(&format!("x{}", "é".repeat(100))).len(),
+            TITLE_SIZE - 1
+        );
+    }
+
+    #[test]
+    fn untagged_passages_have_default_behavior() {
+        assert_eq!(Behavior::from_tags(Tags::new("")), Behavior::default());
+        assert_eq!(
+            Behavior::from_tags(Tags::new("unknown")),
+            Behavior::default()
+        );
+    }
+
+    #[test]
+    fn tags_change_typing_speed() {
+        assert_eq!(Behavior::from_tags(Tags::new("slow")).delay, SLOW_DELAY);
+        assert_eq!(Behavior::from_tags(Tags::new("fast")).delay, FAST_DELAY);
+        assert!(FAST_DELAY < DELAY && DELAY < SLOW_DELAY);
+    }
+
+    #[test]
+    fn tags_change_animation() {
+        assert_eq!(
+            Behavior::from_tags(Tags::new("red")).animation,
+            RED_ANIMATION
+        );
+        assert_eq!(
+            Behavior::from_tags(Tags::new("blue")).animation,
+            BLUE_ANIMATION
+        );
+        assert_eq!(
+            Behavior::from_tags(Tags::new("spooky")).animation,
+            SPOOKY_ANIMATION
+        );
+    }
+
+    #[test]
+    fn tags_combine() {
+        let behavior = Behavior::from_tags(Tags::new("dramatic slow spooky"));
+        assert_eq!(
+            behavior,
+            Behavior {
+                delay: SLOW_DELAY,
+                dramatic: true,
+                animation: SPOOKY_ANIMATION,
+                timeout: None,
+                auto_advance: None,
+            }
+        );
+    }
+
+    #[test]
+    fn timed_tags() {
+        let behavior = Behavior::from_tags(Tags::new("timeout-3s default-2"));
+        assert_eq!(
+            behavior.timeout,
+            Some(Timeout {
+                after: Duration::from_secs(3),
+                default: 1,
+            })
+        );
+
+        let behavior = Behavior::from_tags(Tags::new("timeout-500ms default-0 auto-2s"));
+        assert_eq!(
+            behavior.timeout,
+            Some(Timeout {
+                after: Duration::from_millis(500),
+                default: 0,
+            })
+        );
+        assert_eq!(behavior.auto_advance, Some(Duration::from_secs(2)));
+
+        // Invalid durations are ignored
+        let behavior = Behavior::from_tags(Tags::new("timeout-soon auto-3"));
+        assert_eq!(behavior.timeout, None);
+        assert_eq!(behavior.auto_advance, None);
+    }
+
+    #[test]
+    fn dramatic_passages_pause_at_punctuation() {
+        let behavior = Behavior::from_tags(Tags::new("dramatic"));
+        let mut keyboard = Recorder::default();
+
+        embassy_futures::block_on(type_passage(
+            &mut keyboard,
+            "Wait, what.Really? No!",
+            &behavior,
+            None,
+        ));
+
+        // NOTE: no pause after the first '.' since it's not followed by whitespace
+        let pauses: Vec<(String, Duration)> = keyboard
+            .pauses
+            .iter()
+            .map(|(reports, duration)| (keyboard.text(*reports), *duration))
+            .collect();
+        assert_eq!(
+            pauses,
+            [
+                ("Wait,".into(), CLAUSE_PAUSE),
+                ("Wait, what.Really?".into(), SENTENCE_PAUSE),
+                ("Wait, what.Really? No!".into(), SENTENCE_PAUSE),
+            ]
+        );
+    }
+}
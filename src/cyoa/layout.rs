@@ -0,0 +1,121 @@
+//! Wrapping text at a column with hard newlines
+//!
+//! Editors soft-wrap long lines, and then Up/Down move by visual line, which breaks the
+//! cursor bookkeeping of menus. Wrapping before typing makes every visual line a real one.
+//! Widths are in bytes, since each byte is typed as one character.
+
+/// Split the text into lines of at most `width` bytes (if any), breaking at spaces when
+/// possible and in the middle of words otherwise. Newlines in the text are kept, and spaces
+/// where lines are broken are dropped.
+pub fn lines(text: &str, width: Option<usize>) -> Lines<'_> {
+    Lines {
+        rest: Some(text),
+        width: width.map(|width| width.max(1)),
+    }
+}
+
+pub struct Lines<'a> {
+    /// What remains to be split, or `None` when done
+    rest: Option<&'a str>,
+    width: Option<usize>,
+}
+
+impl<'a> Iterator for Lines<'a> {
+    type Item = &'a str;
+
+    fn next(&mut self) -> Option<&'a str> {
+        let rest = self.rest?;
+
+        let (para, after) = match rest.find('\n') {
+            Some(ix) => (&rest[..ix], Some(&rest[ix + 1..])),
+            None => (rest, None),
+        };
+
+        let width = match self.width {
+            Some(width) if para.len() > width => width,
+            _ => {
+                self.rest = after;
+                return Some(para);
+            }
+        };
+
+        // A space right after the first 'width' bytes still leaves a line that fits
+        let space = para.as_bytes()[..=width].iter().rposition(|b| *b == b' ');
+        if let Some(ix) = space.filter(|ix| *ix > 0) {
+            self.rest = Some(&rest[ix + 1..]);
+            return Some(&para[..ix]);
+        }
+
+        // No space to break at, break the word (but not a char)
+        let mut ix = width;
+        while !para.is_char_boundary(ix) {
+            ix -= 1;
+        }
+        let ix = if ix == 0 {
+            para.chars().next().map_or(0, char::len_utf8)
+        } else {
+            ix
+        };
+        self.rest = Some(&rest[ix..]);
+        Some(&para[..ix])
+    }
+}
+
+/// Number of bytes typed for the wrapped text
+pub fn wrapped_len(text: &str, width: Option<usize>) -> usize {
+    lines(text, width).map(|line| line.len() + 1).sum::<usize>() - 1
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    fn wrap(text: &str, width: usize) -> Vec<&str> {
+        lines(text, Some(width)).collect()
+    }
+
+    #[test]
+    fn does_not_wrap_without_width() {
+        let text = "A rather long line that would otherwise be wrapped\nand another\n";
+        assert_eq!(
+            lines(text, None).collect::<Vec<_>>(),
+            text.split('\n').collect::<Vec<_>>()
+        );
+        assert_eq!(wrapped_len(text, None), text.len());
+    }
+
+    #[test]
+    fn wraps_at_spaces() {
+        assert_eq!(
+            wrap("The quick brown fox jumps", 10),
+            ["The quick", "brown fox", "jumps"]
+        );
+        // the space right after a full line is where it breaks
+        assert_eq!(wrap("abcd efgh", 4), ["abcd", "efgh"]);
+    }
+
+    #[test]
+    fn keeps_newlines() {
+        assert_eq!(
+            wrap("Hello there\n\nGeneral Kenobi\n", 7),
+            ["Hello", "there", "", "General", "Kenobi", ""]
+        );
+    }
+
+    #[test]
+    fn breaks_long_words() {
+        assert_eq!(
+            wrap("Supercalifragilistic day", 8),
+            ["Supercal", "ifragili", "stic day"]
+        );
+        assert_eq!(wrap("abc", 1), ["a", "b", "c"]);
+        assert_eq!(wrap("héllo", 2), ["h", "é", "ll", "o"]);
+    }
+
+    #[test]
+    fn computes_wrapped_len() {
+        // one space becomes a newline, and a word is broken with an extra newline
+        assert_eq!(wrapped_len("The quick", Some(4)), "The\nquic\nk".len());
+    }
+}
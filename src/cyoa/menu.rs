@@ -0,0 +1,440 @@
+//! Ways of listing the links of a passage and showing which one is selected
+//!
+//! The caret overlay needs an editor where Up/Down/Shift+Left behave predictably; the
+//! other styles only need Backspace, which makes them usable in terminals and chat apps.
+
+use usbd_hid::descriptor::KeyboardReport;
+
+use super::{layout, BACKSPACE};
+use crate::keyboard::{Keyboard, ALL_KEYS_UP};
+
+const SHIFT: KeyboardReport = KeyboardReport {
+    modifier: 2,
+    reserved: 0,
+    leds: 0,
+    keycodes: [0, 0, 0, 0, 0, 0],
+};
+const CARET: KeyboardReport = KeyboardReport {
+    modifier: 2,
+    reserved: 0,
+    leds: 0,
+    keycodes: [55, 0, 0, 0, 0, 0],
+};
+const RIGHT: KeyboardReport = KeyboardReport {
+    modifier: 2,
+    reserved: 0,
+    leds: 0,
+    keycodes: [79, 0, 0, 0, 0, 0],
+};
+const LEFT: KeyboardReport = KeyboardReport {
+    modifier: 2,
+    reserved: 0,
+    leds: 0,
+    keycodes: [80, 0, 0, 0, 0, 0],
+};
+const DOWN: KeyboardReport = KeyboardReport {
+    modifier: 0,
+    reserved: 0,
+    leds: 0,
+    keycodes: [81, 0, 0, 0, 0, 0],
+};
+const UP: KeyboardReport = KeyboardReport {
+    modifier: 0,
+    reserved: 0,
+    leds: 0,
+    keycodes: [82, 0, 0, 0, 0, 0],
+};
+const SPACE: KeyboardReport = KeyboardReport {
+    modifier: 0,
+    reserved: 0,
+    leds: 0,
+    keycodes: [44, 0, 0, 0, 0, 0],
+};
+
+/// Padding left of each menu option, where the caret goes
+const MENU_PADDING: &str = "   ";
+/// Marks the selected option, in place of the padding
+const SELECTED: &str = ">  ";
+/// Prompt before the number of the selected option
+const PROMPT: &str = "> ";
+
+/// The links of a passage (the "link section", i.e. the passage text from the first link),
+/// as listed in a menu
+#[derive(Clone, Copy)]
+pub struct Menu<'a> {
+    pub links: &'a str,
+    /// Wrap options at this column, see [`layout`]
+    pub width: Option<usize>,
+}
+
+impl<'a> Menu<'a> {
+    pub fn n_options(&self) -> usize {
+        twine::get_n_links(self.links)
+    }
+
+    fn labels(&self) -> impl Iterator<Item = &'a str> {
+        twine::links(self.links).map(|link| link.label)
+    }
+
+    fn label(&self, n: usize) -> &'a str {
+        twine::get_link_data(self.links, n).label
+    }
+
+    /// The lines of a label typed after a prefix, as wide as the prefix is indented
+    fn label_lines(&self, prefix_len: usize, label: &'a str) -> layout::Lines<'a> {
+        let width = self.width.map(|width| width.saturating_sub(prefix_len));
+        layout::lines(label, width)
+    }
+
+    /// Number of lines of an option
+    fn option_lines(&self, prefix_len: usize, label: &str) -> usize {
+        self.label_lines(prefix_len, label).count()
+    }
+
+    /// Number of bytes typed for an option, excluding the final newline
+    fn option_len(&self, prefix_len: usize, label: &str) -> usize {
+        self.label_lines(prefix_len, label)
+            .map(|line| prefix_len + line.len() + 1)
+            .sum::<usize>()
+            - 1
+    }
+
+    /// Type the prefix and then the label, wrapped with the next lines indented as much as
+    /// the prefix (so that moving up and down keeps the cursor in the indentation)
+    async fn type_option(&self, keyboard: &mut impl Keyboard, prefix: &str, label: &str) {
+        for (ix, line) in self.label_lines(prefix.len(), label).enumerate() {
+            if ix == 0 {
+                keyboard.type_str(prefix).await;
+            } else {
+                keyboard.type_str("\n").await;
+                for _ in 0..prefix.len() {
+                    keyboard.type_str(" ").await;
+                }
+            }
+            keyboard.type_str(line).await;
+        }
+    }
+}
+
+/// Lists the links of a passage and shows the selection. Menus start at the beginning of a
+/// line, and end with the cursor at the beginning of the line after the menu.
+#[allow(async_fn_in_trait)]
+pub trait MenuRenderer {
+    /// Type the menu, with the first option selected
+    async fn show(&self, keyboard: &mut impl Keyboard, menu: &Menu<'_>);
+
+    /// Change the selected option
+    async fn select(&self, keyboard: &mut impl Keyboard, menu: &Menu<'_>, from: usize, to: usize);
+
+    /// Leave the menu as it is, with this option selected
+    async fn close(&self, keyboard: &mut impl Keyboard, menu: &Menu<'_>, selected: usize);
+
+    /// Number of characters left by the closed menu (to erase it)
+    fn len(&self, menu: &Menu<'_>, selected: usize) -> usize;
+}
+
+/// The menu rendering to use
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum MenuStyle {
+    /// See [`CaretMenu`]
+    #[default]
+    Caret,
+    /// See [`NumberedMenu`]
+    Numbered,
+    /// See [`RetypeMenu`]
+    Retype,
+    /// See [`CycleMenu`]
+    Cycle,
+}
+
+impl MenuRenderer for MenuStyle {
+    async fn show(&self, keyboard: &mut impl Keyboard, menu: &Menu<'_>) {
+        match self {
+            MenuStyle::Caret => CaretMenu.show(keyboard, menu).await,
+            MenuStyle::Numbered => NumberedMenu.show(keyboard, menu).await,
+            MenuStyle::Retype => RetypeMenu.show(keyboard, menu).await,
+            MenuStyle::Cycle => CycleMenu.show(keyboard, menu).await,
+        }
+    }
+
+    async fn select(&self, keyboard: &mut impl Keyboard, menu: &Menu<'_>, from: usize, to: usize) {
+        match self {
+            MenuStyle::Caret => CaretMenu.select(keyboard, menu, from, to).await,
+            MenuStyle::Numbered => NumberedMenu.select(keyboard, menu, from, to).await,
+            MenuStyle::Retype => RetypeMenu.select(keyboard, menu, from, to).await,
+            MenuStyle::Cycle => CycleMenu.select(keyboard, menu, from, to).await,
+        }
+    }
+
+    async fn close(&self, keyboard: &mut impl Keyboard, menu: &Menu<'_>, selected: usize) {
+        match self {
+            MenuStyle::Caret => CaretMenu.close(keyboard, menu, selected).await,
+            MenuStyle::Numbered => NumberedMenu.close(keyboard, menu, selected).await,
+            MenuStyle::Retype => RetypeMenu.close(keyboard, menu, selected).await,
+            MenuStyle::Cycle => CycleMenu.close(keyboard, menu, selected).await,
+        }
+    }
+
+    fn len(&self, menu: &Menu<'_>, selected: usize) -> usize {
+        match self {
+            MenuStyle::Caret => CaretMenu.len(menu, selected),
+            MenuStyle::Numbered => NumberedMenu.len(menu, selected),
+            MenuStyle::Retype => RetypeMenu.len(menu, selected),
+            MenuStyle::Cycle => CycleMenu.len(menu, selected),
+        }
+    }
+}
+
+async fn erase(keyboard: &mut impl Keyboard, n: usize) {
+    for _ in 0..n {
+        keyboard.tap(&BACKSPACE).await;
+    }
+}
+
+/// All the options, one per line, with a caret moved in front of the selected one:
+///
+/// ```text
+/// >  First Option
+///    Second Option
+/// ```
+pub struct CaretMenu;
+
+impl CaretMenu {
+    /// Number of lines of the options in the range
+    fn lines(&self, menu: &Menu<'_>, options: core::ops::Range<usize>) -> usize {
+        menu.labels()
+            .skip(options.start)
+            .take(options.len())
+            .map(|label| menu.option_lines(MENU_PADDING.len(), label))
+            .sum()
+    }
+}
+
+impl MenuRenderer for CaretMenu {
+    async fn show(&self, keyboard: &mut impl Keyboard, menu: &Menu<'_>) {
+        // Shenanigans to list the various links to other passages.
+
+        // First write all the links with some padding on the left:
+        //
+        // |   First Option
+        // |   Second Option
+        for label in menu.labels() {
+            menu.type_option(keyboard, MENU_PADDING, label).await;
+            keyboard.type_str("\n").await;
+        }
+
+        // Then the last newline will place the cursor on the leftmost position. From
+        // there we move up and insert a caret in front of the first option.
+        // | > First Option
+        // |   Second Option
+        for _ in 0..self.lines(menu, 0..menu.n_options()) {
+            keyboard.tap(&UP).await;
+        }
+
+        for _ in 0..2 {
+            keyboard.tap(&RIGHT).await;
+        }
+
+        write_caret(keyboard).await;
+    }
+
+    async fn select(&self, keyboard: &mut impl Keyboard, menu: &Menu<'_>, from: usize, to: usize) {
+        // Replace the caret with a space, move the cursor to the other option and replace
+        // the space there with a caret.
+        erase_caret(keyboard).await;
+
+        let (key, lines) = if to > from {
+            (DOWN, self.lines(menu, from..to))
+        } else {
+            (UP, self.lines(menu, to..from))
+        };
+        for _ in 0..lines {
+            keyboard.tap(&key).await;
+        }
+
+        write_caret(keyboard).await;
+    }
+
+    async fn close(&self, keyboard: &mut impl Keyboard, menu: &Menu<'_>, selected: usize) {
+        for _ in 0..self.lines(menu, selected..menu.n_options()) {
+            keyboard.tap(&DOWN).await;
+        }
+    }
+
+    fn len(&self, menu: &Menu<'_>, _selected: usize) -> usize {
+        menu.labels()
+            .map(|label| menu.option_len(MENU_PADDING.len(), label) + 1)
+            .sum()
+    }
+}
+
+/// Replace the character left of the cursor with a caret
+async fn write_caret(keyboard: &mut impl Keyboard) {
+    keyboard.send(&SHIFT).await;
+    keyboard.send(&LEFT).await;
+    keyboard.send(&SHIFT).await;
+    keyboard.send(&CARET).await;
+    keyboard.send(&ALL_KEYS_UP).await;
+}
+
+/// Replace the caret left of the cursor with a space
+async fn erase_caret(keyboard: &mut impl Keyboard) {
+    keyboard.send(&SHIFT).await;
+    keyboard.tap(&LEFT).await;
+    keyboard.tap(&SPACE).await;
+}
+
+/// Numbered options, followed by a prompt with the number of the selected one:
+///
+/// ```text
+/// 1) First Option
+/// 2) Second Option
+/// > 1
+/// ```
+pub struct NumberedMenu;
+
+impl MenuRenderer for NumberedMenu {
+    async fn show(&self, keyboard: &mut impl Keyboard, menu: &Menu<'_>) {
+        let mut buf = [0; NUMBER_SIZE];
+        for (ix, label) in menu.labels().enumerate() {
+            let prefix = format_number(ix + 1, ") ", &mut buf);
+            menu.type_option(keyboard, prefix, label).await;
+            keyboard.type_str("\n").await;
+        }
+
+        keyboard.type_str(PROMPT).await;
+        keyboard.type_str(format_number(1, "", &mut buf)).await;
+    }
+
+    async fn select(&self, keyboard: &mut impl Keyboard, _menu: &Menu<'_>, from: usize, to: usize) {
+        let mut buf = [0; NUMBER_SIZE];
+        erase(keyboard, n_digits(from + 1)).await;
+        keyboard.type_str(format_number(to + 1, "", &mut buf)).await;
+    }
+
+    async fn close(&self, keyboard: &mut impl Keyboard, _menu: &Menu<'_>, _selected: usize) {
+        keyboard.type_str("\n").await;
+    }
+
+    fn len(&self, menu: &Menu<'_>, selected: usize) -> usize {
+        let options: usize = menu
+            .labels()
+            .enumerate()
+            .map(|(ix, label)| menu.option_len(n_digits(ix + 1) + ") ".len(), label) + 1)
+            .sum();
+        options + PROMPT.len() + n_digits(selected + 1) + 1
+    }
+}
+
+/// Enough for any number and a short suffix
+const NUMBER_SIZE: usize = 24;
+
+fn n_digits(n: usize) -> usize {
+    n.checked_ilog10().unwrap_or(0) as usize + 1
+}
+
+/// Format the number followed by the suffix
+fn format_number<'b>(n: usize, suffix: &str, buf: &'b mut [u8; NUMBER_SIZE]) -> &'b str {
+    let digits = n_digits(n);
+    let mut rest = n;
+    for digit in buf[..digits].iter_mut().rev() {
+        *digit = b'0' + (rest % 10) as u8;
+        rest /= 10;
+    }
+    buf[digits..digits + suffix.len()].copy_from_slice(suffix.as_bytes());
+
+    // NOTE: only ASCII digits and a str were written
+    core::str::from_utf8(&buf[..digits + suffix.len()]).unwrap()
+}
+
+/// Looks like [`CaretMenu`], but the whole menu is erased and typed again when the
+/// selection changes
+pub struct RetypeMenu;
+
+impl RetypeMenu {
+    async fn type_menu(&self, keyboard: &mut impl Keyboard, menu: &Menu<'_>, selected: usize) {
+        for (ix, label) in menu.labels().enumerate() {
+            let marker = if ix == selected {
+                SELECTED
+            } else {
+                MENU_PADDING
+            };
+            menu.type_option(keyboard, marker, label).await;
+            keyboard.type_str("\n").await;
+        }
+    }
+}
+
+impl MenuRenderer for RetypeMenu {
+    async fn show(&self, keyboard: &mut impl Keyboard, menu: &Menu<'_>) {
+        self.type_menu(keyboard, menu, 0).await;
+    }
+
+    async fn select(&self, keyboard: &mut impl Keyboard, menu: &Menu<'_>, from: usize, to: usize) {
+        erase(keyboard, self.len(menu, from)).await;
+        self.type_menu(keyboard, menu, to).await;
+    }
+
+    async fn close(&self, _keyboard: &mut impl Keyboard, _menu: &Menu<'_>, _selected: usize) {}
+
+    fn len(&self, menu: &Menu<'_>, selected: usize) -> usize {
+        CaretMenu.len(menu, selected)
+    }
+}
+
+/// Only the selected option, which is replaced by the next one on the same line:
+///
+/// ```text
+/// >  First Option
+/// ```
+pub struct CycleMenu;
+
+impl MenuRenderer for CycleMenu {
+    async fn show(&self, keyboard: &mut impl Keyboard, menu: &Menu<'_>) {
+        menu.type_option(keyboard, SELECTED, menu.label(0)).await;
+    }
+
+    async fn select(&self, keyboard: &mut impl Keyboard, menu: &Menu<'_>, from: usize, to: usize) {
+        erase(keyboard, menu.option_len(SELECTED.len(), menu.label(from))).await;
+        menu.type_option(keyboard, SELECTED, menu.label(to)).await;
+    }
+
+    async fn close(&self, keyboard: &mut impl Keyboard, _menu: &Menu<'_>, _selected: usize) {
+        keyboard.type_str("\n").await;
+    }
+
+    fn len(&self, menu: &Menu<'_>, selected: usize) -> usize {
+        menu.option_len(SELECTED.len(), menu.label(selected)) + 1
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    #[test]
+    fn can_format_numbers() {
+        let mut buf = [0; NUMBER_SIZE];
+        assert_eq!(format_number(1, ") ", &mut buf), "1) ");
+        assert_eq!(format_number(10, "", &mut buf), "10");
+        assert_eq!(format_number(987, ".", &mut buf), "987.");
+    }
+
+    #[test]
+    fn wraps_options_with_hanging_indent() {
+        let menu = Menu {
+            links: "[[Open the creaking door-&gt;door]]",
+            width: Some(12),
+        };
+        assert_eq!(
+            menu.label_lines(3, menu.label(0)).collect::<Vec<_>>(),
+            ["Open the", "creaking", "door"]
+        );
+        assert_eq!(menu.option_lines(3, menu.label(0)), 3);
+        assert_eq!(
+            menu.option_len(3, menu.label(0)),
+            "   Open the\n   creaking\n   door".len()
+        );
+    }
+}
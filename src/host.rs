@@ -0,0 +1,1214 @@
+//! Host-side helpers, used for testing and by the host player
+//!
+//! The [`Editor`] emulates a (very simple) text editor receiving the keyboard
+//! reports, so that what the device would type can be checked without the device.
+
+use core::cell::{Cell, RefCell};
+use core::task::Poll;
+use std::collections::VecDeque;
+
+use embassy_time::Duration;
+use usbd_hid::descriptor::KeyboardReport;
+
+use crate::console::{Transport, PACKET_SIZE};
+use crate::cyoa::{self, Button, Clock, Options, Press};
+use crate::keyboard::{keycode, CapsLockPolicy, Keyboard, ReportFormat};
+use crate::leds;
+use crate::save::{NoStorage, Storage, Storages, SAVE_SIZE};
+
+/// A text buffer interpreting keyboard reports like a typical text editor would
+#[derive(Default)]
+pub struct Editor {
+    lines: Vec<Vec<char>>,
+    /// The cursor, as (line, column)
+    cursor: (usize, usize),
+    /// The other end of the selection, if any
+    anchor: Option<(usize, usize)>,
+    /// The keys held down in the previous report
+    held: [u8; 6],
+    /// Whether Caps Lock is on, inverting the case of letters
+    pub caps_lock: bool,
+    /// How the keyboard types when Caps Lock is on
+    pub caps_lock_policy: CapsLockPolicy,
+    /// The reports the keyboard sends, i.e. in which order the keys of a report are
+    /// pressed: that of the report, or by increasing keycode for NKRO bitmaps
+    pub format: ReportFormat,
+    /// How many keys the keyboard may press in one report (0 meaning 1)
+    pub max_batch: usize,
+}
+
+impl Editor {
+    pub fn new() -> Editor {
+        Editor {
+            lines: vec![vec![]],
+            ..Default::default()
+        }
+    }
+
+    /// The content of the buffer
+    pub fn text(&self) -> String {
+        let lines: Vec<String> = self.lines.iter().map(|l| l.iter().collect()).collect();
+        lines.join("\n")
+    }
+
+    /// The keys held down
+    pub fn held(&self) -> [u8; 6] {
+        self.held
+    }
+
+    /// The cursor, as (line, column)
+    pub fn cursor(&self) -> (usize, usize) {
+        self.cursor
+    }
+
+    /// Process a report. Keys are only considered pressed when they appear in a
+    /// report; keys that are still held don't repeat.
+    pub fn process(&mut self, report: &KeyboardReport) {
+        let shifted = report.modifier & 0x22 != 0;
+
+        let mut keycodes = report.keycodes;
+        if self.format == ReportFormat::Nkro {
+            keycodes.sort_unstable();
+        }
+        for keycode in keycodes {
+            if keycode != 0 && !self.held.contains(&keycode) {
+                self.press(keycode, shifted);
+            }
+        }
+
+        self.held = report.keycodes;
+    }
+
+    fn press(&mut self, keycode: u8, shifted: bool) {
+        match keycode {
+            keycode::CAPS_LOCK => self.caps_lock = !self.caps_lock,
+            40 => {
+                self.delete_selection();
+                let (line, col) = self.cursor;
+                let rest = self.lines[line].split_off(col);
+                self.lines.insert(line + 1, rest);
+                self.cursor = (line + 1, 0);
+            }
+            42 => {
+                if !self.delete_selection() {
+                    let start = self.left_of(self.cursor);
+                    self.delete(start, self.cursor);
+                }
+            }
+            79..=82 => {
+                if shifted {
+                    self.anchor.get_or_insert(self.cursor);
+                } else {
+                    self.anchor = None;
+                }
+
+                self.cursor = match keycode {
+                    79 => self.right_of(self.cursor),
+                    80 => self.left_of(self.cursor),
+                    81 => self.vertical(1),
+                    _ => self.vertical(-1),
+                };
+            }
+            _ => {
+                let shifted = shifted ^ (self.caps_lock && (4..=29).contains(&keycode));
+                if let Some(chr) = keycode_to_char(keycode, shifted) {
+                    self.delete_selection();
+                    let (line, col) = self.cursor;
+                    self.lines[line].insert(col, chr);
+                    self.cursor = (line, col + 1);
+                }
+            }
+        }
+    }
+
+    fn left_of(&self, (line, col): (usize, usize)) -> (usize, usize) {
+        match (line, col) {
+            (0, 0) => (0, 0),
+            (line, 0) => (line - 1, self.lines[line - 1].len()),
+            (line, col) => (line, col - 1),
+        }
+    }
+
+    fn right_of(&self, (line, col): (usize, usize)) -> (usize, usize) {
+        if col < self.lines[line].len() {
+            (line, col + 1)
+        } else if line + 1 < self.lines.len() {
+            (line + 1, 0)
+        } else {
+            (line, col)
+        }
+    }
+
+    fn vertical(&self, delta: isize) -> (usize, usize) {
+        let (line, col) = self.cursor;
+        let line = line.saturating_add_signed(delta).min(self.lines.len() - 1);
+        (line, col.min(self.lines[line].len()))
+    }
+
+    /// Delete the selection, returning 'false' if there was none
+    fn delete_selection(&mut self) -> bool {
+        match self.anchor.take() {
+            Some(anchor) if anchor != self.cursor => {
+                self.delete(anchor.min(self.cursor), anchor.max(self.cursor));
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn delete(&mut self, start: (usize, usize), end: (usize, usize)) {
+        let tail = self.lines[end.0].split_off(end.1);
+        self.lines[start.0].truncate(start.1);
+        self.lines[start.0].extend(tail);
+        self.lines.drain(start.0 + 1..=end.0);
+        self.cursor = start;
+    }
+}
+
+impl Keyboard for Editor {
+    async fn send(&mut self, report: &KeyboardReport) {
+        self.process(report);
+    }
+
+    fn report_format(&self) -> ReportFormat {
+        self.format
+    }
+
+    fn max_batch(&self) -> usize {
+        self.max_batch.max(1)
+    }
+
+    /// Reports are handled as they are sent
+    async fn sync(&mut self) -> bool {
+        true
+    }
+
+    fn caps_lock(&self) -> bool {
+        self.caps_lock
+    }
+
+    fn caps_lock_policy(&self) -> CapsLockPolicy {
+        self.caps_lock_policy
+    }
+}
+
+/// Map keyboard (US layout) keycodes to chars, the reverse of
+/// [`crate::keyboard::char_to_keycode`].
+pub fn keycode_to_char(keycode: u8, shifted: bool) -> Option<char> {
+    const DIGITS: &[u8] = b"1234567890";
+    const SHIFTED_DIGITS: &[u8] = b"!@#$%^&*()";
+    const SYMBOLS: &[u8] = b"-=[]\\ ;'`,./";
+    const SHIFTED_SYMBOLS: &[u8] = b"_+{}| :\"~<>?";
+
+    let chr = match (keycode, shifted) {
+        (4..=29, false) => b'a' + keycode - 4,
+        (4..=29, true) => b'A' + keycode - 4,
+        (30..=39, false) => DIGITS[keycode as usize - 30],
+        (30..=39, true) => SHIFTED_DIGITS[keycode as usize - 30],
+        (43, _) => b'\t',
+        (44, _) => b' ',
+        (45..=56, false) => SYMBOLS[keycode as usize - 45],
+        (45..=56, true) => SHIFTED_SYMBOLS[keycode as usize - 45],
+        _ => return None,
+    };
+
+    Some(char::from(chr))
+}
+
+/// The (short) items of a HID report descriptor, as (prefix without the size bits, data),
+/// e.g. `(0x04, 0x0C)` for Usage Page (Consumer)
+pub fn descriptor_items(descriptor: &[u8]) -> Vec<(u8, u32)> {
+    let mut items = vec![];
+    let mut rest = descriptor;
+    while let Some((prefix, after)) = rest.split_first() {
+        let size = match prefix & 0b11 {
+            3 => 4,
+            size => size as usize,
+        };
+        let (data, after) = after.split_at(size);
+        let data = data
+            .iter()
+            .rev()
+            .fold(0, |acc, byte| (acc << 8) | *byte as u32);
+        items.push((prefix & !0b11, data));
+        rest = after;
+    }
+    items
+}
+
+/// The data of the first item with that prefix (without the size bits) in the descriptor
+pub fn descriptor_item(descriptor: &[u8], prefix: u8) -> Option<u32> {
+    descriptor_items(descriptor)
+        .into_iter()
+        .find(|(p, _)| *p == prefix)
+        .map(|(_, data)| data)
+}
+
+/// A serial port reading the given packets, then closed, and recording what's written:
+/// the USB serial port of the [console](crate::console), on the host
+pub struct MemoryTransport {
+    input: VecDeque<Vec<u8>>,
+    output: Vec<u8>,
+    /// Once the packets are read, wait for more forever instead of closing
+    open: bool,
+}
+
+impl MemoryTransport {
+    pub fn new(packets: &[&[u8]]) -> MemoryTransport {
+        MemoryTransport {
+            input: packets.iter().map(|packet| packet.to_vec()).collect(),
+            output: vec![],
+            open: false,
+        }
+    }
+
+    /// Like [`MemoryTransport::new`], but left open like the device's port: reading after
+    /// the packets never completes
+    pub fn open(packets: &[&[u8]]) -> MemoryTransport {
+        MemoryTransport {
+            open: true,
+            ..MemoryTransport::new(packets)
+        }
+    }
+
+    /// What has been written so far
+    pub fn output(&self) -> String {
+        String::from_utf8_lossy(&self.output).into_owned()
+    }
+}
+
+impl Transport for MemoryTransport {
+    async fn read(&mut self, buf: &mut [u8; PACKET_SIZE]) -> Option<usize> {
+        if self.input.is_empty() && self.open {
+            core::future::pending::<()>().await;
+        }
+        let mut packet = self.input.pop_front()?;
+        // Longer packets are split, as USB would
+        if packet.len() > PACKET_SIZE {
+            self.input.push_front(packet.split_off(PACKET_SIZE));
+        }
+        buf[..packet.len()].copy_from_slice(&packet);
+        Some(packet.len())
+    }
+
+    async fn write(&mut self, data: &[u8]) {
+        self.output.extend_from_slice(data);
+    }
+}
+
+/// What the player does next
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Step {
+    Press(Press),
+    /// Don't press the button for that long
+    Wait(Duration),
+}
+
+/// The steps left to play, shared by the [`ScriptedButton`] and the [`ScriptedClock`]
+pub type Script = RefCell<VecDeque<Step>>;
+
+/// A button replaying the presses of a script. When no steps are left, the button
+/// is never pressed again and 'starved' is set.
+pub struct ScriptedButton<'a> {
+    script: &'a Script,
+    current: Press,
+    starved: &'a Cell<bool>,
+}
+
+impl<'a> ScriptedButton<'a> {
+    pub fn new(script: &'a Script, starved: &'a Cell<bool>) -> Self {
+        ScriptedButton {
+            script,
+            current: Press::Short,
+            starved,
+        }
+    }
+}
+
+impl Button for ScriptedButton<'_> {
+    async fn wait_for_press(&mut self) {
+        loop {
+            // NOTE: not borrowed across the await
+            let step = self.script.borrow_mut().pop_front();
+            match step {
+                Some(Step::Press(press)) => {
+                    self.current = press;
+                    return;
+                }
+                // Nothing is timed, time just passes
+                Some(Step::Wait(_)) => {}
+                None => {
+                    self.starved.set(true);
+                    core::future::pending().await
+                }
+            }
+        }
+    }
+
+    async fn wait_for_release(&mut self) -> Press {
+        self.current
+    }
+}
+
+/// A clock where time only passes when the script waits: sleeping consumes the waits at
+/// the front of the script, and doesn't end if there's a press first.
+///
+/// NOTE: when racing the button, the clock must be polled first to see the waits.
+pub struct ScriptedClock<'a> {
+    script: &'a Script,
+    /// Time slept so far
+    pub elapsed: Duration,
+}
+
+impl<'a> ScriptedClock<'a> {
+    pub fn new(script: &'a Script) -> Self {
+        ScriptedClock {
+            script,
+            elapsed: Duration::from_ticks(0),
+        }
+    }
+}
+
+impl Clock for ScriptedClock<'_> {
+    async fn sleep(&mut self, duration: Duration) {
+        let mut remaining = duration;
+        core::future::poll_fn(|_| {
+            let mut script = self.script.borrow_mut();
+            while let Some(Step::Wait(wait)) = script.front_mut() {
+                if *wait > remaining {
+                    *wait -= remaining;
+                    self.elapsed += remaining;
+                    return Poll::Ready(());
+                }
+
+                remaining -= *wait;
+                self.elapsed += *wait;
+                script.pop_front();
+
+                if remaining == Duration::from_ticks(0) {
+                    return Poll::Ready(());
+                }
+            }
+            Poll::Pending
+        })
+        .await
+    }
+}
+
+/// Keyboard recording all the reports it's sent, and the pauses
+#[derive(Default)]
+pub struct Recorder {
+    pub reports: Vec<KeyboardReport>,
+    /// How many reports were sent before each pause, and its duration
+    pub pauses: Vec<(usize, Duration)>,
+}
+
+impl Recorder {
+    /// The text the first reports type, in an [`Editor`]
+    pub fn text(&self, reports: usize) -> String {
+        let mut editor = Editor::new();
+        for report in &self.reports[..reports] {
+            editor.process(report);
+        }
+        editor.text()
+    }
+}
+
+impl Keyboard for Recorder {
+    async fn send(&mut self, report: &KeyboardReport) {
+        // NOTE: KeyboardReport is not Clone
+        self.reports.push(KeyboardReport {
+            modifier: report.modifier,
+            reserved: report.reserved,
+            leds: report.leds,
+            keycodes: report.keycodes,
+        });
+    }
+
+    async fn pause(&mut self, duration: Duration) {
+        self.pauses.push((self.reports.len(), duration));
+    }
+}
+
+/// Storage keeping the save in memory (initially erased)
+pub struct MemoryStorage {
+    pub buf: [u8; SAVE_SIZE],
+}
+
+impl Default for MemoryStorage {
+    fn default() -> Self {
+        MemoryStorage {
+            buf: [0xFF; SAVE_SIZE],
+        }
+    }
+}
+
+impl Storage for MemoryStorage {
+    async fn read(&mut self, buf: &mut [u8; SAVE_SIZE]) {
+        buf.copy_from_slice(&self.buf);
+    }
+
+    async fn write(&mut self, buf: &[u8; SAVE_SIZE]) {
+        self.buf.copy_from_slice(buf);
+    }
+}
+
+/// The steps needed to start the story and then pick each of the choices
+/// (option indices in the successive menus)
+pub fn choices_to_steps(choices: &[usize]) -> Vec<Step> {
+    let mut steps = vec![Step::Press(Press::Short)];
+    for choice in choices {
+        steps.extend(core::iter::repeat_n(Step::Press(Press::Short), *choice));
+        steps.push(Step::Press(Press::Long));
+    }
+    steps
+}
+
+/// Parse a script of comma separated steps (the initial press is added):
+/// a number picks that option in the current menu, 'b' goes back to the previous passage
+/// and e.g. 'w3000' waits 3 seconds without pressing (for timed passages).
+pub fn parse_script(script: &str) -> Option<Vec<Step>> {
+    let mut steps = vec![Step::Press(Press::Short)];
+    for step in script.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+        if step == "b" {
+            steps.push(Step::Press(Press::Double));
+        } else if let Some(ms) = step.strip_prefix('w') {
+            steps.push(Step::Wait(Duration::from_millis(ms.parse().ok()?)));
+        } else {
+            let n = step.parse().ok()?;
+            steps.extend(core::iter::repeat_n(Step::Press(Press::Short), n));
+            steps.push(Step::Press(Press::Long));
+        }
+    }
+    Some(steps)
+}
+
+/// Play the story with the given steps, until the story ends or the steps
+/// run out, and record the keyboard reports
+pub fn record(
+    story: &str,
+    steps: &[Step],
+    storage: &mut impl Storage,
+    options: &Options,
+) -> Vec<KeyboardReport> {
+    record_library(
+        &[story],
+        steps,
+        &mut NoStorage,
+        core::slice::from_mut(storage),
+        options,
+    )
+}
+
+/// Same as [`record`], with several stories to pick from first (see
+/// [`cyoa::pick_story`]) if there is more than one. Each story has its own save in
+/// 'saves', and the pick is remembered in 'last_story'.
+pub fn record_library(
+    stories: &[&str],
+    steps: &[Step],
+    last_story: &mut impl Storage,
+    saves: &mut [impl Storage],
+    options: &Options,
+) -> Vec<KeyboardReport> {
+    let mut recorder = Recorder::default();
+    let script = Script::new(steps.iter().copied().collect());
+    let starved = Cell::new(false);
+    let mut button = ScriptedButton::new(&script, &starved);
+    let mut clock = ScriptedClock::new(&script);
+    let leds_signal = leds::Signal::new();
+
+    let mut library = Storages { last_story, saves };
+    let play = cyoa::play_library(
+        stories,
+        &mut recorder,
+        &mut button,
+        &mut clock,
+        &leds_signal,
+        &mut library,
+        options,
+    );
+    let out_of_presses = core::future::poll_fn(|_| {
+        if starved.get() {
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    });
+    embassy_futures::block_on(embassy_futures::select::select(play, out_of_presses));
+
+    recorder.reports
+}
+
+/// What an editor shows after receiving the reports
+pub fn replay(reports: &[KeyboardReport]) -> String {
+    let mut editor = Editor::new();
+    for report in reports {
+        editor.process(report);
+    }
+    editor.text()
+}
+
+/// Play the story with the given choices, until the story ends or the choices
+/// run out, and return what was typed
+pub fn play_script(story: &str, choices: &[usize]) -> String {
+    replay(&record(
+        story,
+        &choices_to_steps(choices),
+        &mut NoStorage,
+        &Options::default(),
+    ))
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+    use crate::cyoa::menu::MenuStyle;
+    use std::collections::HashSet;
+
+    const STORY: &str = r#"
+        <tw-storydata startnode="1">
+            <tw-passagedata pid="1" name="intro">Hello!
+[[Left-&gt;left]]
+[[Right-&gt;right]]</tw-passagedata>
+            <tw-passagedata pid="2" name="left">You went left.</tw-passagedata>
+            <tw-passagedata pid="3" name="right">You went right.</tw-passagedata>
+        </tw-storydata>
+    "#;
+
+    #[test]
+    fn editor_types_text() {
+        let mut editor = Editor::new();
+        embassy_futures::block_on(editor.type_str("Hi there,\nWorld!"));
+        assert_eq!(editor.text(), "Hi there,\nWorld!");
+        assert_eq!(editor.cursor(), (1, 6));
+    }
+
+    #[test]
+    fn editor_replaces_selection() {
+        let mut editor = Editor::new();
+        let shift_left = KeyboardReport {
+            modifier: 2,
+            reserved: 0,
+            leds: 0,
+            keycodes: [80, 0, 0, 0, 0, 0],
+        };
+
+        embassy_futures::block_on(async {
+            editor.type_str("abc").await;
+            editor.tap(&shift_left).await;
+            editor.tap(&shift_left).await;
+            editor.type_str("X").await;
+        });
+        assert_eq!(editor.text(), "aX");
+    }
+
+    #[test]
+    fn can_play_first_option() {
+        let transcript = play_script(STORY, &[0]);
+        assert_eq!(
+            transcript,
+            "Hello!\n>  Left\n   Right\n\n\n\nYou went left."
+        );
+    }
+
+    #[test]
+    fn can_play_second_option() {
+        let transcript = play_script(STORY, &[1]);
+        assert_eq!(
+            transcript,
+            "Hello!\n   Left\n>  Right\n\n\n\nYou went right."
+        );
+    }
+
+    #[test]
+    fn can_wrap_around_options() {
+        let transcript = play_script(STORY, &[3]);
+        assert_eq!(
+            transcript,
+            "Hello!\n   Left\n>  Right\n\n\n\nYou went right."
+        );
+    }
+
+    #[test]
+    fn types_the_final_passage() {
+        // The ending is typed too, or the reader would never see how the story ends
+        const STORY: &str = r#"
+            <tw-storydata startnode="1">
+                <tw-passagedata pid="1" name="intro">Hello!
+[[End-&gt;end]]</tw-passagedata>
+                <tw-passagedata pid="2" name="end">The end.</tw-passagedata>
+            </tw-storydata>
+        "#;
+        assert_eq!(play_script(STORY, &[0]), "Hello!\n>  End\n\n\n\nThe end.");
+
+        // even if it is the first one
+        const SHORT_STORY: &str = r#"
+            <tw-storydata startnode="1">
+                <tw-passagedata pid="1" name="intro">The end.</tw-passagedata>
+            </tw-storydata>
+        "#;
+        assert_eq!(play_script(SHORT_STORY, &[]), "The end.");
+    }
+
+    const DEEP_STORY: &str = r#"
+        <tw-storydata startnode="1">
+            <tw-passagedata pid="1" name="intro">Hello!
+[[Go-&gt;middle]]
+[[Stay-&gt;end]]</tw-passagedata>
+            <tw-passagedata pid="2" name="middle">In the middle.
+[[End-&gt;end]]</tw-passagedata>
+            <tw-passagedata pid="3" name="end">The end.</tw-passagedata>
+        </tw-storydata>
+    "#;
+
+    fn backspaces(reports: &[KeyboardReport]) -> usize {
+        reports.iter().filter(|r| r.keycodes[0] == 42).count()
+    }
+
+    #[test]
+    fn can_parse_script() {
+        use Press::*;
+        use Step::Press as P;
+        assert_eq!(parse_script(""), Some(vec![P(Short)]));
+        assert_eq!(
+            parse_script("1, b,w500,0"),
+            Some(vec![
+                P(Short),
+                P(Short),
+                P(Long),
+                P(Double),
+                Step::Wait(Duration::from_millis(500)),
+                P(Long)
+            ])
+        );
+        assert_eq!(parse_script("x"), None);
+        assert_eq!(parse_script("w"), None);
+    }
+
+    #[test]
+    fn scripted_clock_sleeps_through_waits() {
+        let script = Script::new(
+            parse_script("w300,w200,0")
+                .unwrap()
+                .into_iter()
+                .skip(1)
+                .collect(),
+        );
+        let mut clock = ScriptedClock::new(&script);
+
+        // The first wait is split, and the second one is consumed across sleeps
+        embassy_futures::block_on(clock.sleep(Duration::from_millis(100)));
+        embassy_futures::block_on(clock.sleep(Duration::from_millis(300)));
+        assert_eq!(clock.elapsed, Duration::from_millis(400));
+        assert_eq!(
+            script.borrow().front(),
+            Some(&Step::Wait(Duration::from_millis(100)))
+        );
+
+        // The press comes first
+        let sleep = clock.sleep(Duration::from_millis(1000));
+        let press = embassy_futures::select::select(sleep, core::future::ready(()));
+        assert!(matches!(
+            embassy_futures::block_on(press),
+            embassy_futures::select::Either::Second(())
+        ));
+        assert_eq!(clock.elapsed, Duration::from_millis(500));
+        assert_eq!(script.borrow().front(), Some(&Step::Press(Press::Long)));
+    }
+
+    const TIMED_STORY: &str = r#"
+        <tw-storydata startnode="1">
+            <tw-passagedata pid="1" name="intro" tags="timeout-3s default-2">Quick!
+[[Run-&gt;run]]
+[[Hide-&gt;hide]]</tw-passagedata>
+            <tw-passagedata pid="2" name="run">You run.</tw-passagedata>
+            <tw-passagedata pid="3" name="hide" tags="auto-2s">You hide, and the lights go out...
+[[Wait-&gt;dark]]</tw-passagedata>
+            <tw-passagedata pid="4" name="dark">It's dark.
+[[Scream-&gt;run]]</tw-passagedata>
+        </tw-storydata>
+    "#;
+
+    fn play_timed(script: &str) -> String {
+        let steps = parse_script(script).unwrap();
+        replay(&record(
+            TIMED_STORY,
+            &steps,
+            &mut NoStorage,
+            &Options::default(),
+        ))
+    }
+
+    #[test]
+    fn picks_default_on_timeout() {
+        // Hesitating too long picks the default, and then the next passage advances
+        // on its own
+        assert_eq!(
+            play_timed("w3000,w2000"),
+            concat!(
+                "Quick!\n   Run\n>  Hide\n\n\n\n",
+                "You hide, and the lights go out...\n\n\n\n",
+                "It's dark.\n>  Scream\n"
+            )
+        );
+    }
+
+    #[test]
+    fn can_choose_before_timeout() {
+        assert_eq!(
+            play_timed("w2999,0"),
+            "Quick!\n>  Run\n   Hide\n\n\n\nYou run."
+        );
+
+        assert_eq!(play_timed("w2999,1"), play_timed("1"));
+    }
+
+    #[test]
+    fn can_skip_auto_advance() {
+        assert_eq!(play_timed("1,0"), play_timed("1,w2000"));
+
+        // Not advancing yet
+        assert_eq!(
+            play_timed("1,w1999"),
+            "Quick!\n   Run\n>  Hide\n\n\n\nYou hide, and the lights go out...\n"
+        );
+    }
+
+    const RANDOM_STORY: &str = r#"
+        <tw-storydata startnode="1">
+            <tw-passagedata pid="1" name="intro">Heads or tails?
+[[Flip-&gt;(either: &quot;heads&quot;, &quot;tails&quot;)]]</tw-passagedata>
+            <tw-passagedata pid="2" name="heads">Heads, (either: &quot;you win&quot;, &quot;you lose&quot;).
+[[Again-&gt;intro]]</tw-passagedata>
+            <tw-passagedata pid="3" name="tails">Tails.
+[[Again-&gt;intro]]</tw-passagedata>
+        </tw-storydata>
+    "#;
+
+    fn play_random(script: &str, seed: u64) -> String {
+        let steps = parse_script(script).unwrap();
+        let options = Options {
+            seed,
+            ..Options::default()
+        };
+        replay(&record(RANDOM_STORY, &steps, &mut NoStorage, &options))
+    }
+
+    #[test]
+    fn is_reproducible_with_a_seed() {
+        let runs: Vec<String> = (0..20).map(|seed| play_random("0,0,0,0", seed)).collect();
+        for (seed, run) in runs.iter().enumerate() {
+            assert_eq!(*run, play_random("0,0,0,0", seed as u64));
+        }
+
+        // but seeds make a difference
+        assert!(runs.iter().any(|run| *run != runs[0]));
+    }
+
+    #[test]
+    fn can_pick_random_text_and_links() {
+        let endings: HashSet<String> = (0..20)
+            .map(|seed| play_random("0", seed))
+            .map(|run| run.lines().nth(5).unwrap().to_string())
+            .collect();
+        assert_eq!(
+            endings,
+            HashSet::from(["Heads, you win.", "Heads, you lose.", "Tails."].map(String::from))
+        );
+    }
+
+    #[test]
+    fn can_erase_random_text() {
+        let heads = (0..)
+            .find(|seed| play_random("0", *seed).contains("Heads"))
+            .unwrap();
+        assert_eq!(play_random("0,b", heads), "Heads or tails?\n>  Flip\n");
+
+        // Back on heads after erasing, its random text is unknown and is typed again
+        let run = play_random("0,0,b,b", heads);
+        assert!(
+            run.ends_with("\n- - -\n\nHeads or tails?\n>  Flip\n"),
+            "{run}"
+        );
+    }
+
+    #[test]
+    fn can_go_back_by_erasing() {
+        let presses = parse_script("0,b").unwrap();
+        let reports = record(DEEP_STORY, &presses, &mut NoStorage, &Options::default());
+
+        // Erase "In the middle.\n" + "   End\n" + "\n\n\n" + "   Go\n   Stay\n"
+        assert_eq!(backspaces(&reports), 15 + 7 + 3 + 14);
+        assert_eq!(replay(&reports), "Hello!\n>  Go\n   Stay\n");
+
+        // and the menu works again
+        let presses = parse_script("0,b,1").unwrap();
+        let reports = record(DEEP_STORY, &presses, &mut NoStorage, &Options::default());
+        assert_eq!(replay(&reports), "Hello!\n   Go\n>  Stay\n\n\n\nThe end.");
+    }
+
+    #[test]
+    fn can_go_back_to_options_with_the_same_target() {
+        const STORY: &str = r#"
+            <tw-storydata startnode="1">
+                <tw-passagedata pid="1" name="intro">Hello!
+[[Walk-&gt;middle]]
+[[Run away-&gt;middle]]</tw-passagedata>
+                <tw-passagedata pid="2" name="middle">In the middle.
+[[End-&gt;end]]</tw-passagedata>
+                <tw-passagedata pid="3" name="end">The end.</tw-passagedata>
+            </tw-storydata>
+        "#;
+
+        // The menu erased is the one with the second option picked
+        for style in [
+            MenuStyle::Caret,
+            MenuStyle::Numbered,
+            MenuStyle::Retype,
+            MenuStyle::Cycle,
+        ] {
+            let options = Options {
+                menu: style,
+                ..Options::default()
+            };
+            let play = |script| {
+                let presses = parse_script(script).unwrap();
+                replay(&record(STORY, &presses, &mut NoStorage, &options))
+            };
+            assert_eq!(play("1,b"), play(""), "{style:?}");
+        }
+    }
+
+    #[test]
+    fn can_go_back_by_replaying() {
+        let options = Options {
+            go_back: cyoa::GoBack::Replay,
+            ..Options::default()
+        };
+        let presses = parse_script("0,b,1").unwrap();
+        let reports = record(DEEP_STORY, &presses, &mut NoStorage, &options);
+
+        assert_eq!(backspaces(&reports), 0);
+        assert_eq!(
+            replay(&reports),
+            concat!(
+                "Hello!\n>  Go\n   Stay\n\n\n\n",
+                "In the middle.\n>  End\n",
+                "\n- - -\n\n",
+                "Hello!\n   Go\n>  Stay\n\n\n\n",
+                "The end."
+            )
+        );
+    }
+
+    #[test]
+    fn cannot_go_back_from_start() {
+        let presses = parse_script("b,1").unwrap();
+        let reports = record(DEEP_STORY, &presses, &mut NoStorage, &Options::default());
+
+        assert_eq!(backspaces(&reports), 0);
+        assert_eq!(replay(&reports), "Hello!\n   Go\n>  Stay\n\n\n\nThe end.");
+    }
+
+    fn play_with_menu(style: MenuStyle, script: &str) -> String {
+        let options = Options {
+            menu: style,
+            ..Options::default()
+        };
+        let presses = parse_script(script).unwrap();
+        replay(&record(DEEP_STORY, &presses, &mut NoStorage, &options))
+    }
+
+    #[test]
+    fn can_render_menus() {
+        assert_eq!(
+            play_with_menu(MenuStyle::Caret, "1"),
+            "Hello!\n   Go\n>  Stay\n\n\n\nThe end."
+        );
+        assert_eq!(
+            play_with_menu(MenuStyle::Numbered, "1"),
+            "Hello!\n1) Go\n2) Stay\n> 2\n\n\n\nThe end."
+        );
+        assert_eq!(
+            play_with_menu(MenuStyle::Retype, "1"),
+            "Hello!\n   Go\n>  Stay\n\n\n\nThe end."
+        );
+        assert_eq!(
+            play_with_menu(MenuStyle::Cycle, "1"),
+            "Hello!\n>  Stay\n\n\n\nThe end."
+        );
+    }
+
+    #[test]
+    fn can_wrap_around_menus() {
+        for style in [
+            MenuStyle::Caret,
+            MenuStyle::Numbered,
+            MenuStyle::Retype,
+            MenuStyle::Cycle,
+        ] {
+            assert_eq!(
+                play_with_menu(style, "2"),
+                play_with_menu(style, "0,b,0"),
+                "{style:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn can_go_back_with_any_menu() {
+        // The passage with a menu is the second option (selected menus differ for some
+        // styles)
+        const STORY: &str = r#"
+            <tw-storydata startnode="1">
+                <tw-passagedata pid="1" name="intro">Hello!
+[[Stay-&gt;end]]
+[[Go somewhere-&gt;middle]]</tw-passagedata>
+                <tw-passagedata pid="2" name="middle">In the middle.
+[[Back-&gt;intro]]
+[[End-&gt;end]]</tw-passagedata>
+                <tw-passagedata pid="3" name="end">The end.</tw-passagedata>
+            </tw-storydata>
+        "#;
+
+        for style in [
+            MenuStyle::Caret,
+            MenuStyle::Numbered,
+            MenuStyle::Retype,
+            MenuStyle::Cycle,
+        ] {
+            let options = Options {
+                menu: style,
+                ..Options::default()
+            };
+            let play = |script| {
+                let presses = parse_script(script).unwrap();
+                replay(&record(STORY, &presses, &mut NoStorage, &options))
+            };
+
+            assert_eq!(play("1,b"), play(""), "{style:?}");
+            assert_eq!(play("1,0,b"), play("1"), "{style:?}");
+            assert_eq!(play("1,b,1,0,b"), play("1"), "{style:?}");
+        }
+    }
+
+    const LONG_LABELS_STORY: &str = r#"
+        <tw-storydata startnode="1">
+            <tw-passagedata pid="1" name="intro">The corridor splits in three directions.
+[[Follow the draft towards the cellar-&gt;cellar]]
+[[Climb-&gt;attic]]
+[[Knock on the old oak door-&gt;door]]</tw-passagedata>
+            <tw-passagedata pid="2" name="cellar">It's dark.</tw-passagedata>
+            <tw-passagedata pid="3" name="attic">Dusty.</tw-passagedata>
+            <tw-passagedata pid="4" name="door">Nobody answers.
+[[Leave-&gt;intro]]</tw-passagedata>
+        </tw-storydata>
+    "#;
+
+    #[test]
+    fn can_wrap_passages_and_menus() {
+        let options = Options {
+            width: Some(16),
+            ..Options::default()
+        };
+        let presses = parse_script("2").unwrap();
+        let reports = record(LONG_LABELS_STORY, &presses, &mut NoStorage, &options);
+
+        assert_eq!(
+            replay(&reports),
+            concat!(
+                "The corridor\nsplits in three\ndirections.\n",
+                "   Follow the\n   draft towards\n   the cellar\n",
+                "   Climb\n",
+                ">  Knock on the\n   old oak door\n",
+                "\n\n\n",
+                "Nobody answers.\n>  Leave\n"
+            )
+        );
+    }
+
+    #[test]
+    fn can_use_wrapped_menus() {
+        for style in [
+            MenuStyle::Caret,
+            MenuStyle::Numbered,
+            MenuStyle::Retype,
+            MenuStyle::Cycle,
+        ] {
+            for width in [6, 10, 16] {
+                let options = Options {
+                    menu: style,
+                    width: Some(width),
+                    ..Options::default()
+                };
+                let play = |script| {
+                    let presses = parse_script(script).unwrap();
+                    replay(&record(
+                        LONG_LABELS_STORY,
+                        &presses,
+                        &mut NoStorage,
+                        &options,
+                    ))
+                };
+
+                // Wrapped lines all fit, and the right option was picked
+                for (script, end) in [("0", "dark."), ("1", "Dusty."), ("3", "dark.")] {
+                    let text = play(script);
+                    assert!(text.ends_with(end), "{style:?} {width} {script}: {text}");
+                    assert!(text.split('\n').all(|line| line.len() <= width));
+                }
+
+                assert_eq!(play("2,b"), play(""), "{style:?} {width}");
+            }
+        }
+    }
+
+    const ALPHA_STORY: &str = r#"
+        <tw-storydata name="Alpha" ifid="A" startnode="1">
+            <tw-passagedata pid="1" name="intro">Alpha begins.
+[[Go-&gt;middle]]</tw-passagedata>
+            <tw-passagedata pid="2" name="middle">Alpha goes on.
+[[Go-&gt;end]]</tw-passagedata>
+            <tw-passagedata pid="3" name="end">Alpha ends.</tw-passagedata>
+        </tw-storydata>
+    "#;
+
+    const BETA_STORY: &str = r#"
+        <tw-storydata name="Beta" ifid="B" startnode="1">
+            <tw-passagedata pid="1" name="intro">Beta begins.
+[[Go-&gt;middle]]</tw-passagedata>
+            <tw-passagedata pid="2" name="middle">Beta goes on.
+[[Go-&gt;end]]</tw-passagedata>
+            <tw-passagedata pid="3" name="end">Beta ends.</tw-passagedata>
+        </tw-storydata>
+    "#;
+
+    #[test]
+    fn can_pick_stories() {
+        let mut last_story = MemoryStorage::default();
+        let mut saves = [MemoryStorage::default(), MemoryStorage::default()];
+        let mut play = |script| {
+            replay(&record_library(
+                &[ALPHA_STORY, BETA_STORY],
+                &parse_script(script).unwrap(),
+                &mut last_story,
+                &mut saves,
+                &Options::default(),
+            ))
+        };
+
+        assert_eq!(
+            play("1,0"),
+            "   Alpha\n>  Beta\n\n\n\nBeta begins.\n>  Go\n\n\n\nBeta goes on.\n>  Go\n"
+        );
+
+        // Beta was played last, and has a save
+        assert_eq!(play(""), ">  Beta\n   Alpha\n");
+        assert_eq!(
+            play("0"),
+            ">  Beta\n   Alpha\n\n\n\n>  Continue\n   New game\n"
+        );
+
+        // but Alpha doesn't
+        assert_eq!(play("1"), "   Beta\n>  Alpha\n\n\n\nAlpha begins.\n>  Go\n");
+        assert_eq!(play(""), ">  Alpha\n   Beta\n");
+    }
+
+    #[test]
+    fn lists_titles_as_they_read() {
+        let story = |name: &str| ALPHA_STORY.replace("\"Alpha\"", &format!("{name:?}"));
+        let play = |stories: &[&str], script| {
+            replay(&record_library(
+                stories,
+                &parse_script(script).unwrap(),
+                &mut NoStorage,
+                &mut [NoStorage, NoStorage],
+                &Options::default(),
+            ))
+        };
+
+        // Decoded, and without link syntax (see cyoa::picker_title)
+        let odd = story("Jerry&#39;s [[big]] day -&gt; out");
+        assert_eq!(
+            play(&[&odd, BETA_STORY], "1"),
+            "   Jerry's ((big)) day -> out\n>  Beta\n\n\n\nBeta begins.\n>  Go\n"
+        );
+
+        // Long titles are cut
+        let long = story(&"Long ".repeat(200));
+        let title = "Long ".repeat(200)[..cyoa::TITLE_SIZE].to_string();
+        assert_eq!(
+            play(&[&long, BETA_STORY], "1"),
+            format!("   {title}\n>  Beta\n\n\n\nBeta begins.\n>  Go\n")
+        );
+        assert!(play(&[&long, BETA_STORY], "0").ends_with("Alpha begins.\n>  Go\n"));
+    }
+
+    #[test]
+    fn can_continue_saved_story() {
+        let mut storage = MemoryStorage::default();
+        record(
+            DEEP_STORY,
+            &parse_script("0").unwrap(),
+            &mut storage,
+            &Options::default(),
+        );
+
+        // Continue (the first option), then go back to the intro (by replaying, since the
+        // intro isn't on screen anymore) and pick the second option.
+        let reports = record(
+            DEEP_STORY,
+            &parse_script("0,b,1").unwrap(),
+            &mut storage,
+            &Options::default(),
+        );
+        assert_eq!(
+            replay(&reports),
+            concat!(
+                ">  Continue\n   New game\n\n\n\n",
+                "In the middle.\n>  End\n",
+                "\n- - -\n\n",
+                "Hello!\n   Go\n>  Stay\n\n\n\n",
+                "The end."
+            )
+        );
+
+        // The story is over, so there's nothing to continue
+        let reports = record(
+            DEEP_STORY,
+            &parse_script("").unwrap(),
+            &mut storage,
+            &Options::default(),
+        );
+        assert!(replay(&reports).starts_with("Hello!"));
+    }
+
+    #[test]
+    fn can_start_new_game() {
+        let mut storage = MemoryStorage::default();
+        record(
+            DEEP_STORY,
+            &parse_script("0").unwrap(),
+            &mut storage,
+            &Options::default(),
+        );
+
+        let reports = record(
+            DEEP_STORY,
+            &parse_script("1").unwrap(),
+            &mut storage,
+            &Options::default(),
+        );
+        assert_eq!(
+            replay(&reports),
+            "   Continue\n>  New game\n\n\n\nHello!\n>  Go\n   Stay\n"
+        );
+    }
+
+    #[test]
+    fn stops_when_out_of_choices() {
+        let transcript = play_script(STORY, &[]);
+        assert_eq!(transcript, "Hello!\n>  Left\n   Right\n");
+    }
+}
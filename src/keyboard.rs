@@ -1,37 +1,141 @@
 //! USB Keyboard (HID class) helpers
+//!
+//! The keyboard sends either boot reports ([`KeyboardReport`], up to 6 keys at once) or
+//! [N-key rollover reports](nkro), see [`ReportFormat`]. Either way, typing can press
+//! several keys in one report to go faster, see [`batch_report`], which [bulk] typing
+//! takes advantage of.
 
-use embassy_rp::peripherals::USB;
-use embassy_rp::usb::Driver;
-use embassy_time::{Duration, Timer};
-use embassy_usb::class::hid;
-use usbd_hid::descriptor::KeyboardReport;
+use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+use embassy_sync::watch::Watch;
+use embassy_time::{with_timeout, Duration, Timer};
+use embassy_usb::class::hid::{self, ReportId, RequestHandler};
+use embassy_usb::control::OutResponse;
+use embassy_usb::driver::Driver;
+use usbd_hid::descriptor::{KeyboardReport, SerializedDescriptor};
 
-type HidWriter<'a> = hid::HidWriter<'a, Driver<'a, USB>, 8>;
+pub mod bulk;
+pub mod nkro;
 
-/// Write an ASCII-interpreted byte to the HID device
+use nkro::NkroReport;
+
+/// The keyboard interface's writer, large enough for either [`ReportFormat`]
+pub type HidWriter<'a, D> = hid::HidWriter<'a, D, { nkro::REPORT_SIZE }>;
+
+/// The keyboard interface, reading the host's output reports (see [`LockKeys`])
+pub type HidReaderWriter<'a, D> = hid::HidReaderWriter<'a, D, 1, { nkro::REPORT_SIZE }>;
+
+/// The reports the keyboard sends, which its HID interface must be configured for
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ReportFormat {
+    /// Boot reports ([`KeyboardReport`]): up to 6 keys at once, understood by any host
+    #[default]
+    Boot,
+    /// N-key rollover reports ([`NkroReport`]), starting with the boot report
+    Nkro,
+}
+
+impl ReportFormat {
+    /// The report descriptor, for the HID interface's config
+    pub fn descriptor(self) -> &'static [u8] {
+        match self {
+            ReportFormat::Boot => KeyboardReport::desc(),
+            ReportFormat::Nkro => nkro::DESCRIPTOR,
+        }
+    }
+}
+
+/// Write an ASCII-interpreted byte to the HID device, inverting shift for letters if the
+/// host's Caps Lock is on (see [`CapsLockPolicy::InvertShift`])
 /// NOTE: If the SHIFT key has to be pressed, a delay is introduced
-pub async fn write_ascii_byte<'a>(writer: &mut HidWriter<'a>, chr: u8) {
-    let (keycode, shifted) = char_to_keycode(chr);
+pub async fn write_ascii_byte<'a, D: Driver<'a>>(
+    writer: &mut HidWriter<'a, D>,
+    chr: u8,
+    caps_lock: bool,
+) {
+    let (report_shift, report_char) = ascii_byte_reports(chr, caps_lock);
 
-    if shifted {
-        let report_shift = KeyboardReport {
-            modifier: 2,
-            reserved: 0,
-            leds: 0,
-            keycodes: [0, 0, 0, 0, 0, 0],
-        };
+    if let Some(report_shift) = report_shift {
         let _ = writer.write_serialize(&report_shift).await;
         Timer::after(Duration::from_millis(30)).await;
     }
 
-    let report_char = KeyboardReport {
-        modifier: 2,
+    let _ = writer.write_serialize(&report_char).await;
+}
+
+/// The reports of [`write_ascii_byte`]: the modifiers alone first (if the key needs any),
+/// then the key with them
+fn ascii_byte_reports(chr: u8, caps_lock: bool) -> (Option<KeyboardReport>, KeyboardReport) {
+    let report_char = char_report(chr, caps_lock);
+    let report_shift = (report_char.modifier != 0).then_some(KeyboardReport {
+        keycodes: [0, 0, 0, 0, 0, 0],
+        ..report_char
+    });
+    (report_shift, report_char)
+}
+
+/// How to type when the host's Caps Lock is on, which would otherwise invert the case
+/// of letters
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CapsLockPolicy {
+    /// Type as if it was off
+    Ignore,
+    /// Turn it off before typing, and leave it off (turning it back on after each text
+    /// would flip it all the time, e.g. for every line of a passage)
+    TurnOff,
+    /// Type letters with shift inverted
+    #[default]
+    InvertShift,
+}
+
+/// The report typing the ASCII char, with shift inverted for letters if 'invert_shift'
+/// (i.e. when the host's Caps Lock is on)
+pub fn char_report(chr: u8, invert_shift: bool) -> KeyboardReport {
+    let (keycode, shifted) = char_to_keycode(chr);
+    let shifted = shifted ^ (invert_shift && chr.is_ascii_alphabetic());
+    KeyboardReport {
+        modifier: if shifted { modifier::SHIFT } else { 0 },
         reserved: 0,
         leds: 0,
         keycodes: [keycode, 0, 0, 0, 0, 0],
-    };
+    }
+}
 
-    let _ = writer.write_serialize(&report_char).await;
+/// The report pressing the next keys of the text at once (at most 'max_keys', and
+/// always at least one), and how many bytes of the text it types.
+///
+/// Hosts register the keys of a report that weren't in the previous one, so the keys are
+/// distinct and have to be released before they can be typed again. Keys pressed together
+/// share the modifiers, and the host sees them in the order of the report (boot reports)
+/// or by increasing keycode (NKRO reports, which are bitmaps), so the batch stops short
+/// of any key that would break these rules.
+///
+/// NOTE: hosts seem to handle the keys of boot reports in order, but the HID spec doesn't
+/// require them to. Leave 'max_keys' at 1 if keys come out of order.
+pub fn batch_report(
+    text: &[u8],
+    invert_shift: bool,
+    max_keys: usize,
+    format: ReportFormat,
+) -> (KeyboardReport, usize) {
+    let mut report = ALL_KEYS_UP;
+    let mut len = 0;
+
+    for (i, chr) in text.iter().enumerate().take(max_keys.clamp(1, 6)) {
+        let next = char_report(*chr, invert_shift);
+        let keycode = next.keycodes[0];
+        if i > 0 {
+            let keys = &report.keycodes[..i];
+            let in_order = format == ReportFormat::Boot || keys[i - 1] < keycode;
+            if next.modifier != report.modifier || keys.contains(&keycode) || !in_order {
+                break;
+            }
+        }
+        report.modifier = next.modifier;
+        report.keycodes[i] = keycode;
+        len += 1;
+    }
+
+    (report, len)
 }
 
 /// Map ASCII chars to keyboard (US layout) keycodes. The second tuple element
@@ -46,7 +150,11 @@ pub fn char_to_keycode(chr: u8) -> (u8, bool) {
     match char::from(chr) {
         'a'..='z' => (chr - b'a' + 4, false),
         'A'..='Z' => (chr - b'A' + 4, true),
+        '1'..='9' => (chr - b'1' + 30, false),
+        '0' => (39, false),
         '!' => (30, true),
+        '(' => (38, true),
+        ')' => (39, true),
         '\n' => (40, false),
         ' ' => (44, false),
         '-' => (45, false),
@@ -63,6 +171,29 @@ pub fn char_to_keycode(chr: u8) -> (u8, bool) {
     }
 }
 
+/// Modifier bits of a [`KeyboardReport`]
+pub mod modifier {
+    pub const CTRL: u8 = 0x01;
+    pub const SHIFT: u8 = 0x02;
+    pub const ALT: u8 = 0x04;
+    pub const GUI: u8 = 0x08;
+}
+
+/// Keycodes of keys that don't type an ASCII char (see [`char_to_keycode`] for those)
+pub mod keycode {
+    pub const ENTER: u8 = 40;
+    pub const ESCAPE: u8 = 41;
+    pub const SPACE: u8 = 44;
+    pub const CAPS_LOCK: u8 = 57;
+    pub const SCROLL_LOCK: u8 = 71;
+    pub const PAGE_UP: u8 = 75;
+    pub const PAGE_DOWN: u8 = 78;
+    pub const RIGHT: u8 = 79;
+    pub const LEFT: u8 = 80;
+    pub const DOWN: u8 = 81;
+    pub const UP: u8 = 82;
+}
+
 pub const ALL_KEYS_UP: KeyboardReport = KeyboardReport {
     modifier: 0,
     reserved: 0,
@@ -70,27 +201,507 @@ pub const ALL_KEYS_UP: KeyboardReport = KeyboardReport {
     keycodes: [0x00, 0, 0, 0, 0, 0],
 };
 
+const CAPS_LOCK: KeyboardReport = KeyboardReport {
+    modifier: 0,
+    reserved: 0,
+    leds: 0,
+    keycodes: [keycode::CAPS_LOCK, 0, 0, 0, 0, 0],
+};
+
+const SCROLL_LOCK: KeyboardReport = KeyboardReport {
+    modifier: 0,
+    reserved: 0,
+    leds: 0,
+    keycodes: [keycode::SCROLL_LOCK, 0, 0, 0, 0, 0],
+};
+
+/// How long [`HidKeyboard::sync`] waits for the host to echo Scroll Lock
+pub const ECHO_TIMEOUT: Duration = Duration::from_millis(500);
+
 /// Release all keys on the keyboard
-pub async fn release_keys<'a>(writer: &mut HidWriter<'a>) {
+pub async fn release_keys<'a, D: Driver<'a>>(writer: &mut HidWriter<'a, D>) {
     let _ = writer.write_serialize(&ALL_KEYS_UP).await;
 }
 
-/// Write an entire string
-pub async fn write_str<'a>(writer: &mut HidWriter<'a>, s: &'a str, delay: Duration) {
-    let total = s.len();
-    let mut ix = 0;
-    let bytes = s.as_bytes();
+/// Write an entire string, dealing with the host's Caps Lock (as last reported in
+/// 'lock_keys') according to the policy
+pub async fn write_str<'a, D: Driver<'a>>(
+    writer: &mut HidWriter<'a, D>,
+    s: &'a str,
+    delay: Duration,
+    lock_keys: &LockSignal,
+    caps_lock: CapsLockPolicy,
+) {
+    let mut keyboard = HidKeyboard {
+        writer,
+        delay,
+        lock_keys: Some(lock_keys),
+        caps_lock,
+        format: ReportFormat::Boot,
+        max_batch: 1,
+    };
+    keyboard.type_str(s).await;
+}
+
+/// Anything keyboard reports can be sent to: the USB HID writer on the device,
+/// or e.g. an emulated text editor on the host.
+#[allow(async_fn_in_trait)]
+pub trait Keyboard {
+    /// Send a report. Implementations may wait afterwards to give the host time
+    /// to register it.
+    async fn send(&mut self, report: &KeyboardReport);
+
+    /// Set the time to wait after each report, if applicable
+    fn set_delay(&mut self, _delay: Duration) {}
+
+    /// Wait before sending the next report, if applicable
+    async fn pause(&mut self, _duration: Duration) {}
+
+    /// The reports the host reads
+    fn report_format(&self) -> ReportFormat {
+        ReportFormat::Boot
+    }
+
+    /// How many keys typing may press in one report, see [`batch_report`]
+    fn max_batch(&self) -> usize {
+        1
+    }
+
+    /// Whether the host's Caps Lock is on, if known
+    fn caps_lock(&self) -> bool {
+        false
+    }
+
+    /// How to type when the host's Caps Lock is on
+    fn caps_lock_policy(&self) -> CapsLockPolicy {
+        CapsLockPolicy::default()
+    }
+
+    /// Tap Caps Lock, which is on. Implementations may wait for the host to report it off,
+    /// so that [`Keyboard::caps_lock`] is up to date.
+    async fn turn_off_caps_lock(&mut self) {
+        self.tap(&CAPS_LOCK).await;
+    }
+
+    /// Wait until the host has handled the reports sent so far, returning 'false' if
+    /// that can't be known (see [`bulk`])
+    async fn sync(&mut self) -> bool {
+        false
+    }
+
+    /// Press and release a key
+    async fn tap(&mut self, report: &KeyboardReport) {
+        self.send(report).await;
+        self.send(&ALL_KEYS_UP).await;
+    }
+
+    /// Type an entire string, as ASCII bytes, in batches of up to [`Keyboard::max_batch`]
+    async fn type_str(&mut self, s: &str) {
+        let caps_lock = self.caps_lock();
+        let policy = self.caps_lock_policy();
+        let invert_shift = caps_lock && policy == CapsLockPolicy::InvertShift;
+
+        if caps_lock && policy == CapsLockPolicy::TurnOff {
+            self.turn_off_caps_lock().await;
+        }
+
+        let mut rest = s.as_bytes();
+        while !rest.is_empty() {
+            let (report, len) =
+                batch_report(rest, invert_shift, self.max_batch(), self.report_format());
+            rest = &rest[len..];
+
+            if report.modifier != 0 {
+                self.send(&KeyboardReport {
+                    keycodes: [0, 0, 0, 0, 0, 0],
+                    ..report
+                })
+                .await;
+            }
+
+            self.tap(&report).await;
+        }
+    }
+}
+
+/// Keyboard writing to the USB HID device, waiting 'delay' after each report
+pub struct HidKeyboard<'w, 'd, D: Driver<'d>> {
+    pub writer: &'w mut HidWriter<'d, D>,
+    pub delay: Duration,
+    /// The host's lock keys, if they are read
+    pub lock_keys: Option<&'w LockSignal>,
+    pub caps_lock: CapsLockPolicy,
+    /// The reports the interface was configured for
+    pub format: ReportFormat,
+    /// How many keys typing may press in one report
+    pub max_batch: usize,
+}
 
-    while ix < total {
-        let chr = bytes[ix];
+impl<'d, D: Driver<'d>> Keyboard for HidKeyboard<'_, 'd, D> {
+    async fn send(&mut self, report: &KeyboardReport) {
+        let _ = match self.format {
+            ReportFormat::Boot => self.writer.write_serialize(report).await,
+            ReportFormat::Nkro => {
+                self.writer
+                    .write(&NkroReport::from(report).to_bytes())
+                    .await
+            }
+        };
+        Timer::after(self.delay).await;
+    }
+
+    fn report_format(&self) -> ReportFormat {
+        self.format
+    }
+
+    fn max_batch(&self) -> usize {
+        self.max_batch
+    }
+
+    fn set_delay(&mut self, delay: Duration) {
+        self.delay = delay;
+    }
+
+    async fn pause(&mut self, duration: Duration) {
+        Timer::after(duration).await;
+    }
+
+    /// Tap Scroll Lock twice, waiting for the host to echo each tap in its output reports
+    /// (within [`ECHO_TIMEOUT`]), which it does after handling the reports before. Needs
+    /// one of the [`LockSignal`]'s receivers.
+    async fn sync(&mut self) -> bool {
+        let Some(mut receiver) = self.lock_keys.and_then(|lock_keys| lock_keys.receiver()) else {
+            return false;
+        };
+        let mut echoed = true;
+        for _ in 0..2 {
+            let Some(before) = receiver.try_get() else {
+                return false;
+            };
+            // Tap it back even if the first one wasn't echoed: the host may just not
+            // have a Scroll Lock LED
+            self.tap(&SCROLL_LOCK).await;
+            let echo = receiver.changed_and(|keys| keys.scroll_lock != before.scroll_lock);
+            echoed &= with_timeout(ECHO_TIMEOUT, echo).await.is_ok();
+        }
+        echoed
+    }
+
+    /// Wait for the host to report Caps Lock off (within [`ECHO_TIMEOUT`]), so that the next
+    /// text isn't typed with a stale state
+    async fn turn_off_caps_lock(&mut self) {
+        let receiver = self.lock_keys.and_then(|lock_keys| lock_keys.receiver());
+        self.tap(&CAPS_LOCK).await;
+        if let Some(mut receiver) = receiver {
+            let echo = receiver.get_and(|keys| !keys.caps_lock);
+            let _ = with_timeout(ECHO_TIMEOUT, echo).await;
+        }
+    }
+
+    fn caps_lock(&self) -> bool {
+        self.lock_keys
+            .and_then(|lock_keys| lock_keys.try_get())
+            .is_some_and(|keys| keys.caps_lock)
+    }
+
+    fn caps_lock_policy(&self) -> CapsLockPolicy {
+        self.caps_lock
+    }
+}
+
+/// The host's lock keys, as it sets the keyboard LEDs in the output reports
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct LockKeys {
+    pub num_lock: bool,
+    pub caps_lock: bool,
+    pub scroll_lock: bool,
+}
+
+impl LockKeys {
+    /// Parse an output report: one byte of LED bits (see the HID Usage Tables, section 11)
+    pub fn from_report(report: &[u8]) -> Option<LockKeys> {
+        let [leds] = report else {
+            return None;
+        };
+        Some(LockKeys {
+            num_lock: leds & 0x01 != 0,
+            caps_lock: leds & 0x02 != 0,
+            scroll_lock: leds & 0x04 != 0,
+        })
+    }
+}
+
+/// The latest lock keys, for whoever needs them (e.g. the LEDs and the typing)
+pub type LockSignal = Watch<NoopRawMutex, LockKeys, 4>;
+
+/// Handles the keyboard's output reports, updating the lock keys. Hosts send them either
+/// through the interrupt pipe ([`hid::HidReader::run`]) or the control pipe (the config's
+/// `request_handler`), so there is usually one handler for each.
+pub struct LockKeysHandler<'a> {
+    pub signal: &'a LockSignal,
+}
+
+impl RequestHandler for LockKeysHandler<'_> {
+    fn set_report(&mut self, _id: ReportId, data: &[u8]) -> OutResponse {
+        let Some(keys) = LockKeys::from_report(data) else {
+            return OutResponse::Rejected;
+        };
+        // NOTE: the host sends the state again e.g. when another keyboard's lock keys
+        // change, which receivers needn't hear about
+        self.signal.sender().send_if_modified(|current| {
+            let modified = *current != Some(keys);
+            *current = Some(keys);
+            modified
+        });
+        OutResponse::Accepted
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    #[test]
+    fn parses_lock_keys() {
+        assert_eq!(LockKeys::from_report(&[0]), Some(LockKeys::default()));
+        assert_eq!(
+            LockKeys::from_report(&[0x02]),
+            Some(LockKeys {
+                caps_lock: true,
+                ..Default::default()
+            })
+        );
+        assert_eq!(
+            LockKeys::from_report(&[0x1F]),
+            Some(LockKeys {
+                num_lock: true,
+                caps_lock: true,
+                scroll_lock: true,
+            })
+        );
+        assert_eq!(LockKeys::from_report(&[]), None);
+        assert_eq!(LockKeys::from_report(&[0, 0]), None);
+    }
+
+    #[test]
+    fn handles_output_reports() {
+        let signal = LockSignal::new();
+        let mut receiver = signal.receiver().unwrap();
+        let mut handler = LockKeysHandler { signal: &signal };
+        assert_eq!(signal.try_get(), None);
+
+        let response = handler.set_report(ReportId::Out(0), &[0x02]);
+        assert!(matches!(response, OutResponse::Accepted));
+        assert_eq!(
+            receiver.try_changed().map(|keys| keys.caps_lock),
+            Some(true)
+        );
+
+        // Only changes are signaled
+        handler.set_report(ReportId::Out(0), &[0x02]);
+        assert_eq!(receiver.try_changed(), None);
+
+        handler.set_report(ReportId::Out(0), &[0x00]);
+        assert_eq!(
+            receiver.try_changed().map(|keys| keys.caps_lock),
+            Some(false)
+        );
+
+        let response = handler.set_report(ReportId::Out(0), &[]);
+        assert!(matches!(response, OutResponse::Rejected));
+        assert_eq!(signal.try_get(), Some(LockKeys::default()));
+    }
+
+    #[test]
+    fn inverts_shift_for_letters() {
+        let report = |chr, invert| {
+            let report = char_report(chr, invert);
+            (report.modifier, report.keycodes[0])
+        };
+        assert_eq!(report(b'a', false), (0, 4));
+        assert_eq!(report(b'A', false), (modifier::SHIFT, 4));
+        assert_eq!(report(b'a', true), (modifier::SHIFT, 4));
+        assert_eq!(report(b'A', true), (0, 4));
+
+        // Caps Lock doesn't affect other keys
+        for chr in [b'1', b'!', b'.', b'?', b' '] {
+            assert_eq!(report(chr, true), report(chr, false));
+        }
+    }
+
+    #[test]
+    fn writes_shift_only_for_shifted_keys() {
+        // Holding shift for every key typed e.g. "LOREM< IPSUM>" for "lorem, ipsum."
+        for chr in b"lorem ipsum, dolor-sit'amet./\n".iter().copied() {
+            let (shift, report) = ascii_byte_reports(chr, false);
+            assert_eq!((shift, report.modifier), (None, 0), "{:?}", chr as char);
+        }
+
+        let (shift, report) = ascii_byte_reports(b'L', false);
+        assert_eq!(shift.map(|shift| shift.modifier), Some(modifier::SHIFT));
+        assert_eq!(shift.map(|shift| shift.keycodes), Some([0; 6]));
+        assert_eq!((report.modifier, report.keycodes[0]), (modifier::SHIFT, 15));
+
+        // and the Caps Lock compensation applies
+        let (shift, report) = ascii_byte_reports(b'l', true);
+        assert!(shift.is_some());
+        assert_eq!((report.modifier, report.keycodes[0]), (modifier::SHIFT, 15));
+        assert_eq!(
+            ascii_byte_reports(b'L', true),
+            (None, char_report(b'l', false))
+        );
+    }
+
+    /// What the host gets when typing the text with Caps Lock on or off, and whether it
+    /// is on afterwards
+    fn typed(text: &str, caps_lock: bool, policy: CapsLockPolicy) -> (String, bool) {
+        let mut editor = crate::host::Editor::new();
+        editor.caps_lock = caps_lock;
+        editor.caps_lock_policy = policy;
+        embassy_futures::block_on(editor.type_str(text));
+        (editor.text(), editor.caps_lock)
+    }
+
+    #[test]
+    fn types_with_caps_lock() {
+        let text = "Hello, World! 42?";
+        for policy in [
+            CapsLockPolicy::Ignore,
+            CapsLockPolicy::TurnOff,
+            CapsLockPolicy::InvertShift,
+        ] {
+            assert_eq!(
+                typed(text, false, policy),
+                (text.into(), false),
+                "{policy:?}"
+            );
+        }
+
+        assert_eq!(
+            typed(text, true, CapsLockPolicy::Ignore),
+            ("hELLO, wORLD! 42?".into(), true)
+        );
+        assert_eq!(
+            typed(text, true, CapsLockPolicy::TurnOff),
+            (text.into(), false)
+        );
+        assert_eq!(
+            typed(text, true, CapsLockPolicy::InvertShift),
+            (text.into(), true)
+        );
+    }
+
+    #[test]
+    fn turns_caps_lock_off_once() {
+        /// The host's Caps Lock, and the reports sent as (modifier, first keycode)
+        struct CapsLocked(bool, Vec<(u8, u8)>);
+
+        impl Keyboard for CapsLocked {
+            async fn send(&mut self, report: &KeyboardReport) {
+                self.0 ^= report.keycodes[0] == keycode::CAPS_LOCK;
+                self.1.push((report.modifier, report.keycodes[0]));
+            }
+
+            fn caps_lock(&self) -> bool {
+                self.0
+            }
+
+            fn caps_lock_policy(&self) -> CapsLockPolicy {
+                CapsLockPolicy::TurnOff
+            }
+        }
+
+        let mut keyboard = CapsLocked(true, vec![]);
+        embassy_futures::block_on(async {
+            keyboard.type_str("a").await;
+            keyboard.type_str("a").await;
+        });
+        assert!(!keyboard.0);
+        assert_eq!(
+            keyboard.1,
+            [
+                (0, keycode::CAPS_LOCK),
+                (0, 0),
+                (0, 4),
+                (0, 0),
+                (0, 4),
+                (0, 0)
+            ]
+        );
+    }
+
+    #[test]
+    fn batches_distinct_keys() {
+        let batch = |text: &str, max_keys, format| {
+            let (report, len) = batch_report(text.as_bytes(), false, max_keys, format);
+            (report.modifier, report.keycodes, len)
+        };
+        let boot = ReportFormat::Boot;
+        let nkro = ReportFormat::Nkro;
+
+        // t, h, e
+        assert_eq!(batch("the", 6, boot), (0, [23, 11, 8, 0, 0, 0], 3));
+        assert_eq!(batch("the", 2, boot), (0, [23, 11, 0, 0, 0, 0], 2));
+        assert_eq!(batch("the", 1, boot), (0, [23, 0, 0, 0, 0, 0], 1));
+        assert_eq!(batch("the", 0, boot), (0, [23, 0, 0, 0, 0, 0], 1));
+        assert_eq!(batch("abcdefgh", 10, boot), (0, [4, 5, 6, 7, 8, 9], 6));
+
+        // The same key is released before being pressed again
+        assert_eq!(batch("hello", 6, boot), (0, [11, 8, 15, 0, 0, 0], 3));
+        assert_eq!(batch("lo", 6, boot), (0, [15, 18, 0, 0, 0, 0], 2));
+
+        // Shifted keys are pressed together, but not with unshifted ones
+        assert_eq!(
+            batch("Hello", 6, boot),
+            (modifier::SHIFT, [11, 0, 0, 0, 0, 0], 1)
+        );
+        assert_eq!(
+            batch("AB!c", 6, boot),
+            (modifier::SHIFT, [4, 5, 30, 0, 0, 0], 3)
+        );
+
+        // Bitmaps can only press keys by increasing keycode
+        assert_eq!(batch("the", 6, nkro), (0, [23, 0, 0, 0, 0, 0], 1));
+        assert_eq!(batch("he", 6, nkro), (0, [11, 0, 0, 0, 0, 0], 1));
+        assert_eq!(batch("abcdefgh", 10, nkro), (0, [4, 5, 6, 7, 8, 9], 6));
+        assert_eq!(batch("lo", 6, nkro), (0, [15, 18, 0, 0, 0, 0], 2));
+    }
+
+    #[test]
+    fn types_in_batches() {
+        let text = "The quick brown fox jumps over the lazy dog!\nHello, World? 1234567890";
+        let reports = |format, max_batch| {
+            let mut editor = crate::host::Editor::new();
+            editor.format = format;
+            editor.max_batch = max_batch;
+            let mut keyboard = Counting(editor, 0);
+            embassy_futures::block_on(keyboard.type_str(text));
+            assert_eq!(keyboard.0.text(), text, "{format:?} {max_batch}");
+            keyboard.1
+        };
+
+        let one_at_a_time = reports(ReportFormat::Boot, 1);
+        assert_eq!(one_at_a_time, reports(ReportFormat::Nkro, 1));
+        assert!(reports(ReportFormat::Boot, 6) < one_at_a_time * 2 / 3);
+        assert!(reports(ReportFormat::Nkro, 6) < one_at_a_time);
+    }
 
-        ix += 1;
+    /// Counts the reports sent to the editor
+    struct Counting(crate::host::Editor, usize);
 
-        write_ascii_byte(writer, chr).await;
+    impl Keyboard for Counting {
+        async fn send(&mut self, report: &KeyboardReport) {
+            self.1 += 1;
+            self.0.send(report).await;
+        }
 
-        Timer::after(delay).await;
+        fn report_format(&self) -> ReportFormat {
+            self.0.report_format()
+        }
 
-        release_keys(writer).await;
-        Timer::after(delay).await;
+        fn max_batch(&self) -> usize {
+            self.0.max_batch()
+        }
     }
 }
@@ -0,0 +1,258 @@
+//! Bulk typing: long texts, as fast as the host takes them
+//!
+//! Normal typing sends 2 or 3 reports per char, each followed by a delay of tens of ms
+//! (e.g. [`crate::cyoa::DELAY`]), through an interface polled every 60 ms: about a dozen
+//! chars per second. In bulk mode instead (e.g. the clicker's `send`, see
+//! [`crate::proxy`]):
+//!
+//! - the HID interface is polled every [`POLL_MS`] (which the firmware sets when building
+//!   it),
+//! - the keyboard waits [`DELAY`] (a poll) after each report,
+//! - reports press up to [`MAX_BATCH`] distinct keys at once (see [`super::batch_report`]).
+//!
+//! Some hosts can't keep up with that (remote desktops and VM consoles drop keys), so
+//! [`Bulk`] regularly waits for the host to catch up with [`Keyboard::sync`]: the
+//! keyboard taps Scroll Lock, and the host only echoes it in the LEDs of its output
+//! report once it has handled the keys before. Hosts that don't echo it (e.g. macOS has
+//! no Scroll Lock) are given a safety margin instead.
+
+use embassy_time::Duration;
+
+use super::Keyboard;
+
+/// The HID interface's polling interval, in ms
+pub const POLL_MS: u8 = 1;
+
+/// Time to wait after each report
+pub const DELAY: Duration = Duration::from_millis(1);
+
+/// How many keys typing presses in one report
+pub const MAX_BATCH: usize = 6;
+
+/// How [`type_bulk`] lets the host catch up
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FlowControl {
+    /// Wait for the host every that many bytes typed (0 to never wait)
+    pub sync_every: usize,
+    /// How long to wait instead, when the host doesn't echo the lock keys
+    pub margin: Duration,
+}
+
+impl Default for FlowControl {
+    fn default() -> Self {
+        FlowControl {
+            sync_every: 256,
+            margin: Duration::from_millis(100),
+        }
+    }
+}
+
+/// Types text, waiting for the host to catch up every [`FlowControl::sync_every`] bytes,
+/// across calls (e.g. for text streamed in pieces)
+pub struct Bulk {
+    flow: FlowControl,
+    /// Bytes typed since the host last caught up
+    pending: usize,
+    echoed: bool,
+}
+
+impl Bulk {
+    pub fn new(flow: FlowControl) -> Self {
+        Bulk {
+            flow,
+            pending: 0,
+            echoed: true,
+        }
+    }
+
+    /// Whether the host echoed the lock keys every time so far. Once it fails to, the
+    /// margin is waited instead.
+    pub fn echoed(&self) -> bool {
+        self.echoed
+    }
+
+    pub async fn type_str(&mut self, keyboard: &mut impl Keyboard, text: &str) {
+        if self.flow.sync_every == 0 {
+            keyboard.type_str(text).await;
+            return;
+        }
+
+        let mut rest = text;
+        while !rest.is_empty() {
+            let mut end = (self.flow.sync_every - self.pending).min(rest.len());
+            while !rest.is_char_boundary(end) {
+                end += 1;
+            }
+            let (chunk, after) = rest.split_at(end);
+            rest = after;
+
+            keyboard.type_str(chunk).await;
+            self.pending += chunk.len();
+            if self.pending >= self.flow.sync_every {
+                self.catch_up(keyboard).await;
+            }
+        }
+    }
+
+    /// Let the host catch up with what was typed since it last did
+    pub async fn finish(&mut self, keyboard: &mut impl Keyboard) {
+        if self.pending > 0 {
+            self.catch_up(keyboard).await;
+        }
+    }
+
+    async fn catch_up(&mut self, keyboard: &mut impl Keyboard) {
+        self.pending = 0;
+        if self.echoed {
+            self.echoed = keyboard.sync().await;
+        }
+        if !self.echoed {
+            keyboard.pause(self.flow.margin).await;
+        }
+    }
+}
+
+/// Type the text in chunks of [`FlowControl::sync_every`] bytes, waiting for the host to
+/// catch up after each one (see [`Bulk`]). Returns whether the host echoed the lock keys
+/// every time.
+pub async fn type_bulk(keyboard: &mut impl Keyboard, text: &str, flow: &FlowControl) -> bool {
+    let mut bulk = Bulk::new(*flow);
+    bulk.type_str(keyboard, text).await;
+    bulk.finish(keyboard).await;
+    bulk.echoed()
+}
+
+#[cfg(test)]
+mod test {
+
+    use std::collections::VecDeque;
+
+    use usbd_hid::descriptor::KeyboardReport;
+
+    use super::*;
+    use crate::host::Editor;
+
+    /// A host handling reports later than they are sent, dropping them once 'capacity'
+    /// are pending. It catches up when synced (if it echoes the lock keys) or paused.
+    struct SlowHost {
+        editor: Editor,
+        pending: VecDeque<KeyboardReport>,
+        capacity: usize,
+        echoes: bool,
+        syncs: usize,
+        pauses: usize,
+    }
+
+    impl SlowHost {
+        fn new(capacity: usize, echoes: bool) -> SlowHost {
+            SlowHost {
+                editor: Editor::new(),
+                pending: VecDeque::new(),
+                capacity,
+                echoes,
+                syncs: 0,
+                pauses: 0,
+            }
+        }
+
+        fn catch_up(&mut self) {
+            for report in self.pending.drain(..) {
+                self.editor.process(&report);
+            }
+        }
+
+        fn typed(mut self, text: &str, flow: FlowControl) -> (String, bool, usize, usize) {
+            let echoed = embassy_futures::block_on(type_bulk(&mut self, text, &flow));
+            self.catch_up();
+            (self.editor.text(), echoed, self.syncs, self.pauses)
+        }
+    }
+
+    impl Keyboard for SlowHost {
+        async fn send(&mut self, report: &KeyboardReport) {
+            if self.pending.len() < self.capacity {
+                self.pending.push_back(*report);
+            }
+        }
+
+        fn max_batch(&self) -> usize {
+            MAX_BATCH
+        }
+
+        async fn pause(&mut self, _duration: Duration) {
+            self.pauses += 1;
+            self.catch_up();
+        }
+
+        async fn sync(&mut self) -> bool {
+            self.syncs += 1;
+            if self.echoes {
+                self.catch_up();
+            }
+            self.echoes
+        }
+    }
+
+    const TEXT: &str = "Lorem ipsum dolor sit amet, consectetur adipiscing elit, sed do \
+        eiusmod tempor incididunt ut labore et dolore magna aliqua.\nUt enim ad minim veniam, \
+        quis nostrud exercitation ullamco laboris nisi ut aliquip ex ea commodo consequat.";
+
+    #[test]
+    fn waits_for_the_host() {
+        let flow = FlowControl {
+            sync_every: 32,
+            ..Default::default()
+        };
+        let chunks = TEXT.len().div_ceil(32);
+
+        // The host echoes after each chunk
+        let typed = SlowHost::new(100, true).typed(TEXT, flow);
+        assert_eq!(typed, (TEXT.into(), true, chunks, 0));
+
+        // The host doesn't echo: the margin is waited after each chunk instead
+        let typed = SlowHost::new(100, false).typed(TEXT, flow);
+        assert_eq!(typed, (TEXT.into(), false, 1, chunks));
+
+        // Without flow control, keys are dropped
+        let flow = FlowControl {
+            sync_every: 0,
+            ..Default::default()
+        };
+        let (text, _, _, _) = SlowHost::new(100, true).typed(TEXT, flow);
+        assert!(text.len() < TEXT.len());
+    }
+
+    #[test]
+    fn waits_across_pieces() {
+        let flow = FlowControl {
+            sync_every: 32,
+            ..Default::default()
+        };
+        let mut host = SlowHost::new(100, true);
+        embassy_futures::block_on(async {
+            let mut bulk = Bulk::new(flow);
+            for piece in TEXT.as_bytes().chunks(10) {
+                let piece = core::str::from_utf8(piece).unwrap();
+                bulk.type_str(&mut host, piece).await;
+            }
+            bulk.finish(&mut host).await;
+            assert!(bulk.echoed());
+        });
+        // As often as when typed at once
+        assert_eq!(host.syncs, TEXT.len().div_ceil(32));
+        host.catch_up();
+        assert_eq!(host.editor.text(), TEXT);
+    }
+
+    #[test]
+    fn splits_on_chars() {
+        let flow = FlowControl {
+            sync_every: 1,
+            ..Default::default()
+        };
+        let text = "ça va";
+        let (_, echoed, syncs, _) = SlowHost::new(100, true).typed(text, flow);
+        assert!(echoed);
+        assert_eq!(syncs, text.chars().count());
+    }
+}
@@ -0,0 +1,170 @@
+//! N-key rollover keyboard reports
+//!
+//! The boot report ([`KeyboardReport`]) holds at most 6 keys, in an array. The NKRO report
+//! has a bit for each key instead, so that any number of keys can be held at once.
+//!
+//! For hosts that ignore the report descriptor and read boot reports (the boot protocol,
+//! e.g. BIOS setups), the NKRO report starts with the boot report: the modifiers, a
+//! reserved byte and the first 6 keys. [`DESCRIPTOR`] declares those 6 keys as padding,
+//! so hosts reading the bitmap don't see them twice.
+//!
+//! NOTE: embassy-usb's HID class neither advertises the boot subclass nor supports
+//! switching protocols, so a host that insists on the boot protocol may still not
+//! recognize the keyboard. [`super::ReportFormat::Boot`] is the safe choice there.
+
+use usbd_hid::descriptor::KeyboardReport;
+
+/// The highest keycode of the bitmap
+pub const MAX_KEYCODE: u8 = 0x7F;
+
+/// Size of the bitmap, in bytes
+const BITMAP_SIZE: usize = (MAX_KEYCODE as usize + 1) / 8;
+
+/// Size of a report, in bytes: the boot report, then the bitmap
+pub const REPORT_SIZE: usize = 8 + BITMAP_SIZE;
+
+/// Report descriptor: the modifiers, the boot report's keys (as padding), the LEDs output
+/// report and the bitmap of keycodes 0 to [`MAX_KEYCODE`] (see the HID spec 6.2.2).
+#[rustfmt::skip]
+pub const DESCRIPTOR: &[u8] = &[
+    0x05, 0x01,       // Usage Page (Generic Desktop)
+    0x09, 0x06,       // Usage (Keyboard)
+    0xA1, 0x01,       // Collection (Application)
+    0x05, 0x07,       //   Usage Page (Keyboard)
+    0x19, 0xE0,       //   Usage Minimum (Left Control)
+    0x29, 0xE7,       //   Usage Maximum (Right GUI)
+    0x15, 0x00,       //   Logical Minimum (0)
+    0x25, 0x01,       //   Logical Maximum (1)
+    0x75, 0x01,       //   Report Size (1)
+    0x95, 0x08,       //   Report Count (8)
+    0x81, 0x02,       //   Input (Data, Variable, Absolute): the modifiers
+    0x75, 0x08,       //   Report Size (8)
+    0x95, 0x07,       //   Report Count (7)
+    0x81, 0x01,       //   Input (Constant): reserved byte and boot keys
+    0x05, 0x08,       //   Usage Page (LEDs)
+    0x19, 0x01,       //   Usage Minimum (Num Lock)
+    0x29, 0x05,       //   Usage Maximum (Kana)
+    0x75, 0x01,       //   Report Size (1)
+    0x95, 0x05,       //   Report Count (5)
+    0x91, 0x02,       //   Output (Data, Variable, Absolute): the LEDs
+    0x75, 0x03,       //   Report Size (3)
+    0x95, 0x01,       //   Report Count (1)
+    0x91, 0x01,       //   Output (Constant): padding
+    0x05, 0x07,       //   Usage Page (Keyboard)
+    0x19, 0x00,       //   Usage Minimum (0)
+    0x29, 0x7F,       //   Usage Maximum (0x7F)
+    0x75, 0x01,       //   Report Size (1)
+    0x95, 0x80,       //   Report Count (128)
+    0x81, 0x02,       //   Input (Data, Variable, Absolute): the keys
+    0xC0,             // End Collection
+];
+
+/// The modifiers and keys held down
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct NkroReport {
+    pub modifier: u8,
+    /// One bit per keycode, from the least significant bit of the first byte
+    pub keys: [u8; BITMAP_SIZE],
+}
+
+impl NkroReport {
+    /// Whether the key is held down
+    pub fn contains(&self, keycode: u8) -> bool {
+        keycode <= MAX_KEYCODE && self.keys[keycode as usize / 8] & (1 << (keycode % 8)) != 0
+    }
+
+    /// Hold the key down (keycodes above [`MAX_KEYCODE`] are ignored)
+    pub fn press(&mut self, keycode: u8) {
+        if keycode <= MAX_KEYCODE {
+            self.keys[keycode as usize / 8] |= 1 << (keycode % 8);
+        }
+    }
+
+    /// The keys held down, by increasing keycode
+    pub fn keycodes(&self) -> impl Iterator<Item = u8> + '_ {
+        (1..=MAX_KEYCODE).filter(|keycode| self.contains(*keycode))
+    }
+
+    pub fn to_bytes(&self) -> [u8; REPORT_SIZE] {
+        let mut bytes = [0; REPORT_SIZE];
+        bytes[0] = self.modifier;
+        for (slot, keycode) in bytes[2..8].iter_mut().zip(self.keycodes()) {
+            *slot = keycode;
+        }
+        bytes[8..].copy_from_slice(&self.keys);
+        bytes
+    }
+}
+
+impl From<&KeyboardReport> for NkroReport {
+    fn from(report: &KeyboardReport) -> NkroReport {
+        let mut nkro = NkroReport {
+            modifier: report.modifier,
+            ..Default::default()
+        };
+        for keycode in report.keycodes {
+            if keycode != 0 {
+                nkro.press(keycode);
+            }
+        }
+        nkro
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+    use crate::host::descriptor_items;
+
+    #[test]
+    fn descriptor_matches_reports() {
+        // Add up the input items' bits, with the current report size and count
+        let (mut size, mut count, mut bits) = (0, 0, 0);
+        for (prefix, data) in descriptor_items(DESCRIPTOR) {
+            match prefix {
+                0x74 => size = data,
+                0x94 => count = data,
+                0x80 => bits += size * count,
+                _ => {}
+            }
+        }
+        assert_eq!(bits as usize, REPORT_SIZE * 8);
+
+        // The bitmap covers the keycodes, one bit each
+        let items = descriptor_items(DESCRIPTOR);
+        let usages = items.iter().rev().find(|(prefix, _)| *prefix == 0x28);
+        assert_eq!(usages, Some(&(0x28, MAX_KEYCODE as u32)));
+        assert_eq!(BITMAP_SIZE * 8, MAX_KEYCODE as usize + 1);
+    }
+
+    #[test]
+    fn serializes_reports() {
+        let report = KeyboardReport {
+            modifier: 0x02,
+            reserved: 0,
+            leds: 0,
+            keycodes: [23, 11, 8, 0, 0, 0],
+        };
+        let nkro = NkroReport::from(&report);
+        assert!(nkro.contains(8) && nkro.contains(11) && nkro.contains(23));
+        assert!(!nkro.contains(0) && !nkro.contains(9) && !nkro.contains(0xE0));
+        assert_eq!(nkro.keycodes().collect::<Vec<_>>(), [8, 11, 23]);
+
+        let bytes = nkro.to_bytes();
+        // The boot report, with the keys by keycode
+        assert_eq!(bytes[..8], [0x02, 0, 8, 11, 23, 0, 0, 0]);
+        // Keycode 8 is bit 0 of byte 1, 11 bit 3, 23 bit 7 of byte 2
+        assert_eq!(bytes[8..11], [0, 0x09, 0x80]);
+        assert!(bytes[11..].iter().all(|byte| *byte == 0));
+
+        // Only the first 6 keys fit the boot report
+        let mut nkro = NkroReport::default();
+        for keycode in 4..14 {
+            nkro.press(keycode);
+        }
+        nkro.press(MAX_KEYCODE + 1);
+        assert_eq!(nkro.keycodes().count(), 10);
+        assert_eq!(nkro.to_bytes()[2..8], [4, 5, 6, 7, 8, 9]);
+    }
+}
@@ -7,14 +7,23 @@
 // helpers).
 //
 // See RP2040 datasheet Section 4.5.2.1 (Pulse Width Modulation)
+//
+// NOTE: only the animation descriptions are available on the host, everything
+// PWM-related is only built for the device.
 
+#[cfg(target_os = "none")]
 use embassy_rp::pwm;
+#[cfg(target_os = "none")]
 use embassy_rp::pwm::SetDutyCycle;
+#[cfg(target_os = "none")]
 use embassy_rp::Peri;
 
+#[cfg(target_os = "none")]
 use embassy_rp::peripherals::{PIN_18, PIN_19, PIN_20, PWM_SLICE1, PWM_SLICE2};
 
-use embassy_time::{Duration, Instant, Ticker};
+use embassy_time::Duration;
+#[cfg(target_os = "none")]
+use embassy_time::{Instant, Ticker};
 
 use embassy_sync::blocking_mutex::raw::NoopRawMutex;
 
@@ -22,6 +31,7 @@ use embassy_sync::blocking_mutex::raw::NoopRawMutex;
 pub type Signal = embassy_sync::signal::Signal<NoopRawMutex, Animation>;
 
 /// Description of a sine animation
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub struct Animation {
     pub color: (f64, f64, f64),
     pub bounds: (f64, f64),
@@ -32,14 +42,17 @@ pub struct Animation {
 }
 
 // clock divider: 133Mhz / 256 ~= 500kHz
+#[cfg(target_os = "none")]
 const PWM_DIV: u8 = u8::MAX;
 
 // Period in ticks: 512 ticks -> 500kHz / 512 ~= 1kHz (i.e. 1PWM cycle ~= 1ms)
+#[cfg(target_os = "none")]
 const PWM_TOP: u16 = 512;
 
 // PWM slices for each pin, refer to RGB led pins here:
 //  https://shop.pimoroni.com/products/tiny-2040
 // and RP2040 datasheet PWM slices (4.5.2 Programmer's Model)
+#[cfg(target_os = "none")]
 pub struct LEDSlices {
     // GPIO 18 -> PWM 1A
     red: pwm::PwmOutput<'static>,
@@ -49,6 +62,7 @@ pub struct LEDSlices {
     blue: pwm::PwmOutput<'static>,
 }
 
+#[cfg(target_os = "none")]
 impl LEDSlices {
     // NOTE: duty "on/off" is inverted because LEDs are active low
     // NOTE: we 'unwrap' because the error is actually Infallible
@@ -61,12 +75,14 @@ impl LEDSlices {
     }
 }
 
+#[cfg(target_os = "none")]
 type LEDPins = (
     Peri<'static, PIN_18>,
     Peri<'static, PIN_19>,
     Peri<'static, PIN_20>,
 );
 
+#[cfg(target_os = "none")]
 pub fn init_pwm(
     slices: (Peri<'static, PWM_SLICE1>, Peri<'static, PWM_SLICE2>),
     led_pins: LEDPins,
@@ -96,6 +112,7 @@ pub fn init_pwm(
 /// Animate the LEDs forever, updating the PWM slices every 50ms
 /// This uses a pulse-y function that adds more personality to the ghost
 /// than eg a simple sine wave.
+#[cfg(target_os = "none")]
 pub async fn animate_leds(signal: &Signal, mut led_slices: LEDSlices) {
     // Update the LEDs every 50ms
     let mut ticker = Ticker::every(Duration::from_millis(50));
@@ -1,6 +1,20 @@
 //! Main ghostwriter library for async & input handling
 
-#![no_std]
+#![cfg_attr(not(any(test, feature = "std")), no_std)]
 
+pub mod button;
+pub mod clicker;
+pub mod config;
+pub mod console;
+pub mod consumer;
+pub mod cyoa;
+#[cfg(any(test, feature = "std"))]
+pub mod host;
 pub mod keyboard;
 pub mod leds;
+pub mod mouse;
+pub mod proxy;
+pub mod random;
+pub mod ring;
+pub mod save;
+pub mod system;
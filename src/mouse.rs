@@ -0,0 +1,266 @@
+//! USB Mouse (HID class) helpers, and a jiggler keeping the host awake
+//!
+//! The mouse is sent usbd-hid's [`MouseReport`] (buttons, relative movement, wheel and
+//! pan) through its own HID interface, like the [consumer controls](crate::consumer).
+//!
+//! The [`Jiggler`] moves the pointer by a pixel at (slightly) random intervals, in random
+//! directions but never more than a few pixels away from where it started: enough for the
+//! host not to go idle, too little to notice. Like the button's state machines it is pure
+//! and driven by timestamps.
+
+use embassy_time::{Duration, Instant, Timer};
+use embassy_usb::class::hid;
+use embassy_usb::driver::Driver;
+use usbd_hid::descriptor::MouseReport;
+
+use crate::random::Rng;
+
+/// Size of a report, in bytes
+pub const REPORT_SIZE: usize = 5;
+
+pub type HidWriter<'a, D> = hid::HidWriter<'a, D, REPORT_SIZE>;
+
+/// Button bits of a [`MouseReport`]
+pub mod buttons {
+    pub const LEFT: u8 = 0x01;
+    pub const RIGHT: u8 = 0x02;
+    pub const MIDDLE: u8 = 0x04;
+}
+
+/// What the mouse does, for the button mappings
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Action {
+    /// Press and release the buttons
+    Click(u8),
+    /// Scroll up (positive) or down (negative) by that many units
+    Scroll(i8),
+}
+
+pub fn report(buttons: u8, x: i8, y: i8, wheel: i8) -> MouseReport {
+    MouseReport {
+        buttons,
+        x,
+        y,
+        wheel,
+        pan: 0,
+    }
+}
+
+/// Anything mouse reports can be sent to, like [`crate::keyboard::Keyboard`]
+#[allow(async_fn_in_trait)]
+pub trait Mouse {
+    async fn send(&mut self, report: &MouseReport);
+
+    async fn move_by(&mut self, x: i8, y: i8) {
+        self.send(&report(0, x, y, 0)).await;
+    }
+
+    async fn perform(&mut self, action: Action) {
+        match action {
+            Action::Click(buttons) => {
+                self.send(&report(buttons, 0, 0, 0)).await;
+                self.send(&report(0, 0, 0, 0)).await;
+            }
+            Action::Scroll(amount) => self.send(&report(0, 0, 0, amount)).await,
+        }
+    }
+}
+
+/// Mouse writing to the USB HID device, waiting 'delay' after each report
+pub struct HidMouse<'w, 'd, D: Driver<'d>> {
+    pub writer: &'w mut HidWriter<'d, D>,
+    pub delay: Duration,
+}
+
+impl<'d, D: Driver<'d>> Mouse for HidMouse<'_, 'd, D> {
+    async fn send(&mut self, report: &MouseReport) {
+        let _ = self.writer.write_serialize(report).await;
+        Timer::after(self.delay).await;
+    }
+}
+
+/// How the [`Jiggler`] moves
+#[derive(Clone, Copy, Debug)]
+pub struct JiggleConfig {
+    /// Average time between moves
+    pub interval: Duration,
+    /// Moves are up to that much earlier or later than the interval
+    pub jitter: Duration,
+    /// How far the pointer may get from where it started, in pixels (at least 1)
+    pub radius: u8,
+}
+
+impl Default for JiggleConfig {
+    fn default() -> Self {
+        JiggleConfig {
+            interval: Duration::from_secs(30),
+            jitter: Duration::from_secs(10),
+            radius: 2,
+        }
+    }
+}
+
+/// Pointer motion generator, see the [module docs](self)
+#[derive(Clone, Debug)]
+pub struct Jiggler {
+    config: JiggleConfig,
+    rng: Rng,
+    /// Where the pointer is, relative to where it started
+    offset: (i32, i32),
+    next: Instant,
+}
+
+impl Jiggler {
+    /// A jiggler starting at 'now', with the first move after an interval
+    pub fn new(config: JiggleConfig, seed: u64, now: Instant) -> Jiggler {
+        let mut jiggler = Jiggler {
+            config,
+            rng: Rng::new(seed),
+            offset: (0, 0),
+            next: now,
+        };
+        jiggler.next = now + jiggler.interval();
+        jiggler
+    }
+
+    /// Where the pointer is, relative to where it started
+    pub fn offset(&self) -> (i32, i32) {
+        self.offset
+    }
+
+    /// When the next move is due
+    pub fn deadline(&self) -> Instant {
+        self.next
+    }
+
+    /// The move due at 'now', if any (then the next one is scheduled)
+    pub fn poll(&mut self, now: Instant) -> Option<(i8, i8)> {
+        if now < self.next {
+            return None;
+        }
+        self.next = now + self.interval();
+        Some(self.step())
+    }
+
+    /// A random interval, within the jitter of the configured one
+    fn interval(&mut self) -> Duration {
+        let jitter = self
+            .config
+            .jitter
+            .as_ticks()
+            .min(self.config.interval.as_ticks());
+        let earliest = self.config.interval.as_ticks() - jitter;
+        let extra = self.rng.below((2 * jitter + 1) as usize) as u64;
+        Duration::from_ticks(earliest + extra)
+    }
+
+    /// A one pixel move in a random direction, back towards the start on the axes where it
+    /// would otherwise go past the radius
+    fn step(&mut self) -> (i8, i8) {
+        let radius = self.config.radius.max(1) as i32;
+        let (dx, dy) = loop {
+            let dx = self.rng.below(3) as i32 - 1;
+            let dy = self.rng.below(3) as i32 - 1;
+            if (dx, dy) != (0, 0) {
+                break (dx, dy);
+            }
+        };
+
+        let bounce = |pos: i32, d: i32| if (pos + d).abs() > radius { -d } else { d };
+        let (dx, dy) = (bounce(self.offset.0, dx), bounce(self.offset.1, dy));
+        self.offset = (self.offset.0 + dx, self.offset.1 + dy);
+        (dx as i8, dy as i8)
+    }
+}
+
+/// Move the mouse with the jiggler, forever (e.g. until cancelled in a select)
+pub async fn jiggle(mouse: &mut impl Mouse, jiggler: &mut Jiggler) -> ! {
+    loop {
+        Timer::at(jiggler.deadline()).await;
+        if let Some((x, y)) = jiggler.poll(Instant::now()) {
+            mouse.move_by(x, y).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    /// The moves of the jiggler until 'end' (in s), with the time (in ms) they were made at
+    fn moves(config: JiggleConfig, seed: u64, end: u64) -> Vec<(u64, (i8, i8))> {
+        let mut jiggler = Jiggler::new(config, seed, Instant::from_millis(0));
+        let mut out = vec![];
+        while jiggler.deadline() <= Instant::from_secs(end) {
+            let now = jiggler.deadline();
+            assert_eq!(jiggler.poll(now - Duration::from_millis(1)), None);
+            out.push((now.as_millis(), jiggler.poll(now).unwrap()));
+        }
+        out
+    }
+
+    #[test]
+    fn jiggles_at_random_intervals() {
+        let config = JiggleConfig::default();
+        let moves = moves(config, 42, 24 * 3600);
+
+        let mut last = 0;
+        for (at, _) in &moves {
+            let interval = at - last;
+            assert!((20_000..=40_000).contains(&interval), "{interval}");
+            last = *at;
+        }
+
+        // Not always the same interval
+        let first = moves[1].0 - moves[0].0;
+        assert!(moves.windows(2).any(|w| w[1].0 - w[0].0 != first));
+
+        // Reproducible with the seed
+        assert_eq!(moves, self::moves(config, 42, 24 * 3600));
+        assert_ne!(moves, self::moves(config, 43, 24 * 3600));
+    }
+
+    #[test]
+    fn stays_around_the_start() {
+        for radius in [0, 1, 2, 5] {
+            let config = JiggleConfig {
+                interval: Duration::from_secs(1),
+                jitter: Duration::from_millis(0),
+                radius,
+            };
+            let mut offset = (0, 0);
+            let mut farthest = 0;
+            for (_, (x, y)) in moves(config, 0, 10_000) {
+                // Every move is a (possibly diagonal) pixel
+                assert!((x, y) != (0, 0) && x.abs() <= 1 && y.abs() <= 1);
+                offset = (offset.0 + x as i32, offset.1 + y as i32);
+                farthest = farthest.max(offset.0.abs()).max(offset.1.abs());
+            }
+            assert_eq!(farthest, radius.max(1) as i32, "{radius}");
+        }
+    }
+
+    /// Records the reports sent, as (buttons, x, y, wheel)
+    #[derive(Default)]
+    struct Recorder(Vec<(u8, i8, i8, i8)>);
+
+    impl Mouse for Recorder {
+        async fn send(&mut self, report: &MouseReport) {
+            self.0
+                .push((report.buttons, report.x, report.y, report.wheel));
+        }
+    }
+
+    #[test]
+    fn performs_actions() {
+        let mut mouse = Recorder::default();
+        embassy_futures::block_on(mouse.perform(Action::Click(buttons::LEFT)));
+        embassy_futures::block_on(mouse.perform(Action::Scroll(-3)));
+        embassy_futures::block_on(mouse.move_by(1, -1));
+        assert_eq!(
+            mouse.0,
+            [(1, 0, 0, 0), (0, 0, 0, 0), (0, 0, 0, -3), (0, 1, -1, 0)]
+        );
+    }
+}
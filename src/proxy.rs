@@ -0,0 +1,420 @@
+//! Typing proxy: the host streams text over the serial port, and the device types it
+//!
+//! This gets text into machines where pasting is blocked (KVMs, VM consoles, BIOS
+//! screens). After the console's `send` command, everything the host sends is text to
+//! type, up to an [`EOT`] (Ctrl-D):
+//!
+//! ```text
+//! (printf 'send\n'; cat snippet.txt; printf '\004') > /dev/ttyACM0
+//! ```
+//!
+//! The text is buffered in a [`RingBuffer`] while it is typed. The device only reads from
+//! the serial port when the buffer has room for a whole packet, so the host's writes block
+//! (USB flow control) rather than overflow it. Every [`PROGRESS_EVERY`] bytes typed, the
+//! device writes a `typed: <bytes>` line, and at the end the total and `ok`, or `error:
+//! cancelled` if the button was pressed in the meantime. The rest of the text is then
+//! skipped, until the host stops sending for [`DRAIN_IDLE`] (e.g. when the sender was
+//! killed, which sends no EOT): what it sends afterwards is read as commands again.
+//!
+//! The text is typed in bulk ([`crate::keyboard::bulk`]), letting the host catch up as
+//! often as the flow control says. Like [`crate::keyboard::Keyboard::type_str`], only ASCII
+//! is typed: other chars are typed as `?`.
+
+use core::cell::RefCell;
+use core::fmt::Write;
+use core::future::Future;
+
+use embassy_futures::join::join;
+use embassy_futures::select::{select, Either};
+use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+use embassy_sync::signal::Signal;
+use embassy_time::Duration;
+
+use crate::console::{self, LineReader, Reply, Transport, PACKET_SIZE};
+use crate::cyoa::Clock;
+use crate::keyboard::bulk::{Bulk, FlowControl};
+use crate::keyboard::{Keyboard, ALL_KEYS_UP};
+use crate::ring::RingBuffer;
+
+/// Ends the text (Ctrl-D)
+pub const EOT: u8 = 0x04;
+
+/// How long the host may pause while the rest of a cancelled text is skipped
+pub const DRAIN_IDLE: Duration = Duration::from_secs(1);
+
+/// Size of the buffer, in bytes
+pub const BUFFER_SIZE: usize = 1024;
+
+/// How often progress is reported, in bytes typed
+pub const PROGRESS_EVERY: usize = 256;
+
+/// How many bytes are typed at once
+const CHUNK_SIZE: usize = 32;
+
+/// How the text was typed
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Sent {
+    /// Bytes typed
+    pub typed: usize,
+    pub cancelled: bool,
+}
+
+/// What the reading and typing share
+struct Shared {
+    buffer: RingBuffer<BUFFER_SIZE>,
+    /// Everything has been read (or the port was closed)
+    ended: bool,
+    /// Bytes typed so far
+    typed: usize,
+    /// Everything has been typed
+    done: bool,
+}
+
+/// Type the text read from the serial port up to an [`EOT`], until 'cancel' completes
+/// (the rest of the text is then skipped), reporting progress every 'progress_every'
+/// bytes typed. What follows the text is read as lines again.
+pub async fn type_stream<const L: usize>(
+    transport: &mut impl Transport,
+    lines: &mut LineReader<L>,
+    keyboard: &mut impl Keyboard,
+    flow: &FlowControl,
+    clock: &mut impl Clock,
+    cancel: impl Future,
+    progress_every: usize,
+) -> Sent {
+    let shared = RefCell::new(Shared {
+        buffer: RingBuffer::new(),
+        ended: false,
+        typed: 0,
+        done: false,
+    });
+    // Something was read, something was typed
+    let read = Signal::<NoopRawMutex, ()>::new();
+    let typed = Signal::<NoopRawMutex, ()>::new();
+
+    let receive = async {
+        let mut reported = 0;
+        loop {
+            let (free, ended, done, total) = {
+                let shared = shared.borrow();
+                (
+                    shared.buffer.free(),
+                    shared.ended,
+                    shared.done,
+                    shared.typed,
+                )
+            };
+            if done {
+                return;
+            }
+            // The total is reported at the end anyway
+            let finishing = ended && free == BUFFER_SIZE;
+            if !finishing && total >= reported + progress_every.max(1) {
+                reported = total;
+                let mut reply = Reply::new();
+                let _ = write!(reply, "typed: {total}\r\n");
+                transport.write(reply.as_bytes()).await;
+            }
+            if ended || free < PACKET_SIZE {
+                typed.wait().await;
+                continue;
+            }
+
+            // Both are cancel safe
+            match select(lines.read_until(transport, free, EOT), typed.wait()).await {
+                Either::First(Some((bytes, end))) => {
+                    let mut shared = shared.borrow_mut();
+                    shared.buffer.push(bytes);
+                    shared.ended = end;
+                    read.signal(());
+                }
+                Either::First(None) => {
+                    shared.borrow_mut().ended = true;
+                    read.signal(());
+                }
+                Either::Second(()) => {}
+            }
+        }
+    };
+
+    let type_text = async {
+        let mut bulk = Bulk::new(*flow);
+        let mut chunk = [0; CHUNK_SIZE];
+        loop {
+            let (len, ended) = {
+                let mut shared = shared.borrow_mut();
+                (shared.buffer.pop(&mut chunk), shared.ended)
+            };
+            if len == 0 && ended {
+                break;
+            }
+            if len == 0 {
+                read.wait().await;
+                continue;
+            }
+            typed.signal(());
+
+            bulk.type_str(keyboard, ascii(&mut chunk[..len])).await;
+            shared.borrow_mut().typed += len;
+            typed.signal(());
+        }
+        bulk.finish(keyboard).await;
+        shared.borrow_mut().done = true;
+        typed.signal(());
+    };
+
+    let cancelled = matches!(
+        select(join(receive, type_text), cancel).await,
+        Either::Second(_)
+    );
+    let Shared {
+        typed, mut ended, ..
+    } = shared.into_inner();
+
+    if cancelled {
+        // The typing may have stopped mid-key
+        keyboard.send(&ALL_KEYS_UP).await;
+        while !ended {
+            let rest = lines.read_until(transport, PACKET_SIZE, EOT);
+            ended = !matches!(
+                select(rest, clock.sleep(DRAIN_IDLE)).await,
+                Either::First(Some((_, false)))
+            );
+        }
+    }
+
+    let mut reply = Reply::new();
+    let _ = write!(reply, "typed: {typed}\r\n");
+    let _ = match cancelled {
+        false => write!(reply, "ok\r\n"),
+        true => write!(reply, "error: {}\r\n", console::Error::Cancelled.message()),
+    };
+    transport.write(reply.as_bytes()).await;
+
+    Sent { typed, cancelled }
+}
+
+/// The bytes as ASCII text: the first byte of other (UTF-8) chars becomes a '?', and the
+/// rest are dropped. Works on chars split across chunks too.
+fn ascii(bytes: &mut [u8]) -> &str {
+    let mut len = 0;
+    for i in 0..bytes.len() {
+        let byte = match bytes[i] {
+            // Continuation bytes
+            0x80..=0xBF => continue,
+            byte if byte.is_ascii() => byte,
+            _ => b'?',
+        };
+        bytes[len] = byte;
+        len += 1;
+    }
+    // Only ASCII is left
+    core::str::from_utf8(&bytes[..len]).unwrap_or("")
+}
+
+#[cfg(test)]
+mod test {
+
+    use core::future::pending;
+
+    use usbd_hid::descriptor::KeyboardReport;
+
+    use super::*;
+    use crate::host::{Editor, MemoryTransport};
+
+    #[test]
+    fn types_ascii() {
+        assert_eq!(ascii(&mut b"Hello!".to_vec()), "Hello!");
+        assert_eq!(ascii(&mut "Ça va ?".as_bytes().to_vec()), "?a va ?");
+        // A char split across chunks
+        let bytes = "é".as_bytes();
+        assert_eq!(ascii(&mut bytes[..1].to_vec()), "?");
+        assert_eq!(ascii(&mut bytes[1..].to_vec()), "");
+    }
+
+    /// The host always seems to have stopped sending, once there is nothing to read
+    struct NoWait;
+
+    impl Clock for NoWait {
+        async fn sleep(&mut self, _duration: Duration) {}
+    }
+
+    /// Send the packets after a 'send' command, typing into an editor
+    fn send(packets: &[&[u8]], cancel: impl Future) -> (Sent, String, String, Option<String>) {
+        let mut transport = MemoryTransport::new(packets);
+        let mut lines = LineReader::<64>::new();
+        let mut editor = Editor::new();
+        embassy_futures::block_on(async {
+            let sent = type_stream(
+                &mut transport,
+                &mut lines,
+                &mut editor,
+                &FlowControl::default(),
+                &mut NoWait,
+                cancel,
+                16,
+            )
+            .await;
+            let next = lines.next_line(&mut transport).await;
+            let next = next.map(|line| line.unwrap().to_string());
+            (sent, editor.text(), transport.output(), next)
+        })
+    }
+
+    #[test]
+    fn types_the_text() {
+        let (sent, text, output, next) = send(
+            &[b"Hello, ", b"World!\nHow are", b" you?\x04status\n"],
+            pending::<()>(),
+        );
+        assert_eq!(
+            sent,
+            Sent {
+                typed: 26,
+                cancelled: false
+            }
+        );
+        assert_eq!(text, "Hello, World!\nHow are you?");
+        assert_eq!(output, "typed: 26\r\nok\r\n");
+        // What follows the text is a command again
+        assert_eq!(next.as_deref(), Some("status"));
+    }
+
+    #[test]
+    fn types_long_texts() {
+        // Longer than the buffer, and than the packets
+        let text: String = (0..3 * BUFFER_SIZE)
+            .map(|i| (b'a' + (i % 26) as u8) as char)
+            .collect();
+        let mut packet = text.clone().into_bytes();
+        packet.push(EOT);
+        let (sent, typed, output, next) = send(&[&packet], pending::<()>());
+        assert_eq!(sent.typed, text.len());
+        assert_eq!(typed, text);
+        assert!(output.ends_with(&format!("typed: {}\r\nok\r\n", text.len())));
+        // With progress along the way (at least whenever the buffer was refilled)
+        let progress: Vec<usize> = output
+            .lines()
+            .filter_map(|line| line.strip_prefix("typed: "))
+            .map(|typed| typed.parse().unwrap())
+            .collect();
+        assert!(progress.len() >= 3, "{progress:?}");
+        assert!(progress.windows(2).all(|w| w[0] <= w[1]), "{progress:?}");
+        assert_eq!(next, None);
+    }
+
+    #[test]
+    fn skips_the_end_of_the_send_line() {
+        // The LF of a CRLF is not typed, even in the next packet
+        for packets in [&[&b"send\r\nHi\n\x04"[..]][..], &[b"send\r", b"\nHi\n\x04"]] {
+            let mut transport = MemoryTransport::new(packets);
+            let mut lines = LineReader::<64>::new();
+            let mut editor = Editor::new();
+            embassy_futures::block_on(async {
+                assert_eq!(lines.next_line(&mut transport).await, Some(Ok("send")));
+                let flow = FlowControl::default();
+                type_stream(
+                    &mut transport,
+                    &mut lines,
+                    &mut editor,
+                    &flow,
+                    &mut NoWait,
+                    pending::<()>(),
+                    16,
+                )
+                .await;
+            });
+            assert_eq!(editor.text(), "Hi\n");
+        }
+    }
+
+    /// An editor typing one report at a time, signaling once it typed 'after' reports
+    struct Slow<'a> {
+        editor: Editor,
+        reports: usize,
+        after: usize,
+        cancel: &'a Signal<NoopRawMutex, ()>,
+    }
+
+    impl Keyboard for Slow<'_> {
+        async fn send(&mut self, report: &KeyboardReport) {
+            self.editor.send(report).await;
+            self.reports += 1;
+            if self.reports == self.after {
+                self.cancel.signal(());
+            }
+            embassy_futures::yield_now().await;
+        }
+    }
+
+    #[test]
+    fn cancels() {
+        let cancel = Signal::<NoopRawMutex, ()>::new();
+        let mut transport = MemoryTransport::new(&[
+            b"a quick brown fox jumps over the lazy dog, ",
+            b"and again and again\x04ver",
+            b"sion\n",
+        ]);
+        let mut lines = LineReader::<64>::new();
+        let mut keyboard = Slow {
+            editor: Editor::new(),
+            reports: 0,
+            after: 20,
+            cancel: &cancel,
+        };
+
+        embassy_futures::block_on(async {
+            let sent = type_stream(
+                &mut transport,
+                &mut lines,
+                &mut keyboard,
+                &FlowControl::default(),
+                &mut NoWait,
+                // Checked first, so that it cancels as soon as signaled
+                cancel.wait(),
+                16,
+            )
+            .await;
+            assert!(sent.cancelled);
+            // Only the chunk being typed was, partly
+            let typed = keyboard.editor.text();
+            assert!(!typed.is_empty() && typed.len() < 32, "{typed}");
+            // Keys are released
+            assert_eq!(keyboard.editor.held(), [0; 6]);
+
+            // The rest of the text is skipped
+            let next = lines.next_line(&mut transport).await;
+            assert_eq!(next, Some(Ok("version")));
+        });
+        assert!(transport.output().ends_with("error: cancelled\r\n"));
+    }
+
+    #[test]
+    fn stops_skipping_when_the_host_stops() {
+        // The sender is killed before the end of the text: no EOT
+        let cancel = Signal::<NoopRawMutex, ()>::new();
+        let mut transport = MemoryTransport::open(&[
+            b"a quick brown fox jumps over the lazy dog, ",
+            b"and again and again",
+        ]);
+        let mut lines = LineReader::<64>::new();
+        let mut keyboard = Slow {
+            editor: Editor::new(),
+            reports: 0,
+            after: 20,
+            cancel: &cancel,
+        };
+
+        let sent = embassy_futures::block_on(type_stream(
+            &mut transport,
+            &mut lines,
+            &mut keyboard,
+            &FlowControl::default(),
+            &mut NoWait,
+            cancel.wait(),
+            16,
+        ));
+        assert!(sent.cancelled);
+        assert!(transport.output().ends_with("error: cancelled\r\n"));
+    }
+}
@@ -0,0 +1,70 @@
+//! Seedable randomness for stories
+//!
+//! Stories pick random text and links (see [`twine::macros`]) from a small PRNG rather than
+//! a hardware source, so that a run can be reproduced from its seed: the device seeds it
+//! from the ring oscillator, and the host player takes the seed on the command line.
+
+/// xorshift64* generator: tiny and plenty good enough to pick story options
+#[derive(Clone, Debug)]
+pub struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    /// The same seed always gives the same sequence
+    pub fn new(seed: u64) -> Rng {
+        // Scramble the seed (SplitMix64) so that close seeds don't start alike, and so
+        // that the state is never 0 (which xorshift never leaves)
+        let mut z = seed.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^= z >> 31;
+
+        Rng { state: z.max(1) }
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state ^= self.state >> 12;
+        self.state ^= self.state << 25;
+        self.state ^= self.state >> 27;
+        self.state.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+
+    /// A number in `0..n` (0 if `n` is 0)
+    pub fn below(&mut self, n: usize) -> usize {
+        if n == 0 {
+            return 0;
+        }
+        // NOTE: the modulo bias is negligible for the few options of a story
+        (self.next_u64() % n as u64) as usize
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    #[test]
+    fn is_reproducible() {
+        let mut a = Rng::new(42);
+        let mut b = Rng::new(42);
+        for _ in 0..100 {
+            assert_eq!(a.next_u64(), b.next_u64());
+        }
+
+        let mut c = Rng::new(43);
+        assert_ne!(Rng::new(42).next_u64(), c.next_u64());
+    }
+
+    #[test]
+    fn picks_every_option() {
+        let mut rng = Rng::new(0);
+        let mut seen = [0; 3];
+        for _ in 0..300 {
+            seen[rng.below(3)] += 1;
+        }
+        assert!(seen.iter().all(|n| *n > 50), "{seen:?}");
+        assert_eq!(rng.below(0), 0);
+    }
+}
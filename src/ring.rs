@@ -0,0 +1,86 @@
+//! A fixed-size FIFO of bytes
+
+/// Holds up to 'N' bytes, wrapping around its buffer
+pub struct RingBuffer<const N: usize> {
+    buf: [u8; N],
+    start: usize,
+    len: usize,
+}
+
+impl<const N: usize> RingBuffer<N> {
+    pub const fn new() -> Self {
+        RingBuffer {
+            buf: [0; N],
+            start: 0,
+            len: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// How many more bytes fit
+    pub fn free(&self) -> usize {
+        N - self.len
+    }
+
+    /// Append as many of the bytes as fit, returning how many
+    pub fn push(&mut self, bytes: &[u8]) -> usize {
+        let len = bytes.len().min(self.free());
+        for (i, byte) in bytes[..len].iter().enumerate() {
+            self.buf[(self.start + self.len + i) % N] = *byte;
+        }
+        self.len += len;
+        len
+    }
+
+    /// Take the oldest bytes, as many as fit 'out', returning how many
+    pub fn pop(&mut self, out: &mut [u8]) -> usize {
+        let len = out.len().min(self.len);
+        for (i, slot) in out[..len].iter_mut().enumerate() {
+            *slot = self.buf[(self.start + i) % N];
+        }
+        self.start = (self.start + len) % N;
+        self.len -= len;
+        len
+    }
+}
+
+impl<const N: usize> Default for RingBuffer<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    #[test]
+    fn wraps_around() {
+        let mut ring = RingBuffer::<8>::new();
+        let mut out = [0; 8];
+        assert!(ring.is_empty());
+        assert_eq!(ring.pop(&mut out), 0);
+
+        assert_eq!(ring.push(b"hello"), 5);
+        assert_eq!((ring.len(), ring.free()), (5, 3));
+        assert_eq!(ring.pop(&mut out[..3]), 3);
+        assert_eq!(&out[..3], b"hel");
+
+        // Only what fits is pushed, across the end of the buffer
+        assert_eq!(ring.push(b", world"), 6);
+        assert_eq!(ring.free(), 0);
+        assert_eq!(ring.push(b"!"), 0);
+
+        assert_eq!(ring.pop(&mut out), 8);
+        assert_eq!(&out, b"lo, worl");
+        assert!(ring.is_empty());
+    }
+}
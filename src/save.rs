@@ -0,0 +1,484 @@
+//! Saving cyoa progress, to resume the story after the device is unplugged
+//!
+//! A save is the current passage and the history (for going back), stored as pids with the
+//! option picked in each passage of the history. It is tied to the story's IFID so that a
+//! save from another story is never resumed. The layout is:
+//!
+//! ```text
+//! | "GWSV" | version | payload length | payload | fletcher-16 of the payload (LE) |
+//!
+//! payload: | IFID | passage pid | history length | history (oldest first) |
+//! history entry: | pid | picked option |
+//! ```
+//!
+//! where strings are prefixed with their length (one byte). Erased flash (all 0xFF) does not
+//! have the magic, so it reads as no save.
+//!
+//! When there are several stories, each has its own save slot, and the story played last
+//! is remembered in the same kind of record (with magic "GWLS" and the story's IFID as the
+//! payload).
+
+use crate::cyoa::{History, HISTORY_SIZE};
+
+/// Size of a save, in bytes
+pub const SAVE_SIZE: usize = 256;
+
+/// Bumped whenever the layout changes; saves with another version are ignored
+pub const VERSION: u8 = 2;
+
+const MAGIC: &[u8; 4] = b"GWSV";
+const LAST_STORY_MAGIC: &[u8; 4] = b"GWLS";
+
+/// Magic, version and payload length
+const HEADER_SIZE: usize = MAGIC.len() + 2;
+const CHECKSUM_SIZE: usize = 2;
+
+/// Where the story is at
+pub struct Save<'a> {
+    /// pid of the current passage
+    pub passage: &'a str,
+    pub history: History<'a, HISTORY_SIZE>,
+}
+
+/// Persistent storage for (exactly) one save
+#[allow(async_fn_in_trait)]
+pub trait Storage {
+    async fn read(&mut self, buf: &mut [u8; SAVE_SIZE]);
+    async fn write(&mut self, buf: &[u8; SAVE_SIZE]);
+}
+
+/// Storage that doesn't persist anything
+pub struct NoStorage;
+
+impl Storage for NoStorage {
+    async fn read(&mut self, buf: &mut [u8; SAVE_SIZE]) {
+        buf.fill(0xFF);
+    }
+
+    async fn write(&mut self, _buf: &[u8; SAVE_SIZE]) {}
+}
+
+/// Where a library of stories (see [`crate::cyoa::play_library`]) keeps a record
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Slot {
+    /// Which story was played last
+    LastStory,
+    /// The save of the n-th story
+    Save(usize),
+}
+
+/// Persistent storage for the records of a library of stories, one per slot
+#[allow(async_fn_in_trait)]
+pub trait Library {
+    async fn read(&mut self, slot: Slot, buf: &mut [u8; SAVE_SIZE]);
+    async fn write(&mut self, slot: Slot, buf: &[u8; SAVE_SIZE]);
+
+    /// The storage of one of the slots
+    fn slot(&mut self, slot: Slot) -> SlotStorage<'_, Self>
+    where
+        Self: Sized,
+    {
+        SlotStorage {
+            library: self,
+            slot,
+        }
+    }
+}
+
+/// One of the slots of a library
+pub struct SlotStorage<'l, L> {
+    library: &'l mut L,
+    slot: Slot,
+}
+
+impl<L: Library> Storage for SlotStorage<'_, L> {
+    async fn read(&mut self, buf: &mut [u8; SAVE_SIZE]) {
+        self.library.read(self.slot, buf).await;
+    }
+
+    async fn write(&mut self, buf: &[u8; SAVE_SIZE]) {
+        self.library.write(self.slot, buf).await;
+    }
+}
+
+/// A library with a storage per slot
+pub struct Storages<'a, L, S> {
+    pub last_story: &'a mut L,
+    /// One per story
+    pub saves: &'a mut [S],
+}
+
+impl<L: Storage, S: Storage> Library for Storages<'_, L, S> {
+    async fn read(&mut self, slot: Slot, buf: &mut [u8; SAVE_SIZE]) {
+        match slot {
+            Slot::LastStory => self.last_story.read(buf).await,
+            Slot::Save(ix) => self.saves[ix].read(buf).await,
+        }
+    }
+
+    async fn write(&mut self, slot: Slot, buf: &[u8; SAVE_SIZE]) {
+        match slot {
+            Slot::LastStory => self.last_story.write(buf).await,
+            Slot::Save(ix) => self.saves[ix].write(buf).await,
+        }
+    }
+}
+
+/// Encode the save for the story with this IFID. Returns `None` if it doesn't fit.
+pub fn encode(ifid: &str, save: &Save, buf: &mut [u8; SAVE_SIZE]) -> Option<()> {
+    encode_record(MAGIC, buf, |writer| {
+        writer.write_str(ifid)?;
+        writer.write_str(save.passage)?;
+        writer.write_byte(save.history.iter().count() as u8)?;
+        for (pid, selected) in save.history.iter() {
+            writer.write_str(pid)?;
+            writer.write_byte(u8::try_from(selected).ok()?)?;
+        }
+        Some(())
+    })
+}
+
+/// Decode a save for this story. Returns `None` if there is no (valid) save, or if it's for
+/// another story or refers to passages the story doesn't have.
+pub fn decode<'a>(story: &'a str, buf: &[u8; SAVE_SIZE]) -> Option<Save<'a>> {
+    let mut reader = decode_record(MAGIC, buf)?;
+    if reader.read_str()? != twine::story_metadata(story).ifid.as_bytes() {
+        return None;
+    }
+
+    let passage = find_pid(story, reader.read_str()?)?;
+    let mut history = History::default();
+    for _ in 0..reader.read_byte()? {
+        let pid = find_pid(story, reader.read_str()?)?;
+        history.push(pid, reader.read_byte()? as usize);
+    }
+
+    Some(Save { passage, history })
+}
+
+/// Encode which story (by IFID) was played last
+pub fn encode_last_story(ifid: &str, buf: &mut [u8; SAVE_SIZE]) -> Option<()> {
+    encode_record(LAST_STORY_MAGIC, buf, |writer| writer.write_str(ifid))
+}
+
+/// Decode which of the stories was played last, if any of them
+pub fn decode_last_story(stories: &[&str], buf: &[u8; SAVE_SIZE]) -> Option<usize> {
+    let ifid = decode_record(LAST_STORY_MAGIC, buf)?.read_str()?;
+    stories
+        .iter()
+        .position(|story| twine::story_metadata(story).ifid.as_bytes() == ifid)
+}
+
+/// Write the header, the payload (written by 'write') and its checksum
+fn encode_record(
+    magic: &[u8; 4],
+    buf: &mut [u8; SAVE_SIZE],
+    write: impl FnOnce(&mut Writer) -> Option<()>,
+) -> Option<()> {
+    buf.fill(0xFF);
+
+    let (header, rest) = buf.split_at_mut(HEADER_SIZE);
+    let payload_capacity = rest.len() - CHECKSUM_SIZE;
+    let mut writer = Writer {
+        buf: &mut rest[..payload_capacity],
+        len: 0,
+    };
+    write(&mut writer)?;
+
+    let len = writer.len;
+    let checksum = fletcher16(&rest[..len]);
+    rest[len..len + CHECKSUM_SIZE].copy_from_slice(&checksum.to_le_bytes());
+
+    header[..magic.len()].copy_from_slice(magic);
+    header[magic.len()] = VERSION;
+    header[magic.len() + 1] = len as u8;
+
+    Some(())
+}
+
+/// Check the header and checksum, and return a reader for the payload
+fn decode_record<'b>(magic: &[u8; 4], buf: &'b [u8; SAVE_SIZE]) -> Option<Reader<'b>> {
+    let (header, rest) = buf.split_at(HEADER_SIZE);
+    if &header[..magic.len()] != magic || header[magic.len()] != VERSION {
+        return None;
+    }
+
+    let len = header[magic.len() + 1] as usize;
+    let payload = rest.get(..len)?;
+    let checksum = rest.get(len..len + CHECKSUM_SIZE)?;
+    if fletcher16(payload).to_le_bytes() != checksum {
+        return None;
+    }
+
+    Some(Reader { buf: payload })
+}
+
+/// The story's own pid (to borrow from the story rather than the buffer)
+fn find_pid<'a>(story: &'a str, pid: &[u8]) -> Option<&'a str> {
+    twine::passages(story)
+        .map(|passage| passage.pid)
+        .find(|candidate| candidate.as_bytes() == pid)
+}
+
+fn fletcher16(data: &[u8]) -> u16 {
+    let (mut sum1, mut sum2) = (0u16, 0u16);
+    for byte in data {
+        sum1 = (sum1 + *byte as u16) % 255;
+        sum2 = (sum2 + sum1) % 255;
+    }
+    (sum2 << 8) | sum1
+}
+
+struct Writer<'b> {
+    buf: &'b mut [u8],
+    len: usize,
+}
+
+impl Writer<'_> {
+    fn write_byte(&mut self, byte: u8) -> Option<()> {
+        *self.buf.get_mut(self.len)? = byte;
+        self.len += 1;
+        Some(())
+    }
+
+    /// Write the string, prefixed with its length
+    fn write_str(&mut self, s: &str) -> Option<()> {
+        self.write_byte(u8::try_from(s.len()).ok()?)?;
+        self.buf
+            .get_mut(self.len..self.len + s.len())?
+            .copy_from_slice(s.as_bytes());
+        self.len += s.len();
+        Some(())
+    }
+}
+
+struct Reader<'b> {
+    buf: &'b [u8],
+}
+
+impl<'b> Reader<'b> {
+    fn read_byte(&mut self) -> Option<u8> {
+        let (byte, rest) = self.buf.split_first()?;
+        self.buf = rest;
+        Some(*byte)
+    }
+
+    fn read_str(&mut self) -> Option<&'b [u8]> {
+        let len = self.read_byte()? as usize;
+        let s = self.buf.get(..len)?;
+        self.buf = &self.buf[len..];
+        Some(s)
+    }
+}
+
+// Flash storage
+
+/// Size of the flash on the Tiny 2040 (see memory.x)
+#[cfg(target_os = "none")]
+pub const FLASH_SIZE: usize = 2048 * 1024;
+
+/// Maximum number of stories, i.e. of save slots
+pub const MAX_STORIES: usize = 8;
+
+/// Size of the STORAGE region of memory.x, at the end of the flash, which the firmware is
+/// never linked into (see build.rs)
+#[cfg(target_os = "none")]
+pub const STORAGE_SIZE: usize = include!(concat!(env!("OUT_DIR"), "/storage_size.rs"));
+
+/// Offset of the STORAGE region: every sector written at runtime must be in it
+#[cfg(target_os = "none")]
+pub const STORAGE_OFFSET: u32 = (FLASH_SIZE - STORAGE_SIZE) as u32;
+
+/// Offset of the (first story's) save in flash: the last sector, far from the firmware
+#[cfg(target_os = "none")]
+pub const SAVE_OFFSET: u32 = (FLASH_SIZE - embassy_rp::flash::ERASE_SIZE) as u32;
+
+/// Offset of the n-th story's save: slots go down from the last sector
+#[cfg(target_os = "none")]
+pub const fn save_offset(story: usize) -> u32 {
+    SAVE_OFFSET - (story * embassy_rp::flash::ERASE_SIZE) as u32
+}
+
+/// Offset of the record of the last story played, right below the save slots
+#[cfg(target_os = "none")]
+pub const LAST_STORY_OFFSET: u32 = save_offset(MAX_STORIES);
+
+// The save slots and the last story played are reserved in memory.x
+#[cfg(target_os = "none")]
+const _: () = assert!(LAST_STORY_OFFSET >= STORAGE_OFFSET);
+
+#[cfg(target_os = "none")]
+pub type Flash<'d> = embassy_rp::flash::Flash<
+    'd,
+    embassy_rp::peripherals::FLASH,
+    embassy_rp::flash::Blocking,
+    FLASH_SIZE,
+>;
+
+/// A save stored in its own flash sector
+#[cfg(target_os = "none")]
+pub struct FlashStorage<'f, 'd> {
+    pub flash: &'f mut Flash<'d>,
+    pub offset: u32,
+}
+
+#[cfg(target_os = "none")]
+impl Storage for FlashStorage<'_, '_> {
+    async fn read(&mut self, buf: &mut [u8; SAVE_SIZE]) {
+        if self.flash.blocking_read(self.offset, buf).is_err() {
+            defmt::warn!("Could not read save");
+            buf.fill(0xFF);
+        }
+    }
+
+    async fn write(&mut self, buf: &[u8; SAVE_SIZE]) {
+        let sector_end = self.offset + embassy_rp::flash::ERASE_SIZE as u32;
+        if self.flash.blocking_erase(self.offset, sector_end).is_err()
+            || self.flash.blocking_write(self.offset, buf).is_err()
+        {
+            defmt::warn!("Could not write save");
+        }
+    }
+}
+
+/// The flash sectors of a library: the saves and the last story played
+#[cfg(target_os = "none")]
+pub struct FlashLibrary<'f, 'd> {
+    pub flash: &'f mut Flash<'d>,
+}
+
+#[cfg(target_os = "none")]
+impl<'d> FlashLibrary<'_, 'd> {
+    fn storage(&mut self, slot: Slot) -> FlashStorage<'_, 'd> {
+        let offset = match slot {
+            Slot::LastStory => LAST_STORY_OFFSET,
+            Slot::Save(ix) => save_offset(ix),
+        };
+        FlashStorage {
+            flash: self.flash,
+            offset,
+        }
+    }
+}
+
+#[cfg(target_os = "none")]
+impl Library for FlashLibrary<'_, '_> {
+    async fn read(&mut self, slot: Slot, buf: &mut [u8; SAVE_SIZE]) {
+        self.storage(slot).read(buf).await;
+    }
+
+    async fn write(&mut self, slot: Slot, buf: &[u8; SAVE_SIZE]) {
+        self.storage(slot).write(buf).await;
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    const STORY: &str = r#"
+        <tw-storydata ifid="ABC" startnode="1">
+            <tw-passagedata pid="1" name="intro">[[Go-&gt;middle]]</tw-passagedata>
+            <tw-passagedata pid="2" name="middle">[[End-&gt;end]]</tw-passagedata>
+            <tw-passagedata pid="3" name="end">The end.</tw-passagedata>
+        </tw-storydata>
+    "#;
+
+    fn encoded(ifid: &str) -> [u8; SAVE_SIZE] {
+        let mut history = History::default();
+        history.push("1", 0);
+        history.push("2", 1);
+
+        let mut buf = [0; SAVE_SIZE];
+        encode(
+            ifid,
+            &Save {
+                passage: "3",
+                history,
+            },
+            &mut buf,
+        )
+        .unwrap();
+        buf
+    }
+
+    #[test]
+    fn can_resume_save() {
+        let save = decode(STORY, &encoded("ABC")).unwrap();
+
+        assert_eq!(save.passage, "3");
+        assert_eq!(
+            save.history.iter().collect::<Vec<_>>(),
+            [("1", 0), ("2", 1)]
+        );
+    }
+
+    #[test]
+    fn ignores_erased_flash() {
+        assert!(decode(STORY, &[0xFF; SAVE_SIZE]).is_none());
+        assert!(decode_last_story(&[STORY], &[0xFF; SAVE_SIZE]).is_none());
+    }
+
+    #[test]
+    fn can_remember_last_story() {
+        const OTHER_STORY: &str = r#"<tw-storydata ifid="XYZ" startnode="1"></tw-storydata>"#;
+
+        let mut buf = [0; SAVE_SIZE];
+        encode_last_story("XYZ", &mut buf).unwrap();
+        assert_eq!(decode_last_story(&[STORY, OTHER_STORY], &buf), Some(1));
+        assert_eq!(decode_last_story(&[STORY], &buf), None);
+
+        // A save isn't a last story record
+        assert_eq!(decode_last_story(&[STORY], &encoded("ABC")), None);
+    }
+
+    #[test]
+    fn ignores_other_stories() {
+        assert!(decode(STORY, &encoded("XYZ")).is_none());
+    }
+
+    #[test]
+    fn ignores_other_versions() {
+        let mut buf = encoded("ABC");
+        buf[MAGIC.len()] = VERSION + 1;
+        assert!(decode(STORY, &buf).is_none());
+    }
+
+    #[test]
+    fn ignores_corrupted_saves() {
+        let mut buf = encoded("ABC");
+        // the passage pid, "3" -> "2"
+        buf[HEADER_SIZE + 5] = b'2';
+        assert!(decode(STORY, &buf).is_none());
+    }
+
+    #[test]
+    fn ignores_unknown_passages() {
+        let mut history = History::default();
+        history.push("42", 0);
+
+        let mut buf = [0; SAVE_SIZE];
+        encode(
+            "ABC",
+            &Save {
+                passage: "1",
+                history,
+            },
+            &mut buf,
+        )
+        .unwrap();
+        assert!(decode(STORY, &buf).is_none());
+    }
+
+    #[test]
+    fn does_not_encode_oversized_saves() {
+        let ifid = "X".repeat(SAVE_SIZE);
+        let mut buf = [0; SAVE_SIZE];
+        let save = Save {
+            passage: "1",
+            history: History::default(),
+        };
+        assert!(encode(&ifid, &save, &mut buf).is_none());
+    }
+}
@@ -0,0 +1,121 @@
+//! USB System Control (HID class) helpers: sleep, wake up and power down
+//!
+//! Like [consumer controls](crate::consumer), system controls are sent through their own
+//! HID interface with [`DESCRIPTOR`]. Reports are a single byte, the usage ID (0, which
+//! is out of the logical range, meaning released).
+//!
+//! NOTE: a host that is asleep has suspended the USB bus and doesn't read reports, so
+//! [`Usage::WakeUp`] only wakes e.g. the screen. Waking a suspended host takes a USB
+//! remote wakeup instead (which the clicker does on any press).
+
+use embassy_time::{Duration, Timer};
+use embassy_usb::class::hid;
+use embassy_usb::driver::Driver;
+
+/// Size of a report, in bytes
+pub const REPORT_SIZE: usize = 1;
+
+pub type HidWriter<'a, D> = hid::HidWriter<'a, D, REPORT_SIZE>;
+
+/// Report descriptor: one array field of 8 bits, with the usages of [`Usage`]. The logical
+/// values are the usage IDs themselves.
+#[rustfmt::skip]
+pub const DESCRIPTOR: &[u8] = &[
+    0x05, 0x01,       // Usage Page (Generic Desktop)
+    0x09, 0x80,       // Usage (System Control)
+    0xA1, 0x01,       // Collection (Application)
+    0x16, 0x81, 0x00, //   Logical Minimum (0x81)
+    0x26, 0x83, 0x00, //   Logical Maximum (0x83)
+    0x19, 0x81,       //   Usage Minimum (System Power Down)
+    0x29, 0x83,       //   Usage Maximum (System Wake Up)
+    0x75, 0x08,       //   Report Size (8)
+    0x95, 0x01,       //   Report Count (1)
+    0x81, 0x00,       //   Input (Data, Array, Absolute)
+    0xC0,             // End Collection
+];
+
+/// Generic Desktop page usages (see the HID Usage Tables, section 4.5)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Usage {
+    PowerDown = 0x81,
+    Sleep = 0x82,
+    WakeUp = 0x83,
+}
+
+/// The usage currently pressed (if any)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Report {
+    pub usage: u8,
+}
+
+impl Report {
+    pub const RELEASED: Report = Report { usage: 0 };
+
+    pub fn pressed(usage: Usage) -> Report {
+        Report { usage: usage as u8 }
+    }
+
+    pub fn to_bytes(&self) -> [u8; REPORT_SIZE] {
+        [self.usage]
+    }
+}
+
+/// Anything system control reports can be sent to, like [`crate::keyboard::Keyboard`]
+#[allow(async_fn_in_trait)]
+pub trait SystemControl {
+    async fn send(&mut self, report: &Report);
+
+    /// Press and release a control
+    async fn press(&mut self, usage: Usage) {
+        self.send(&Report::pressed(usage)).await;
+        self.send(&Report::RELEASED).await;
+    }
+}
+
+/// System control writing to the USB HID device, waiting 'delay' after each report
+pub struct HidSystemControl<'w, 'd, D: Driver<'d>> {
+    pub writer: &'w mut HidWriter<'d, D>,
+    pub delay: Duration,
+}
+
+impl<'d, D: Driver<'d>> SystemControl for HidSystemControl<'_, 'd, D> {
+    async fn send(&mut self, report: &Report) {
+        let _ = self.writer.write(&report.to_bytes()).await;
+        Timer::after(self.delay).await;
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+    use crate::host::{descriptor_item, descriptor_items};
+
+    #[test]
+    fn descriptor_matches_reports() {
+        let items = descriptor_items(DESCRIPTOR);
+        let value = |prefix| descriptor_item(DESCRIPTOR, prefix).unwrap();
+
+        // Usage Page (Generic Desktop), Usage (System Control), Collection (Application)
+        assert_eq!(&items[..3], &[(0x04, 0x01), (0x08, 0x80), (0xA0, 0x01)]);
+        assert_eq!(items.last(), Some(&(0xC0, 0)));
+
+        // Report Size times Report Count, in bytes
+        assert_eq!((value(0x74) * value(0x94) / 8) as usize, REPORT_SIZE);
+
+        // The logical range is the usage range, so that values are usage IDs
+        assert_eq!(value(0x14), Usage::PowerDown as u32);
+        assert_eq!(value(0x18), Usage::PowerDown as u32);
+        assert_eq!(value(0x24), Usage::WakeUp as u32);
+        assert_eq!(value(0x28), Usage::WakeUp as u32);
+    }
+
+    #[test]
+    fn serializes_reports() {
+        assert_eq!(Report::pressed(Usage::PowerDown).to_bytes(), [0x81]);
+        assert_eq!(Report::pressed(Usage::Sleep).to_bytes(), [0x82]);
+        assert_eq!(Report::pressed(Usage::WakeUp).to_bytes(), [0x83]);
+        assert_eq!(Report::RELEASED.to_bytes(), [0x00]);
+    }
+}
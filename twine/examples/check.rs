@@ -0,0 +1,22 @@
+//! Check a story for broken links, unreachable passages, etc.
+//!
+//! Usage: check <story.html>
+
+use std::process::ExitCode;
+
+fn main() -> ExitCode {
+    let Some(path) = std::env::args().nth(1) else {
+        eprintln!("Usage: check <story.html>");
+        return ExitCode::FAILURE;
+    };
+
+    let story = std::fs::read_to_string(&path).expect("Could not read story");
+    let report = twine::analysis::analyze(&story);
+    print!("{}", report);
+
+    if report.is_ok() {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    }
+}
@@ -0,0 +1,29 @@
+//! Export a story's passage graph as DOT (Graphviz) or JSON
+//!
+//! Usage: export <dot|json> <story.html>
+
+use std::process::ExitCode;
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let [format, path] = args.as_slice() else {
+        eprintln!("Usage: export <dot|json> <story.html>");
+        return ExitCode::FAILURE;
+    };
+
+    let story = std::fs::read_to_string(path).expect("Could not read story");
+
+    let mut out = String::new();
+    let res = match format.as_str() {
+        "dot" => twine::export::to_dot(&story, &mut out),
+        "json" => twine::export::to_json(&story, &mut out),
+        _ => {
+            eprintln!("Unknown format '{}', expected 'dot' or 'json'", format);
+            return ExitCode::FAILURE;
+        }
+    };
+    res.expect("Could not export story");
+
+    print!("{}", out);
+    ExitCode::SUCCESS
+}
@@ -0,0 +1,371 @@
+//! Story graph analysis
+//!
+//! Builds the graph of passages (nodes) and links (edges) and reports the issues that would
+//! make the device misbehave (broken links) or that are likely mistakes (unreachable passages,
+//! loops the player can never escape). This allocates and is meant to be used on the host.
+
+use std::collections::HashMap;
+
+use crate::{find_start_passage_id, links, passages};
+
+/// The passage graph of a story
+pub struct Graph<'a> {
+    /// The passage names, indexed by node
+    pub names: Vec<&'a str>,
+    /// The start node, if the 'startnode' passage exists
+    pub start: Option<usize>,
+    /// For each node, the nodes it links to
+    pub edges: Vec<Vec<usize>>,
+    /// Links to passages that don't exist
+    pub broken_links: Vec<BrokenLink<'a>>,
+}
+
+/// A link whose target passage does not exist
+#[derive(Debug, PartialEq, Eq)]
+pub struct BrokenLink<'a> {
+    /// Name of the passage containing the link
+    pub passage: &'a str,
+    pub target: &'a str,
+}
+
+/// Everything that was found wrong (or suspicious) with a story
+#[derive(Debug, Default)]
+pub struct Report<'a> {
+    /// Set if the 'startnode' does not match any passage
+    pub missing_start: bool,
+    pub broken_links: Vec<BrokenLink<'a>>,
+    /// Passages that cannot be reached from the start passage
+    pub unreachable: Vec<&'a str>,
+    /// Passages without any links, i.e. where the story ends
+    pub dead_ends: Vec<&'a str>,
+    /// Groups of passages that link to each other but never to an ending, trapping the player
+    pub cycles_without_exit: Vec<Vec<&'a str>>,
+}
+
+impl Report<'_> {
+    /// Whether the story can be played without issues. Dead ends are not considered issues
+    /// since they are how stories end.
+    pub fn is_ok(&self) -> bool {
+        !self.missing_start
+            && self.broken_links.is_empty()
+            && self.unreachable.is_empty()
+            && self.cycles_without_exit.is_empty()
+    }
+}
+
+impl core::fmt::Display for Report<'_> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        if self.missing_start {
+            writeln!(f, "start passage does not exist")?;
+        }
+        for link in &self.broken_links {
+            writeln!(f, "broken link: '{}' -> '{}'", link.passage, link.target)?;
+        }
+        for name in &self.unreachable {
+            writeln!(f, "unreachable passage: '{}'", name)?;
+        }
+        for name in &self.dead_ends {
+            writeln!(f, "dead end: '{}'", name)?;
+        }
+        for cycle in &self.cycles_without_exit {
+            writeln!(f, "cycle without exit: '{}'", cycle.join("', '"))?;
+        }
+        Ok(())
+    }
+}
+
+impl<'a> Graph<'a> {
+    pub fn build(story: &'a str) -> Graph<'a> {
+        let passages: Vec<_> = passages(story).collect();
+
+        let names: Vec<&str> = passages.iter().map(|p| p.name).collect();
+        let by_name: HashMap<&str, usize> =
+            names.iter().enumerate().map(|(i, n)| (*n, i)).collect();
+
+        let start_pid = find_start_passage_id(story);
+        let start = passages.iter().position(|p| p.pid == start_pid);
+
+        let mut edges = vec![vec![]; passages.len()];
+        let mut broken_links = vec![];
+
+        for (ix, passage) in passages.iter().enumerate() {
+            for target in links(passage.text).flat_map(|link| link.targets()) {
+                match by_name.get(target) {
+                    Some(node) => edges[ix].push(*node),
+                    None => broken_links.push(BrokenLink {
+                        passage: passage.name,
+                        target,
+                    }),
+                }
+            }
+        }
+
+        Graph {
+            names,
+            start,
+            edges,
+            broken_links,
+        }
+    }
+
+    /// The nodes reachable from the start node (including the start node itself)
+    pub fn reachable(&self) -> Vec<bool> {
+        let mut seen = vec![false; self.names.len()];
+        let mut todo: Vec<usize> = self.start.into_iter().collect();
+
+        while let Some(node) = todo.pop() {
+            if seen[node] {
+                continue;
+            }
+            seen[node] = true;
+            todo.extend(&self.edges[node]);
+        }
+
+        seen
+    }
+
+    /// The strongly connected components of the graph (Tarjan's algorithm), in reverse
+    /// topological order
+    pub fn components(&self) -> Vec<Vec<usize>> {
+        struct Tarjan<'g> {
+            edges: &'g [Vec<usize>],
+            index: Vec<Option<usize>>,
+            lowlink: Vec<usize>,
+            on_stack: Vec<bool>,
+            stack: Vec<usize>,
+            next_index: usize,
+            components: Vec<Vec<usize>>,
+        }
+
+        impl Tarjan<'_> {
+            fn visit(&mut self, node: usize) {
+                self.index[node] = Some(self.next_index);
+                self.lowlink[node] = self.next_index;
+                self.next_index += 1;
+                self.stack.push(node);
+                self.on_stack[node] = true;
+
+                for &next in &self.edges[node] {
+                    match self.index[next] {
+                        None => {
+                            self.visit(next);
+                            self.lowlink[node] = self.lowlink[node].min(self.lowlink[next]);
+                        }
+                        Some(index) if self.on_stack[next] => {
+                            self.lowlink[node] = self.lowlink[node].min(index);
+                        }
+                        _ => {}
+                    }
+                }
+
+                if Some(self.lowlink[node]) == self.index[node] {
+                    let mut component = vec![];
+                    loop {
+                        let other = self.stack.pop().unwrap();
+                        self.on_stack[other] = false;
+                        component.push(other);
+                        if other == node {
+                            break;
+                        }
+                    }
+                    self.components.push(component);
+                }
+            }
+        }
+
+        let n = self.names.len();
+        let mut tarjan = Tarjan {
+            edges: &self.edges,
+            index: vec![None; n],
+            lowlink: vec![0; n],
+            on_stack: vec![false; n],
+            stack: vec![],
+            next_index: 0,
+            components: vec![],
+        };
+
+        for node in 0..n {
+            if tarjan.index[node].is_none() {
+                tarjan.visit(node);
+            }
+        }
+
+        tarjan.components
+    }
+}
+
+/// Analyze the story and report issues
+pub fn analyze(story: &str) -> Report<'_> {
+    let graph = Graph::build(story);
+    let reachable = graph.reachable();
+
+    let unreachable = (0..graph.names.len())
+        .filter(|&node| !reachable[node])
+        .map(|node| graph.names[node])
+        .collect();
+
+    let dead_ends = (0..graph.names.len())
+        .filter(|&node| graph.edges[node].is_empty())
+        .map(|node| graph.names[node])
+        .collect();
+
+    // A component is a trap if it's a cycle (more than one node or a self link) that the player
+    // can reach but not leave. Broken links don't count as exits since they can't be followed.
+    let cycles_without_exit = graph
+        .components()
+        .into_iter()
+        .filter(|component| reachable[component[0]])
+        .filter(|component| {
+            component.len() > 1 || graph.edges[component[0]].contains(&component[0])
+        })
+        .filter(|component| {
+            component.iter().all(|node| {
+                graph.edges[*node]
+                    .iter()
+                    .all(|next| component.contains(next))
+            })
+        })
+        .map(|mut component| {
+            component.sort();
+            component
+                .into_iter()
+                .map(|node| graph.names[node])
+                .collect()
+        })
+        .collect();
+
+    Report {
+        missing_start: graph.start.is_none(),
+        broken_links: graph.broken_links,
+        unreachable,
+        dead_ends,
+        cycles_without_exit,
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    #[test]
+    fn can_analyze_healthy_story() {
+        const STORY: &str = r#"
+        <tw-storydata startnode="1">
+            <tw-passagedata pid="1" name="intro">Hi [[Go-&gt;middle]]</tw-passagedata>
+            <tw-passagedata pid="2" name="middle">[[intro]] [[end]]</tw-passagedata>
+            <tw-passagedata pid="3" name="end">The end.</tw-passagedata>
+        </tw-storydata>
+        "#;
+
+        let report = analyze(STORY);
+        assert!(report.is_ok(), "{}", report);
+        assert_eq!(report.dead_ends, ["end"]);
+    }
+
+    #[test]
+    fn can_find_broken_links() {
+        const STORY: &str = r#"
+        <tw-storydata startnode="1">
+            <tw-passagedata pid="1" name="intro">[[Go-&gt;renamed]] [[end]]</tw-passagedata>
+            <tw-passagedata pid="2" name="end">The end.</tw-passagedata>
+        </tw-storydata>
+        "#;
+
+        let report = analyze(STORY);
+        assert!(!report.is_ok());
+        assert_eq!(
+            report.broken_links,
+            [BrokenLink {
+                passage: "intro",
+                target: "renamed"
+            }]
+        );
+    }
+
+    #[test]
+    fn checks_all_random_link_targets() {
+        const STORY: &str = r#"
+        <tw-storydata startnode="1">
+            <tw-passagedata pid="1" name="intro">[[Flip-&gt;(either: &quot;heads&quot;, &quot;tail&quot;)]]</tw-passagedata>
+            <tw-passagedata pid="2" name="heads">The end.</tw-passagedata>
+            <tw-passagedata pid="3" name="tails">The end.</tw-passagedata>
+        </tw-storydata>
+        "#;
+
+        let report = analyze(STORY);
+        assert_eq!(
+            report.broken_links,
+            [BrokenLink {
+                passage: "intro",
+                target: "tail"
+            }]
+        );
+        assert_eq!(report.unreachable, ["tails"]);
+    }
+
+    #[test]
+    fn can_find_unreachable_passages() {
+        const STORY: &str = r#"
+        <tw-storydata startnode="1">
+            <tw-passagedata pid="1" name="intro">[[end]]</tw-passagedata>
+            <tw-passagedata pid="2" name="orphan">[[end]]</tw-passagedata>
+            <tw-passagedata pid="3" name="end">The end.</tw-passagedata>
+        </tw-storydata>
+        "#;
+
+        let report = analyze(STORY);
+        assert_eq!(report.unreachable, ["orphan"]);
+        assert!(report.cycles_without_exit.is_empty());
+    }
+
+    #[test]
+    fn can_find_missing_start() {
+        const STORY: &str = r#"
+        <tw-storydata startnode="42">
+            <tw-passagedata pid="1" name="intro">The end.</tw-passagedata>
+        </tw-storydata>
+        "#;
+
+        let report = analyze(STORY);
+        assert!(report.missing_start);
+        assert_eq!(report.unreachable, ["intro"]);
+    }
+
+    #[test]
+    fn can_find_cycles_without_exit() {
+        const STORY: &str = r#"
+        <tw-storydata startnode="1">
+            <tw-passagedata pid="1" name="intro">[[a]] [[end]]</tw-passagedata>
+            <tw-passagedata pid="2" name="a">[[b]]</tw-passagedata>
+            <tw-passagedata pid="3" name="b">[[a]] [[c]]</tw-passagedata>
+            <tw-passagedata pid="4" name="c">[[c]]</tw-passagedata>
+            <tw-passagedata pid="5" name="end">The end.</tw-passagedata>
+        </tw-storydata>
+        "#;
+
+        let report = analyze(STORY);
+        // 'a' and 'b' can escape to 'c', which loops on itself forever
+        assert_eq!(report.cycles_without_exit, [vec!["c"]]);
+
+        const STORY_WITH_EXIT: &str = r#"
+        <tw-storydata startnode="1">
+            <tw-passagedata pid="1" name="a">[[b]]</tw-passagedata>
+            <tw-passagedata pid="2" name="b">[[a]] [[end]]</tw-passagedata>
+            <tw-passagedata pid="3" name="end">The end.</tw-passagedata>
+        </tw-storydata>
+        "#;
+
+        let report = analyze(STORY_WITH_EXIT);
+        assert!(report.is_ok(), "{}", report);
+    }
+
+    #[test]
+    fn cyoa_story_is_sound() {
+        // The story that is flashed on the device
+        const STORY: &str = include_str!("../../src/bin/cyoa/ghostwriter.html");
+
+        let report = analyze(STORY);
+        assert!(report.is_ok(), "{}", report);
+    }
+}
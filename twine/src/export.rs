@@ -0,0 +1,190 @@
+//! Export the passage graph to other formats
+//!
+//! Both exporters stream to a `core::fmt::Write` and do not allocate. Passage names, texts
+//! and link labels are exported as they appear in the story (i.e. HTML-escaped), which is
+//! also what the device plays.
+
+use core::fmt::{self, Write};
+
+use crate::{find_start_passage_id, links, passages, story_metadata};
+
+/// Export the story as a Graphviz (DOT) digraph: passages are nodes (the start passage is
+/// highlighted) and links are edges labelled with the link labels.
+pub fn to_dot(story: &str, out: &mut impl Write) -> fmt::Result {
+    let start_pid = find_start_passage_id(story);
+
+    writeln!(out, "digraph story {{")?;
+    writeln!(out, "  node [shape=box];")?;
+
+    for passage in passages(story) {
+        write!(out, "  ")?;
+        write_dot_str(out, passage.name)?;
+        if passage.pid == start_pid {
+            write!(out, " [style=\"bold,filled\", fillcolor=lightblue]")?;
+        }
+        writeln!(out, ";")?;
+
+        for link in links(passage.text) {
+            // Random links have an edge to each possible target
+            for target in link.targets() {
+                write!(out, "  ")?;
+                write_dot_str(out, passage.name)?;
+                write!(out, " -> ")?;
+                write_dot_str(out, target)?;
+                write!(out, " [label=")?;
+                write_dot_str(out, link.label)?;
+                writeln!(out, "];")?;
+            }
+        }
+    }
+
+    writeln!(out, "}}")
+}
+
+/// Export the story as JSON:
+///
+/// ```json
+/// { "name": "Story", "ifid": "...", "start": "1",
+///   "passages": [
+///     { "pid": "1", "name": "intro", "tags": ["slow"], "position": [350, 200],
+///       "text": "...", "links": [ { "label": "Go", "target": "end" } ] } ] }
+/// ```
+pub fn to_json(story: &str, out: &mut impl Write) -> fmt::Result {
+    let metadata = story_metadata(story);
+
+    write!(out, "{{\"name\":")?;
+    write_json_str(out, metadata.name)?;
+    write!(out, ",\"ifid\":")?;
+    write_json_str(out, metadata.ifid)?;
+    write!(out, ",\"start\":")?;
+    write_json_str(out, metadata.start_node)?;
+    write!(out, ",\"passages\":[")?;
+
+    for (ix, passage) in passages(story).enumerate() {
+        if ix > 0 {
+            write!(out, ",")?;
+        }
+
+        write!(out, "{{\"pid\":")?;
+        write_json_str(out, passage.pid)?;
+        write!(out, ",\"name\":")?;
+        write_json_str(out, passage.name)?;
+        write!(out, ",\"tags\":[")?;
+        for (ix, tag) in passage.tags.iter().enumerate() {
+            if ix > 0 {
+                write!(out, ",")?;
+            }
+            write_json_str(out, tag)?;
+        }
+        write!(out, "],\"position\":")?;
+        match passage.position {
+            Some((x, y)) => write!(out, "[{},{}]", x, y)?,
+            None => write!(out, "null")?,
+        }
+        write!(out, ",\"text\":")?;
+        write_json_str(out, passage.text)?;
+        write!(out, ",\"links\":[")?;
+
+        for (ix, link) in links(passage.text).enumerate() {
+            if ix > 0 {
+                write!(out, ",")?;
+            }
+            write!(out, "{{\"label\":")?;
+            write_json_str(out, link.label)?;
+            write!(out, ",\"target\":")?;
+            write_json_str(out, link.target)?;
+            write!(out, "}}")?;
+        }
+
+        write!(out, "]}}")?;
+    }
+
+    writeln!(out, "]}}")
+}
+
+/// Write a quoted DOT string
+fn write_dot_str(out: &mut impl Write, s: &str) -> fmt::Result {
+    out.write_char('"')?;
+    for chr in s.chars() {
+        match chr {
+            '"' => out.write_str("\\\"")?,
+            '\\' => out.write_str("\\\\")?,
+            '\n' => out.write_str("\\n")?,
+            chr => out.write_char(chr)?,
+        }
+    }
+    out.write_char('"')
+}
+
+/// Write a quoted JSON string
+fn write_json_str(out: &mut impl Write, s: &str) -> fmt::Result {
+    out.write_char('"')?;
+    for chr in s.chars() {
+        match chr {
+            '"' => out.write_str("\\\"")?,
+            '\\' => out.write_str("\\\\")?,
+            '\n' => out.write_str("\\n")?,
+            '\r' => out.write_str("\\r")?,
+            '\t' => out.write_str("\\t")?,
+            chr if (chr as u32) < 0x20 => write!(out, "\\u{:04x}", chr as u32)?,
+            chr => out.write_char(chr)?,
+        }
+    }
+    out.write_char('"')
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    const STORY: &str = r#"<tw-storydata name="Test" ifid="ABC" startnode="1"><tw-passagedata pid="1" name="intro" tags="slow red" position="350,200.5">Say "hi"
+[[Go-&gt;end]]</tw-passagedata><tw-passagedata pid="2" name="end">The end.</tw-passagedata></tw-storydata>"#;
+
+    #[test]
+    fn can_export_dot() {
+        let mut dot = String::new();
+        to_dot(STORY, &mut dot).unwrap();
+
+        assert_eq!(
+            dot,
+            r#"digraph story {
+  node [shape=box];
+  "intro" [style="bold,filled", fillcolor=lightblue];
+  "intro" -> "end" [label="Go"];
+  "end";
+}
+"#
+        );
+    }
+
+    #[test]
+    fn can_export_json() {
+        let mut json = String::new();
+        to_json(STORY, &mut json).unwrap();
+
+        assert_eq!(
+            json,
+            concat!(
+                r#"{"name":"Test","ifid":"ABC","start":"1","passages":["#,
+                r#"{"pid":"1","name":"intro","tags":["slow","red"],"position":[350,200.5],"#,
+                r#""text":"Say \"hi\"\n[[Go-&gt;end]]","#,
+                r#""links":[{"label":"Go","target":"end"}]},"#,
+                r#"{"pid":"2","name":"end","tags":[],"position":null,"text":"The end.","links":[]}"#,
+                "]}\n"
+            )
+        );
+    }
+
+    #[test]
+    fn exports_invalid_positions_as_null() {
+        let story = STORY.replace("350,200.5", "NaN,inf");
+        let mut json = String::new();
+        to_json(&story, &mut json).unwrap();
+
+        assert!(
+            json.contains(r#""tags":["slow","red"],"position":null,"#),
+            "{json}"
+        );
+    }
+}
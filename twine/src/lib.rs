@@ -1,4 +1,9 @@
-#![cfg_attr(not(test), no_std)]
+#![cfg_attr(not(any(test, feature = "std")), no_std)]
+
+#[cfg(feature = "std")]
+pub mod analysis;
+pub mod export;
+pub mod macros;
 
 struct Parser<'a> {
     tokenizer: htmlparser::Tokenizer<'a>,
@@ -11,21 +16,25 @@ impl<'a> Parser<'a> {
         }
     }
 
-    fn find_elem(&mut self, tag: &str) {
+    // Returns 'false' if no such element was found before the end of the story
+    fn find_elem(&mut self, tag: &str) -> bool {
         for token in self.tokenizer.by_ref() {
             let token = token.expect("Could not read token");
 
             if let htmlparser::Token::ElementStart { local, .. } = token {
                 if local == tag {
-                    break;
+                    return true;
                 }
             }
         }
+
+        false
     }
 
+    // Looks up the attributes of the element just found.
     // NOTE: this always consumes all the attributes and the opening tag's closing bracket.
-    fn find_attr(&mut self, attribute_name: &str) -> Option<&'a str> {
-        let mut result: Option<&'a str> = None;
+    fn find_attrs<const N: usize>(&mut self, attribute_names: [&str; N]) -> [Option<&'a str>; N] {
+        let mut result: [Option<&'a str>; N] = [None; N];
         loop {
             // Always expect a next token (even if it's just ElementEnd
             let token = self.tokenizer.next().unwrap();
@@ -33,8 +42,8 @@ impl<'a> Parser<'a> {
 
             match token {
                 htmlparser::Token::Attribute { local, value, .. } => {
-                    if local == attribute_name {
-                        result = Some(value.map(|s| s.as_str()).unwrap_or("true"));
+                    if let Some(ix) = attribute_names.iter().position(|name| local == *name) {
+                        result[ix] = Some(value.map(|s| s.as_str()).unwrap_or("true"));
                     }
                 }
 
@@ -50,59 +59,202 @@ impl<'a> Parser<'a> {
 
         result
     }
+}
 
-    fn find_elem_by_attr(&mut self, tag: &str, attr_name: &str, attr_val: &str) {
-        loop {
-            self.find_elem(tag);
-            let val = self.find_attr(attr_name);
-            if let Some(val) = val {
-                if val == attr_val {
-                    return;
-                }
+pub fn find_passage_text_by_id<'a>(story: &'a str, passage_id: &str) -> &'a str {
+    find_passage_by_id(story, passage_id).text
+}
+
+pub fn find_passage_text_by_name<'a>(story: &'a str, passage_name: &str) -> &'a str {
+    find_passage_by_name(story, passage_name).text
+}
+
+pub fn find_start_passage_id(story: &str) -> &str {
+    story_metadata(story).start_node
+}
+
+/// The story attributes, as found on the 'tw-storydata' element
+pub struct StoryMetadata<'a> {
+    story: &'a str,
+    pub name: &'a str,
+    pub ifid: &'a str,
+    pub format: &'a str,
+    pub format_version: &'a str,
+    pub creator: &'a str,
+    pub creator_version: &'a str,
+    /// The pid of the start passage
+    pub start_node: &'a str,
+}
+
+/// A tag color definition, as found in a 'tw-tag' element
+pub struct TagColor<'a> {
+    pub name: &'a str,
+    pub color: &'a str,
+}
+
+impl<'a> StoryMetadata<'a> {
+    /// All the tag color definitions of the story
+    pub fn tag_colors(&self) -> impl Iterator<Item = TagColor<'a>> {
+        let mut parser = Parser::parse(self.story);
+        core::iter::from_fn(move || {
+            if !parser.find_elem("tw-tag") {
+                return None;
             }
-        }
+            let [name, color] = parser.find_attrs(["name", "color"]);
+            Some(TagColor {
+                name: name.unwrap_or(""),
+                color: color.unwrap_or(""),
+            })
+        })
+    }
+
+    /// The color of the given tag, if defined
+    pub fn tag_color(&self, tag: &str) -> Option<&'a str> {
+        self.tag_colors()
+            .find(|tag_color| tag_color.name == tag)
+            .map(|tag_color| tag_color.color)
     }
 }
 
-pub fn find_passage_text_by_id<'a>(story: &'a str, passage_id: &str) -> &'a str {
+/// The story's attributes, all empty if there is no 'tw-storydata' element (e.g. in
+/// another HTML file)
+pub fn story_metadata(story: &str) -> StoryMetadata<'_> {
     let mut parser = Parser::parse(story);
 
-    parser.find_elem_by_attr("tw-passagedata", "pid", passage_id);
+    let attrs = if parser.find_elem("tw-storydata") {
+        parser.find_attrs([
+            "name",
+            "ifid",
+            "format",
+            "format-version",
+            "creator",
+            "creator-version",
+            "startnode",
+        ])
+    } else {
+        [None; 7]
+    };
+    let [name, ifid, format, format_version, creator, creator_version, start_node] =
+        attrs.map(|attr| attr.unwrap_or(""));
+
+    StoryMetadata {
+        story,
+        name,
+        ifid,
+        format,
+        format_version,
+        creator,
+        creator_version,
+        start_node,
+    }
+}
 
-    let text = parser
-        .tokenizer
-        .next()
-        .expect("No more tokens")
-        .expect("Could not read token");
+/// A passage of the story, as found in a 'tw-passagedata' element
+pub struct Passage<'a> {
+    pub pid: &'a str,
+    pub name: &'a str,
+    pub tags: Tags<'a>,
+    /// The position of the passage in the Twine editor
+    pub position: Option<(f32, f32)>,
+    pub text: &'a str,
+}
 
-    match text {
-        htmlparser::Token::Text { text, .. } => text.as_str(),
-        _ => panic!("Bad token"),
+/// The tags of a passage (space separated in the 'tags' attribute)
+#[derive(Clone, Copy, Default)]
+pub struct Tags<'a>(&'a str);
+
+impl<'a> Tags<'a> {
+    /// Tags from a space separated list
+    pub fn new(tags: &'a str) -> Tags<'a> {
+        Tags(tags)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &'a str> {
+        self.0.split_ascii_whitespace()
+    }
+
+    pub fn contains(&self, tag: &str) -> bool {
+        self.iter().any(|t| t == tag)
+    }
+
+    /// The value of the first 'name-value' tag, e.g. "3s" for 'timeout-3s'
+    pub fn value(&self, name: &str) -> Option<&'a str> {
+        self.iter().find_map(|tag| {
+            tag.strip_prefix(name)
+                .and_then(|rest| rest.strip_prefix('-'))
+        })
     }
 }
 
-pub fn find_passage_text_by_name<'a>(story: &'a str, passage_name: &str) -> &'a str {
-    let mut parser = Parser::parse(story);
+/// Parse a duration like "3s" or "500ms" (in tags), in milliseconds
+pub fn parse_duration_ms(duration: &str) -> Option<u64> {
+    if let Some(ms) = duration.strip_suffix("ms") {
+        ms.parse().ok()
+    } else {
+        duration
+            .strip_suffix('s')?
+            .parse::<u64>()
+            .ok()?
+            .checked_mul(1000)
+    }
+}
 
-    parser.find_elem_by_attr("tw-passagedata", "name", passage_name);
+/// Parse a position like "350,200.5". Only finite coordinates are positions (e.g. "NaN"
+/// would parse but isn't valid JSON when exported).
+fn parse_position(position: &str) -> Option<(f32, f32)> {
+    let (x, y) = position.split_once(',')?;
+    let parse = |v: &str| v.trim().parse().ok().filter(|v: &f32| v.is_finite());
+    Some((parse(x)?, parse(y)?))
+}
 
-    let text = parser
-        .tokenizer
-        .next()
-        .expect("No more tokens")
-        .expect("Could not read token");
+/// Iterator over all the passages of a story, in document order
+pub struct Passages<'a> {
+    parser: Parser<'a>,
+}
 
-    match text {
-        htmlparser::Token::Text { text, .. } => text.as_str(),
-        _ => panic!("Bad token"),
+impl<'a> Iterator for Passages<'a> {
+    type Item = Passage<'a>;
+
+    fn next(&mut self) -> Option<Passage<'a>> {
+        if !self.parser.find_elem("tw-passagedata") {
+            return None;
+        }
+
+        let [pid, name, tags, position] =
+            self.parser.find_attrs(["pid", "name", "tags", "position"]);
+
+        // Empty passages don't have a text node, the next token is the closing tag
+        let text = match self.parser.tokenizer.next() {
+            Some(Ok(htmlparser::Token::Text { text })) => text.as_str(),
+            _ => "",
+        };
+
+        Some(Passage {
+            pid: pid.unwrap_or(""),
+            name: name.unwrap_or(""),
+            tags: Tags(tags.unwrap_or("")),
+            position: position.and_then(parse_position),
+            text,
+        })
     }
 }
 
-pub fn find_start_passage_id(story: &str) -> &str {
-    let mut parser = Parser::parse(story);
+pub fn passages(story: &str) -> Passages<'_> {
+    Passages {
+        parser: Parser::parse(story),
+    }
+}
+
+pub fn find_passage_by_id<'a>(story: &'a str, passage_id: &str) -> Passage<'a> {
+    passages(story)
+        .find(|passage| passage.pid == passage_id)
+        .unwrap_or_else(|| panic!("No passage with pid={}", passage_id))
+}
 
-    parser.find_elem("tw-storydata");
-    parser.find_attr("startnode").unwrap()
+pub fn find_passage_by_name<'a>(story: &'a str, passage_name: &str) -> Passage<'a> {
+    passages(story)
+        .find(|passage| passage.name == passage_name)
+        .unwrap_or_else(|| panic!("No passage with name={}", passage_name))
 }
 
 pub fn get_n_links(passage: &str) -> usize {
@@ -111,9 +263,22 @@ pub fn get_n_links(passage: &str) -> usize {
 
 pub struct LinkData<'a> {
     pub label: &'a str,
+    /// The target passage's name, or an `(either:)` macro of names (see [`macros`])
     pub target: &'a str,
 }
 
+impl<'a> LinkData<'a> {
+    /// The passages the link may lead to
+    pub fn targets(&self) -> impl Iterator<Item = &'a str> {
+        let either = macros::Either::parse(self.target);
+        let single = either.is_none().then_some(self.target);
+        either
+            .into_iter()
+            .flat_map(|either| either.options())
+            .chain(single)
+    }
+}
+
 pub fn get_link_data<'a>(passage: &'a str, n: usize) -> LinkData<'a> {
     const START: &str = "[[";
     const END: &str = "]]";
@@ -133,6 +298,61 @@ pub fn get_link_data<'a>(passage: &'a str, n: usize) -> LinkData<'a> {
     LinkData { label, target }
 }
 
+/// Iterate over all the links of a passage
+pub fn links(passage: &str) -> impl Iterator<Item = LinkData<'_>> {
+    (0..get_n_links(passage)).map(|n| get_link_data(passage, n))
+}
+
+/// The characters of the text (e.g. an attribute) with its HTML entities decoded: the
+/// named ones Twine escapes (`&amp;`, `&lt;`, `&gt;`, `&quot;`, `&apos;`) and numeric ones.
+/// Anything else is left as is.
+pub fn unescaped(text: &str) -> impl Iterator<Item = char> + '_ {
+    let mut rest = text;
+    core::iter::from_fn(move || {
+        let (chr, n) = match rest.find(';').filter(|_| rest.starts_with('&')) {
+            Some(end) => match decode_entity(&rest[1..end]) {
+                Some(chr) => (chr, end + 1),
+                None => ('&', 1),
+            },
+            None => {
+                let chr = rest.chars().next()?;
+                (chr, chr.len_utf8())
+            }
+        };
+        rest = &rest[n..];
+        Some(chr)
+    })
+}
+
+/// Decode the HTML entities of the text (see [`unescaped`]) into the buffer. Returns `None`
+/// if it doesn't fit.
+pub fn unescape<'b>(text: &str, buf: &'b mut [u8]) -> Option<&'b str> {
+    let mut len = 0;
+    for chr in unescaped(text) {
+        let encoded = chr.encode_utf8(buf.get_mut(len..len + chr.len_utf8())?);
+        len += encoded.len();
+    }
+    // NOTE: only whole chars were written
+    core::str::from_utf8(&buf[..len]).ok()
+}
+
+/// The character of an entity, without its '&' and ';'
+fn decode_entity(entity: &str) -> Option<char> {
+    let code = match entity {
+        "amp" => return Some('&'),
+        "lt" => return Some('<'),
+        "gt" => return Some('>'),
+        "quot" => return Some('"'),
+        "apos" => return Some('\''),
+        _ => entity.strip_prefix('#')?,
+    };
+    let code = match code.strip_prefix(['x', 'X']) {
+        Some(hex) => u32::from_str_radix(hex, 16).ok()?,
+        None => code.parse().ok()?,
+    };
+    char::from_u32(code)
+}
+
 #[cfg(test)]
 mod test {
 
@@ -149,32 +369,6 @@ mod test {
         assert_eq!(passage_id, "1");
     }
 
-    #[test]
-    fn can_find_elem_by_attr() {
-        const STORY: &str = r#"
-        <tw-storydata>
-            <tw-passagedata pid="1"></tw-passagedata>
-            <tw-passagedata pid="2">Hello</tw-passagedata>
-        </tw-storydata>
-        "#;
-
-        let mut parser = Parser::parse(&STORY);
-        parser.find_elem_by_attr("tw-passagedata", "pid", "2");
-        if let Some(next) = parser.tokenizer.next() {
-            let token = next.expect("Could not read token");
-            match token {
-                htmlparser::Token::Text { text } => {
-                    assert_eq!(text.as_str(), "Hello");
-                }
-                _ => {
-                    panic!("Unexpected token: {:?}", token);
-                }
-            }
-        } else {
-            panic!("No more tokens");
-        }
-    }
-
     #[test]
     fn can_find_passage_text_by_id() {
         const STORY: &str = r#"
@@ -229,6 +423,163 @@ mod test {
         assert_eq!(link_1.target, "Bar");
     }
 
+    #[test]
+    fn can_iterate_passages() {
+        const STORY: &str = r#"
+            <tw-storydata startnode="1">
+                <tw-passagedata pid="1" name="intro">Once upon a time...</tw-passagedata>
+                <tw-passagedata pid="2" name="empty"></tw-passagedata>
+                <tw-passagedata pid="3" name="end">The end.</tw-passagedata>
+            </tw-storydata>
+        "#;
+
+        let passages: Vec<_> = passages(STORY).collect();
+        assert_eq!(passages.len(), 3);
+
+        assert_eq!(passages[0].pid, "1");
+        assert_eq!(passages[0].name, "intro");
+        assert_eq!(passages[0].text, "Once upon a time...");
+
+        assert_eq!(passages[1].name, "empty");
+        assert_eq!(passages[1].text, "");
+
+        assert_eq!(passages[2].pid, "3");
+        assert_eq!(passages[2].text, "The end.");
+    }
+
+    #[test]
+    fn can_read_passage_tags_and_position() {
+        const STORY: &str = r#"
+            <tw-storydata startnode="1">
+                <tw-passagedata pid="1" name="intro" tags="slow  red" position="350,200.5">Hi</tw-passagedata>
+                <tw-passagedata pid="2" name="end" tags="">The end.</tw-passagedata>
+            </tw-storydata>
+        "#;
+
+        let passages: Vec<_> = passages(STORY).collect();
+
+        assert_eq!(passages[0].tags.iter().collect::<Vec<_>>(), ["slow", "red"]);
+        assert!(passages[0].tags.contains("red"));
+        assert!(!passages[0].tags.contains("re"));
+        assert_eq!(passages[0].position, Some((350.0, 200.5)));
+
+        assert_eq!(passages[1].tags.iter().count(), 0);
+        assert_eq!(passages[1].position, None);
+    }
+
+    #[test]
+    fn ignores_invalid_positions() {
+        for position in ["NaN,1", "1,inf", "-inf,0", "1", "a,b", ""] {
+            assert_eq!(parse_position(position), None, "{position}");
+        }
+        assert_eq!(parse_position(" 1 , -2.5"), Some((1.0, -2.5)));
+    }
+
+    #[test]
+    fn can_list_random_link_targets() {
+        let passage = "[[Flip-&gt;(either: &quot;heads&quot;, &quot;tails&quot;)]] [[Leave]]";
+        let targets: Vec<Vec<_>> = links(passage).map(|l| l.targets().collect()).collect();
+        assert_eq!(targets, [vec!["heads", "tails"], vec!["Leave"]]);
+    }
+
+    #[test]
+    fn can_read_tag_values() {
+        let tags = Tags::new("red timeout-3s default-2 timeout-1s");
+
+        assert_eq!(tags.value("timeout"), Some("3s"));
+        assert_eq!(tags.value("default"), Some("2"));
+        assert_eq!(tags.value("red"), None);
+        assert_eq!(tags.value("time"), None);
+    }
+
+    #[test]
+    fn can_parse_durations() {
+        assert_eq!(parse_duration_ms("3s"), Some(3000));
+        assert_eq!(parse_duration_ms("250ms"), Some(250));
+        assert_eq!(parse_duration_ms("3"), None);
+        assert_eq!(parse_duration_ms("1.5s"), None);
+        assert_eq!(parse_duration_ms("ms"), None);
+    }
+
+    #[test]
+    fn can_unescape() {
+        let mut buf = [0; 32];
+        assert_eq!(
+            unescape("Tom &amp; Jerry&#39;s &quot;caf&#xE9;&quot;", &mut buf),
+            Some("Tom & Jerry's \"café\"")
+        );
+        assert_eq!(unescape("&lt;3 &gt;_&lt;", &mut buf), Some("<3 >_<"));
+
+        // Unknown entities and lone ampersands are kept
+        assert_eq!(
+            unescape("R&D; &nbsp; a & b", &mut buf),
+            Some("R&D; &nbsp; a & b")
+        );
+
+        assert_eq!(unescape("", &mut buf), Some(""));
+        assert_eq!(unescape("&amp;&amp;", &mut [0; 1]), None);
+    }
+
+    #[test]
+    fn can_find_passage_by_name() {
+        const STORY: &str = r#"
+            <tw-storydata startnode="1">
+                <tw-passagedata pid="1" name="intro">Hi</tw-passagedata>
+                <tw-passagedata pid="2" name="end" tags="slow">The end.</tw-passagedata>
+            </tw-storydata>
+        "#;
+
+        let passage = find_passage_by_name(STORY, "end");
+        assert_eq!(passage.pid, "2");
+        assert!(passage.tags.contains("slow"));
+        assert_eq!(passage.text, "The end.");
+
+        let passage = find_passage_by_id(STORY, "1");
+        assert_eq!(passage.name, "intro");
+    }
+
+    #[test]
+    fn can_read_story_metadata() {
+        const STORY: &str = r#"
+            <tw-storydata name="Ghostwriter" startnode="29" creator="Twine" creator-version="2.10.0" format="Harlowe" format-version="3.3.9" ifid="346B819B-F4E8-48B1-A493-5267BE63D8BF" hidden>
+                <tw-tag name="spooky" color="purple"></tw-tag>
+                <tw-tag name="slow" color="green"></tw-tag>
+                <tw-passagedata pid="29" name="intro">Hi</tw-passagedata>
+            </tw-storydata>
+        "#;
+
+        let metadata = story_metadata(STORY);
+        assert_eq!(metadata.name, "Ghostwriter");
+        assert_eq!(metadata.ifid, "346B819B-F4E8-48B1-A493-5267BE63D8BF");
+        assert_eq!(metadata.format, "Harlowe");
+        assert_eq!(metadata.format_version, "3.3.9");
+        assert_eq!(metadata.creator, "Twine");
+        assert_eq!(metadata.creator_version, "2.10.0");
+        assert_eq!(metadata.start_node, "29");
+
+        let tags: Vec<_> = metadata.tag_colors().map(|t| (t.name, t.color)).collect();
+        assert_eq!(tags, [("spooky", "purple"), ("slow", "green")]);
+        assert_eq!(metadata.tag_color("slow"), Some("green"));
+        assert_eq!(metadata.tag_color("fast"), None);
+    }
+
+    #[test]
+    fn other_html_has_empty_metadata() {
+        let metadata = story_metadata("<html><body><p>Not a story</p></body></html>");
+        assert_eq!(metadata.name, "");
+        assert_eq!(metadata.ifid, "");
+        assert_eq!(metadata.start_node, "");
+        assert_eq!(metadata.tag_colors().count(), 0);
+    }
+
+    #[test]
+    fn can_iterate_links() {
+        let passage = "Hi\n[[Hello]]\n[[Foo-&gt;Bar]]";
+
+        let targets: Vec<_> = links(passage).map(|link| link.target).collect();
+        assert_eq!(targets, ["Hello", "Bar"]);
+    }
+
     #[test]
     fn can_find_link_data_story() {
         // Test that passages can be accessed by name or alias
@@ -0,0 +1,198 @@
+//! Harlowe-style macros in passages
+//!
+//! Only `(either: "a", "b", ...)` is supported: it picks one of its options at random. In
+//! the passage text it is replaced with the pick, and as a link target it sends the reader
+//! to one of several passages:
+//!
+//! ```text
+//! The coin lands on (either: "heads", "tails").
+//! [[Flip it again->(either: "heads", "tails")]]
+//! ```
+//!
+//! Options are quoted with double or single quotes, which are usually HTML escaped in the
+//! story (`&quot;` and `&#39;`). Anything that doesn't parse as a macro is left as is.
+//!
+//! Picking is left to the caller (see [`resolve`]), so that a run can be reproduced from
+//! the seed of its random number generator.
+
+const EITHER: &str = "(either:";
+
+/// Quotes options can be wrapped in, as they appear in the story
+const QUOTES: [&str; 4] = ["&quot;", "&#39;", "\"", "'"];
+
+/// The (unparsed) options of an `(either:)` macro
+#[derive(Clone, Copy, Debug)]
+pub struct Either<'a> {
+    /// Everything between the colon and the closing parenthesis
+    args: &'a str,
+}
+
+impl<'a> Either<'a> {
+    /// Parse the macro, if the text is exactly one (up to surrounding whitespace)
+    pub fn parse(text: &'a str) -> Option<Either<'a>> {
+        match find(text.trim())? {
+            (0, either, "") => Some(either),
+            _ => None,
+        }
+    }
+
+    pub fn options(&self) -> Options<'a> {
+        Options {
+            rest: Some(self.args),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.options().count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn get(&self, n: usize) -> Option<&'a str> {
+        self.options().nth(n)
+    }
+}
+
+/// Iterator over the options of an `(either:)` macro
+pub struct Options<'a> {
+    rest: Option<&'a str>,
+}
+
+impl<'a> Iterator for Options<'a> {
+    type Item = &'a str;
+
+    fn next(&mut self) -> Option<&'a str> {
+        let (option, rest) = parse_option(self.rest?)?;
+        self.rest = rest.strip_prefix(',');
+        Some(option)
+    }
+}
+
+/// Split off a quoted option from what follows it (without leading whitespace)
+fn parse_option(args: &str) -> Option<(&str, &str)> {
+    let args = args.trim_start();
+    let quote = QUOTES.iter().find(|quote| args.starts_with(**quote))?;
+    let quoted = &args[quote.len()..];
+    let end = quoted.find(quote)?;
+    Some((&quoted[..end], quoted[end + quote.len()..].trim_start()))
+}
+
+/// Find the first valid macro in the text: its start, the macro, and the text after it
+fn find(text: &str) -> Option<(usize, Either<'_>, &str)> {
+    let mut from = 0;
+    while let Some(ix) = text[from..].find(EITHER) {
+        let start = from + ix;
+        if let Some((args, rest)) = split_args(&text[start + EITHER.len()..]) {
+            return Some((start, Either { args }, rest));
+        }
+        from = start + EITHER.len();
+    }
+    None
+}
+
+/// Split quoted, comma separated options from the text after the closing parenthesis
+fn split_args(text: &str) -> Option<(&str, &str)> {
+    let mut rest = text;
+    loop {
+        let (_, after) = parse_option(rest)?;
+        if let Some(after) = after.strip_prefix(',') {
+            rest = after;
+        } else {
+            let after = after.strip_prefix(')')?;
+            let args_len = text.len() - after.len() - 1;
+            return Some((&text[..args_len], after));
+        }
+    }
+}
+
+/// Whether the text has macros (which [`resolve`] would replace)
+pub fn contains_macros(text: &str) -> bool {
+    find(text).is_some()
+}
+
+/// Write the text to `buf` with its macros replaced by the option picked by `pick`, which
+/// is given the number of options and returns the index of one. Returns `None` if the
+/// text doesn't fit.
+pub fn resolve<'b>(
+    text: &str,
+    buf: &'b mut [u8],
+    mut pick: impl FnMut(usize) -> usize,
+) -> Option<&'b str> {
+    let mut len = 0;
+    let mut write = |s: &str| -> Option<()> {
+        buf.get_mut(len..len + s.len())?
+            .copy_from_slice(s.as_bytes());
+        len += s.len();
+        Some(())
+    };
+
+    let mut rest = text;
+    while let Some((start, either, after)) = find(rest) {
+        write(&rest[..start])?;
+        write(either.get(pick(either.len())).unwrap_or(""))?;
+        rest = after;
+    }
+    write(rest)?;
+
+    // NOTE: only whole strs were copied
+    core::str::from_utf8(&buf[..len]).ok()
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    #[test]
+    fn can_parse_either() {
+        let either = Either::parse(r#" (either: "a", 'b',&quot;c&quot; ) "#).unwrap();
+        assert_eq!(either.options().collect::<Vec<_>>(), ["a", "b", "c"]);
+        assert_eq!(either.len(), 3);
+        assert_eq!(either.get(1), Some("b"));
+
+        // The options can contain the other quotes, commas and parentheses
+        let either = Either::parse("(either: &quot;it&#39;s, (well)&quot;)").unwrap();
+        assert_eq!(either.options().collect::<Vec<_>>(), ["it&#39;s, (well)"]);
+    }
+
+    #[test]
+    fn ignores_invalid_macros() {
+        assert!(Either::parse("lounge").is_none());
+        assert!(Either::parse("(either: a, b)").is_none());
+        assert!(Either::parse(r#"(either: "a" "b")"#).is_none());
+        assert!(Either::parse(r#"(either: "a", "b""#).is_none());
+        assert!(Either::parse(r#"(either: "a") and more"#).is_none());
+    }
+
+    #[test]
+    fn can_resolve_text() {
+        let text = "Heads or tails? (either: &quot;Heads&quot;, &quot;Tails&quot;)! (either: it)";
+        let mut buf = [0; 64];
+
+        let resolved = resolve(text, &mut buf, |n| n - 1).unwrap();
+        assert_eq!(resolved, "Heads or tails? Tails! (either: it)");
+
+        let mut picks = vec![];
+        resolve(
+            "(either: 'a', 'b') (either: 'c', 'd', 'e')",
+            &mut buf,
+            |n| {
+                picks.push(n);
+                0
+            },
+        );
+        assert_eq!(picks, [2, 3]);
+
+        assert!(contains_macros(text));
+        assert!(!contains_macros("(either: it)"));
+    }
+
+    #[test]
+    fn does_not_resolve_oversized_text() {
+        let mut buf = [0; 8];
+        assert_eq!(resolve("(either: 'fits')", &mut buf, |_| 0), Some("fits"));
+        assert_eq!(resolve("(either: 'much too long')", &mut buf, |_| 0), None);
+    }
+}